@@ -0,0 +1,1184 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use hlddup::{
+    cgroup::{CgroupOptions, CPU_WEIGHT_RANGE},
+    dedup::{
+        DedupOptions, FsIdentity, FsyncPolicy, KeepRule, LinkAction, OverlayPolicy,
+        ReadOnlyDirPolicy, SparsePolicy, TieBreak,
+    },
+    dupchecks::Safety,
+    filelist::FileList,
+    filters::PathPatterns,
+    hashcache::{HashAlgo, MAX_MAX_SAMPLES, MAX_SAMPLE_SIZE, MIN_SAMPLES},
+    output::{OutputKind, OutputSpec},
+    plugin::load_plugin,
+    report::ReportOptions,
+    utils::{format_size, parse_size, parse_time, PriorityExtensions, ReadOptions},
+    verify::DEFAULT_VERIFY_SAMPLE,
+    PromptUserMode, ScanOptions,
+};
+
+use crate::config::{default_config_path, Profile};
+
+/// The usage message printed for `--help`.
+const USAGE: &str = "\
+Hard-links duplicate files.
+
+Usage:
+    hldup [options] [dirs...]
+    hldup report <--du|--extensions|--ages|--components> [options] [dirs...]
+    hldup verify-hashing [--sample <n>] [options] [dirs...]
+    hldup merge-scans <scans...> -o <output>
+    hldup plan-diff <old report> <new report>
+    hldup serve --socket <path> [options]
+    hldup recover --journal <file>
+    hldup discover
+
+Dirs default to the current directory. Pass `--` to treat everything after it
+as a directory, even if it starts with `-`.
+
+Profiles:
+    --profile <name>              Use the options & dirs of the profile <name>
+                                  from the config file
+    --config <file>               Read profiles from <file> rather than
+                                  ~/.config/hldup/config
+
+Prompting:
+    --prompt                      Ask before linking each duplicate (default)
+    --default-yes                 Link every duplicate without asking
+    --default-no                  Link nothing, only report duplicates
+    --dry-run                     Report the links that would be made
+    --auto-known                  Link content consolidated in a previous run
+                                  without asking
+    --auto-link <class>           Link duplicates classified as <class> (safe,
+                                  review, or risky) or safer without asking
+    --never-link <class>          Never link duplicates classified as <class>
+                                  or riskier
+    --no-confirm                  Don't ask to confirm the whole plan before
+                                  linking, even on a terminal
+    --learn-excludes              Suggest excluding the directories & extensions
+                                  of duplicates declined at the prompt, adding
+                                  those accepted to the profile
+
+Linking:
+    --action <action>             Replace duplicates with a hardlink (default),
+                                  a relative symlink, or a reflink clone,
+                                  delete them, or only report them
+    --force                       Allow --action delete without prompting
+    --keep <rule>                 most-links, shallowest-path, shortest-name,
+                                  or lexicographically-first copy is kept
+    --tie-break <rule>            Keep the first path (default) or lowest
+                                  inode among equally ranked copies
+    --canonical-under <dir>       Keep the copies inside <dir>
+    --link-into <dir>             Also link every duplicated content into the
+                                  content-addressed store <dir>
+    --protect <pattern>           Never replace paths matching the glob
+                                  <pattern>, only link to them; may be repeated
+    --replace-symlinks            Allow symlinks to be replaced
+    --sparse-mismatch <policy>    warn, skip, or link sparse/allocated pairs
+    --readonly-dirs <policy>      skip or chmod read-only directories
+    --overlay <policy>            skip or link on overlay filesystems
+    --same-fs-by <identity>       Match filesystems by device or mount
+    --link-tiny                   Link files smaller than a filesystem block
+    --link-incomplete             Link files that look like unfinished downloads
+    --max-links <n>               Override the filesystem's link limit
+    --max-links-per-inode <n>     Give each kept file at most <n> links
+    --fsync <policy>              Sync directories per batch, each, or never
+    --journal <file>              Record every change in the crash-safe journal
+                                  <file>, for `hldup recover`
+    --max-new-links <n>           Stop before replacing more than <n> paths
+    --max-bytes-saved <size>      Stop once <size> has been freed
+    --min-free-space <size>       Stop once free space drops below <size>
+    --min-free-inodes <n>         Stop once free inodes drop below <n>
+
+Scanning:
+    --state <file>                Remember hashes & results between runs
+    --skip-unchanged              Don't walk directory trees the --state file
+                                  shows are unchanged
+    --save-scan <file>            Save the scanned hashes to <file>
+    --walk-checkpoint <file>      Record the walk's progress in <file>, and
+                                  resume an interrupted walk from it
+    --index <file>                Link against & add to the content index in
+                                  <file>
+    --append-scan <file>          Also link against the files in the saved
+                                  scan <file>, without replacing them; may be
+                                  repeated
+    --exclude <pattern>           Skip paths matching the glob <pattern>;
+                                  may be repeated
+    --include <pattern>           Only scan files matching the glob <pattern>;
+                                  may be repeated
+    --min-size <size>             Skip files smaller than <size>
+    --max-size <size>             Skip files larger than <size>
+    --older-than <time>           Skip files modified since <time>, an age
+                                  like 30d or a date like 2024-01-31
+    --newer-than <time>           Skip files last modified before <time>
+    --max-depth <n>               Scan at most <n> levels below each directory
+    --priority-ext <exts>         Handle these extensions first
+    --include-snapshots           Scan ZFS & btrfs snapshots
+    --follow-symlinks             Descend into symlinked directories
+    --skip-hidden                 Skip files & directories starting with a .
+    --plugin <file>               Filter files & pairs with the WebAssembly
+                                  module <file>; may be repeated
+    -x, --one-file-system         Skip directories on other filesystems than
+                                  the directory being scanned
+    --files-from <file>           Also scan the dirs & files listed one per line
+                                  in <file>; may be repeated
+    --files0-from <file>          Scan the NUL-separated paths in <file>, or
+                                  stdin if -, instead of walking dirs
+    --quick-estimate              Only estimate the duplication from file
+                                  sizes & names, without hashing
+    --ignore-permission-errors    Don't warn about each unreadable path
+    --read-retries <n>            Retry failed reads up to <n> times
+    --tune-sampling               Choose the hash sampling by measuring the
+                                  storage & files
+    --sample-size <size>          Read <size> bytes per hash sample
+    --max-samples <n>             Take at most <n> hash samples per file
+    --hash-algo <algo>            Hash samples with seahash, xxh3, or blake3
+    --no-zero-resampling          Don't take extra samples of files whose
+                                  samples are all zero
+    --max-io <size>               Stop once <size> bytes have been read
+    --threads <n>                 Read up to <n> files at once (default: 1
+                                  per CPU)
+    --direct-io                   Compare files using O_DIRECT
+    --debug-compare               Hexdump files around their first difference
+
+Resource limits (Linux cgroup v2):
+    --cgroup <path>               Move into the cgroup <path>, relative to
+                                  /sys/fs/cgroup, creating it if needed
+    --cgroup-io-max <limit>       Write <limit> (e.g. \"8:0 rbps=10M\") to the
+                                  cgroup's io.max; may be repeated
+    --cgroup-cpu-weight <n>       Set the cgroup's cpu.weight (1-10000)
+
+Output:
+    -q, --quiet                   Only log errors & the final summary
+    -v, --verbose                 Log more detail; repeat (-vv, -vvv) for more
+    --damaged-log <file>          Write damaged files & offsets to <file>
+    --events <file>               Write JSON Lines events to <file>, or - for
+                                  stdout
+    --report <file>               Write a JSON report of the groups found,
+                                  comparisons & links made to <file>
+    --output <kind>:<file>        Write events, report, csv, or summary output
+                                  to <file>, or - for stdout; may be repeated
+    --status-fifo <path>          Write the run's progress as JSON to the named
+                                  pipe at <path>, creating it if needed
+    -h, --help                    Print this message
+    -V, --version                 Print the version
+";
+
+/// The task the application was asked to perform.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Command {
+    /// Find & link duplicates; the default when no command is given.
+    #[default]
+    Dedup,
+    /// Scan for duplicates and print a report, without modifying anything.
+    Report(ReportOptions),
+    /// Merge the scans saved with `--save-scan` into a single scan, reporting
+    /// the possible duplicates found across them.
+    MergeScans {
+        inputs: Vec<PathBuf>,
+        output: PathBuf,
+    },
+    /// Compare the links made or planned by 2 runs, from the JSON reports
+    /// they saved with `--report`.
+    PlanDiff { old: PathBuf, new: PathBuf },
+    /// Handle hashing, comparison, and link requests from other programs over
+    /// a Unix socket.
+    Serve { socket: PathBuf },
+    /// Scan for duplicates and check how often the sampled hashes group
+    /// together files whose full contents differ, hashing up to `sample` files
+    /// in full.
+    VerifyHashing { sample: usize },
+    /// Finish or undo the operations in the `--journal` left incomplete by a
+    /// crash.
+    Recover,
+    /// List the mounted local filesystems & suggest roots to deduplicate.
+    Discover,
+    /// Print the usage message.
+    Help,
+    /// Print the version.
+    Version,
+}
+
+impl Command {
+    /// Whether the command scans directories passed on the command line.
+    fn scans_dirs(&self) -> bool {
+        matches!(
+            self,
+            Command::Dedup | Command::Report(_) | Command::VerifyHashing { .. }
+        )
+    }
+}
+
+/// Prints the usage message to stdout.
+pub fn print_usage() {
+    print!("{USAGE}");
+}
+
+/// Prints the version to stdout.
+pub fn print_version() {
+    println!("hldup {}", env!("CARGO_PKG_VERSION"));
+}
+
+#[derive(Debug, Default)]
+pub struct AppArgs {
+    pub command: Command,
+    pub dedup_opts: DedupOptions,
+    pub dirs: Vec<PathBuf>,
+    pub state_path: Option<PathBuf>,
+    pub read_opts: ReadOptions,
+    pub damaged_log: Option<PathBuf>,
+    pub scan_opts: ScanOptions,
+    /// The outputs written besides the log, from `--output`, `--events`, and
+    /// `--report`.
+    pub outputs: Vec<OutputSpec>,
+    /// The journal changes are recorded in, if any.
+    pub journal_path: Option<PathBuf>,
+    pub save_scan: Option<PathBuf>,
+    /// Whether to choose the sampling parameters with a calibration pass.
+    pub tune_sampling: bool,
+    /// The sample size to use, overriding the default or tuned one.
+    pub sample_size: Option<usize>,
+    /// The maximum samples per file to use, overriding the default or tuned
+    /// one.
+    pub max_samples: Option<u32>,
+    /// The algorithm samples are hashed with.
+    pub hash_algo: HashAlgo,
+    /// Whether to hash files whose samples are all zero like any other.
+    pub no_zero_resampling: bool,
+    /// Individual files to scan alongside `dirs`.
+    pub files: Vec<PathBuf>,
+    /// The list of files to scan instead of walking `dirs`, if given.
+    pub file_list: Option<FileList>,
+    /// Where the index of contents seen across runs is kept, if anywhere.
+    pub index_path: Option<PathBuf>,
+    /// Where the walk's progress is checkpointed, if anywhere.
+    pub walk_checkpoint: Option<PathBuf>,
+    /// The named pipe the run's progress is written to, if any.
+    pub status_fifo: Option<PathBuf>,
+    /// Saved scans of archives whose files are linked to but never replaced.
+    pub append_scans: Vec<PathBuf>,
+    /// Whether to only estimate the duplication from sizes & names.
+    pub quick_estimate: bool,
+    /// The cgroup to run in, if any.
+    pub cgroup: Option<CgroupOptions>,
+    /// How much to log: negative for `-q`, or the number of `-v`s.
+    pub verbosity: i8,
+    /// Whether to suggest exclude rules for the duplicates declined at the
+    /// prompt.
+    pub learn_excludes: bool,
+    /// The config file & name of the profile the options came from, if any.
+    pub profile: Option<(PathBuf, String)>,
+}
+
+/// Every long flag [AppArgs::parse] accepts that has an `HLDUP_*` variable,
+/// with what its value is called in errors, or `None` if it's a switch.
+///
+/// Both the parser & [env_args] read this, so that every flag gets a variable
+/// and each variable is read the same way as its flag.
+const FLAGS: &[(&str, Option<&str>)] = &[
+    ("--profile", Some("a name")),
+    ("--config", Some("a path")),
+    ("--prompt", None),
+    ("--default-yes", None),
+    ("--default-no", None),
+    ("--dry-run", None),
+    ("--auto-known", None),
+    ("--auto-link", Some("a safety class")),
+    ("--never-link", Some("a safety class")),
+    ("--no-confirm", None),
+    ("--learn-excludes", None),
+    ("--action", Some("an action")),
+    ("--force", None),
+    ("--keep", Some("a rule")),
+    ("--tie-break", Some("a rule")),
+    ("--canonical-under", Some("a directory")),
+    ("--link-into", Some("a directory")),
+    ("--protect", Some("a pattern")),
+    ("--replace-symlinks", None),
+    ("--sparse-mismatch", Some("a policy: warn, skip, or link")),
+    ("--readonly-dirs", Some("a policy: skip or chmod")),
+    ("--overlay", Some("a policy: skip or link")),
+    ("--same-fs-by", Some("either device or mount")),
+    ("--link-tiny", None),
+    ("--link-incomplete", None),
+    ("--max-links", Some("a count")),
+    ("--max-links-per-inode", Some("a count")),
+    ("--fsync", Some("a policy: batch, each, or never")),
+    ("--journal", Some("a path")),
+    ("--max-new-links", Some("a count")),
+    ("--max-bytes-saved", Some("a size")),
+    ("--min-free-space", Some("a size")),
+    ("--min-free-inodes", Some("a count")),
+    ("--state", Some("a path")),
+    ("--skip-unchanged", None),
+    ("--save-scan", Some("a path")),
+    ("--walk-checkpoint", Some("a path")),
+    ("--index", Some("a path")),
+    ("--append-scan", Some("a path")),
+    ("--exclude", Some("a pattern")),
+    ("--include", Some("a pattern")),
+    ("--min-size", Some("a size")),
+    ("--max-size", Some("a size")),
+    ("--older-than", Some("an age or date")),
+    ("--newer-than", Some("an age or date")),
+    ("--max-depth", Some("a depth")),
+    (
+        "--priority-ext",
+        Some("a comma-separated list of extensions"),
+    ),
+    ("--include-snapshots", None),
+    ("--follow-symlinks", None),
+    ("--skip-hidden", None),
+    ("--plugin", Some("a path")),
+    ("--one-file-system", None),
+    ("--files-from", Some("a file")),
+    ("--files0-from", Some("a file or -")),
+    ("--quick-estimate", None),
+    ("--ignore-permission-errors", None),
+    ("--read-retries", Some("a count")),
+    ("--tune-sampling", None),
+    ("--sample-size", Some("a size")),
+    ("--max-samples", Some("a count")),
+    ("--hash-algo", Some("an algorithm")),
+    ("--no-zero-resampling", None),
+    ("--max-io", Some("a size")),
+    ("--threads", Some("a count")),
+    ("--direct-io", None),
+    ("--debug-compare", None),
+    ("--cgroup", Some("a path")),
+    ("--cgroup-io-max", Some("a limit")),
+    ("--cgroup-cpu-weight", Some("a weight")),
+    ("--quiet", None),
+    ("--verbose", None),
+    ("--damaged-log", Some("a path")),
+    ("--events", Some("a path, or - for stdout")),
+    ("--report", Some("a path")),
+    ("--output", Some("<kind>:<path>")),
+    ("--status-fifo", Some("a path")),
+];
+
+/// The flags [AppArgs::parse] accepts without an `HLDUP_*` variable: short
+/// aliases, those only valid for a single command, and `--help` &
+/// `--version`, in the same form as [FLAGS].
+const CLI_ONLY_FLAGS: &[(&str, Option<&str>)] = &[
+    ("-h", None),
+    ("--help", None),
+    ("-V", None),
+    ("--version", None),
+    ("-q", None),
+    ("-x", None),
+    ("-o", Some("a path")),
+    ("--socket", Some("a path")),
+    ("--sample", Some("a count")),
+    ("--du", None),
+    ("--ages", None),
+    ("--components", None),
+    ("--extensions", None),
+];
+
+/// Builds the flags set by `HLDUP_*` environment variables, such as
+/// `HLDUP_MIN_SIZE=1M` for `--min-size 1M` or `HLDUP_DEFAULT_YES=1` for
+/// `--default-yes`.
+///
+/// Every flag in [FLAGS] gets a variable.
+fn env_args() -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (flag, value_name) in FLAGS {
+        let var = format!("HLDUP_{}", flag[2..].replace('-', "_").to_uppercase());
+        let Some(value) = env::var_os(&var) else {
+            continue;
+        };
+        let value = value
+            .into_string()
+            .map_err(|value| format!("{var} is not valid UTF-8: {value:?}"))?;
+        if value_name.is_some() {
+            if !value.is_empty() {
+                args.push((*flag).to_owned());
+                args.push(value);
+            }
+            continue;
+        }
+        match value.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => args.push((*flag).to_owned()),
+            "" | "0" | "false" | "no" | "off" => {}
+            _ => {
+                return Err(format!(
+                    "Invalid value for {var}: {value:?}; expected 1 or 0"
+                ))
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Canonicalizes `path`, which may not exist yet, by canonicalizing its
+/// nearest existing ancestor & appending the rest.
+fn canonicalize_missing(path: &Path) -> io::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                return Err(e);
+            };
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            Ok(canonicalize_missing(parent)?.join(name))
+        }
+        res => res,
+    }
+}
+
+/// Checks if `arg` is `-v`, or several `v`s in a row like `-vv`.
+fn is_verbose_flag(arg: &str) -> bool {
+    arg.strip_prefix('-')
+        .is_some_and(|vs| !vs.is_empty() && vs.bytes().all(|b| b == b'v'))
+}
+
+/// Removes `--profile` & `--config` from `raw`, loading the selected
+/// [Profile] if there is one.
+fn select_profile(raw: &[impl AsRef<str>]) -> Result<(Profile, Vec<&str>), String> {
+    let mut rest = Vec::with_capacity(raw.len());
+    let mut name = None;
+    let mut config = None;
+    let mut raw = raw.iter().map(AsRef::as_ref);
+    while let Some(arg) = raw.next() {
+        match arg {
+            "--" => {
+                rest.push(arg);
+                rest.extend(raw.by_ref());
+            }
+            "--profile" => name = Some(raw.next().ok_or("--profile requires a name")?),
+            "--config" => {
+                config = Some(PathBuf::from(raw.next().ok_or("--config requires a path")?))
+            }
+            other => rest.push(other),
+        }
+    }
+    let Some(name) = name else {
+        return Ok((Profile::default(), rest));
+    };
+    let path = config
+        .or_else(default_config_path)
+        .ok_or("Couldn't find the config file; pass --config")?;
+    Ok((Profile::load(&path, name)?, rest))
+}
+
+impl AppArgs {
+    /// The paths of the outputs of `kind`, in the order they were given.
+    pub fn outputs_of(&self, kind: OutputKind) -> impl Iterator<Item = &Path> {
+        self.outputs
+            .iter()
+            .filter(move |output| output.kind == kind)
+            .map(|output| output.path.as_path())
+    }
+
+    pub fn parse(raw: &[impl AsRef<str>]) -> Result<Self, String> {
+        // Asking for help or the version is checked before anything from the
+        // environment or a profile is read, so a bad one can't get in the way
+        let early = raw
+            .iter()
+            .map(AsRef::as_ref)
+            .take_while(|arg| *arg != "--")
+            .find_map(|arg| match arg {
+                "-h" | "--help" => Some(Command::Help),
+                "-V" | "--version" => Some(Command::Version),
+                _ => None,
+            });
+        if let Some(command) = early {
+            return Ok(Self {
+                command,
+                ..Self::default()
+            });
+        }
+        let (mut command, raw) = match raw.split_first() {
+            Some((first, rest)) if first.as_ref() == "report" => {
+                (Command::Report(ReportOptions::default()), rest)
+            }
+            Some((first, rest)) if first.as_ref() == "merge-scans" => (
+                Command::MergeScans {
+                    inputs: Vec::new(),
+                    output: PathBuf::new(),
+                },
+                rest,
+            ),
+            Some((first, rest)) if first.as_ref() == "plan-diff" => (
+                Command::PlanDiff {
+                    old: PathBuf::new(),
+                    new: PathBuf::new(),
+                },
+                rest,
+            ),
+            Some((first, rest)) if first.as_ref() == "serve" => (
+                Command::Serve {
+                    socket: PathBuf::new(),
+                },
+                rest,
+            ),
+            Some((first, rest)) if first.as_ref() == "recover" => (Command::Recover, rest),
+            Some((first, rest)) if first.as_ref() == "discover" => (Command::Discover, rest),
+            Some((first, rest)) if first.as_ref() == "verify-hashing" => (
+                Command::VerifyHashing {
+                    sample: DEFAULT_VERIFY_SAMPLE,
+                },
+                rest,
+            ),
+            _ => (Command::Dedup, raw),
+        };
+        let mut dirs = Vec::new();
+        let mut dedup_opts = DedupOptions {
+            confirm_plan: true,
+            ..DedupOptions::default()
+        };
+        let mut state_path = None;
+        let mut read_opts = ReadOptions::default();
+        let mut damaged_log = None;
+        let mut scan_opts = ScanOptions::default();
+        let mut outputs = Vec::new();
+        let mut journal_path = None;
+        let mut save_scan = None;
+        let mut tune_sampling = false;
+        let mut sample_size = None;
+        let mut max_samples = None;
+        let mut hash_algo = HashAlgo::default();
+        let mut no_zero_resampling = false;
+        let mut link_into = None;
+        let mut force = false;
+        let mut exclude = Vec::new();
+        let mut protect = Vec::new();
+        let mut include = Vec::new();
+        let mut file_list = None;
+        let mut files_from = Vec::new();
+        let mut index_path = None;
+        let mut append_scans = Vec::new();
+        let mut walk_checkpoint = None;
+        let mut status_fifo = None;
+        let mut quick_estimate = false;
+        let mut cgroup_path = None;
+        let mut cgroup_io_max = Vec::new();
+        let mut cgroup_cpu_weight = None;
+        let mut verbosity = 0i8;
+        let mut learn_excludes = false;
+        let mut plugins = Vec::new();
+        // A profile's flags come before those from the environment, which come
+        // before those on the command line, so that each overrides the last
+        let env_args = env_args()?;
+        let raw = env_args
+            .iter()
+            .map(String::as_str)
+            .chain(raw.iter().map(AsRef::as_ref))
+            .collect::<Vec<_>>();
+        let (profile, raw) = select_profile(&raw)?;
+        let mut raw = profile.args.iter().map(String::as_str).chain(raw);
+        while let Some(arg) = raw.next() {
+            let is_flag = arg.starts_with('-') && !matches!(arg, "-" | "--");
+            let value = match FLAGS
+                .iter()
+                .chain(CLI_ONLY_FLAGS)
+                .find(|(flag, _)| *flag == arg)
+            {
+                Some((_, Some(value_name))) => raw
+                    .next()
+                    .ok_or_else(|| format!("{arg} requires {value_name}"))?,
+                Some((_, None)) => "",
+                None if is_flag && !is_verbose_flag(arg) => {
+                    return Err(format!("Unknown flag {arg}; see --help for usage"));
+                }
+                None => "",
+            };
+            match arg {
+                // Nothing after these is checked or acted on, so that asking
+                // for help never creates a store or fails on a missing root
+                "-h" | "--help" => {
+                    return Ok(Self {
+                        command: Command::Help,
+                        ..Self::default()
+                    });
+                }
+                "-V" | "--version" => {
+                    return Ok(Self {
+                        command: Command::Version,
+                        ..Self::default()
+                    });
+                }
+                "-q" | "--quiet" => {
+                    verbosity = -1;
+                }
+                "--verbose" => {
+                    verbosity = verbosity.max(0).saturating_add(1);
+                }
+                other if is_verbose_flag(other) => {
+                    let count = (other.len() - 1).min(i8::MAX as usize) as i8;
+                    verbosity = verbosity.max(0).saturating_add(count);
+                }
+                "--" => {
+                    dirs.extend(raw.by_ref().map(PathBuf::from));
+                }
+                "--prompt" => {
+                    dedup_opts.prompt_mode = PromptUserMode::Prompt;
+                }
+                "--default-yes" => {
+                    dedup_opts.prompt_mode = PromptUserMode::DefaultYes;
+                }
+                "--default-no" => {
+                    dedup_opts.prompt_mode = PromptUserMode::DefaultNo;
+                }
+                "--dry-run" => {
+                    dedup_opts.dry_run = true;
+                }
+                "--no-confirm" => {
+                    dedup_opts.confirm_plan = false;
+                }
+                "--learn-excludes" => {
+                    learn_excludes = true;
+                }
+                "--state" => {
+                    state_path = Some(PathBuf::from(value));
+                }
+                "--read-retries" => {
+                    read_opts.retries = value
+                        .parse()
+                        .map_err(|e| format!("Invalid retry count {value:?}: {e}"))?;
+                }
+                "--max-io" => {
+                    read_opts.max_io = Some(parse_size(value)?);
+                }
+                "--threads" => {
+                    read_opts.threads = value
+                        .parse()
+                        .map_err(|e| format!("Invalid thread count {value:?}: {e}"))?;
+                }
+                "--quick-estimate" => {
+                    quick_estimate = true;
+                }
+                "--cgroup" => {
+                    cgroup_path = Some(PathBuf::from(value));
+                }
+                "--cgroup-io-max" => {
+                    cgroup_io_max.push(value.to_owned());
+                }
+                "--cgroup-cpu-weight" => {
+                    let weight = value
+                        .parse()
+                        .map_err(|e| format!("Invalid CPU weight {value:?}: {e}"))?;
+                    if !CPU_WEIGHT_RANGE.contains(&weight) {
+                        return Err(format!(
+                            "--cgroup-cpu-weight must be between {} and {}",
+                            CPU_WEIGHT_RANGE.start(),
+                            CPU_WEIGHT_RANGE.end()
+                        ));
+                    }
+                    cgroup_cpu_weight = Some(weight);
+                }
+                "--direct-io" => {
+                    read_opts.direct_io = true;
+                }
+                "--debug-compare" => {
+                    read_opts.debug_compare = true;
+                }
+                "--tune-sampling" => {
+                    tune_sampling = true;
+                }
+                "--sample-size" => {
+                    let size = parse_size(value)?;
+                    if size == 0 {
+                        return Err("--sample-size must be at least 1 byte".to_owned());
+                    }
+                    if size > MAX_SAMPLE_SIZE {
+                        return Err(format!(
+                            "--sample-size must be at most {}",
+                            format_size(MAX_SAMPLE_SIZE)
+                        ));
+                    }
+                    sample_size = Some(size as usize);
+                }
+                "--max-samples" => {
+                    let count = value
+                        .parse()
+                        .map_err(|e| format!("Invalid sample count {value:?}: {e}"))?;
+                    if !(MIN_SAMPLES..=MAX_MAX_SAMPLES).contains(&count) {
+                        return Err(format!(
+                            "--max-samples must be between {MIN_SAMPLES} and {MAX_MAX_SAMPLES}"
+                        ));
+                    }
+                    max_samples = Some(count);
+                }
+                "--hash-algo" => {
+                    hash_algo = HashAlgo::parse(value)?;
+                }
+                "--no-zero-resampling" => {
+                    no_zero_resampling = true;
+                }
+                "--damaged-log" => {
+                    damaged_log = Some(PathBuf::from(value));
+                }
+                "--min-free-space" => {
+                    dedup_opts.min_free_bytes = parse_size(value)?;
+                }
+                "--min-free-inodes" => {
+                    dedup_opts.min_free_inodes = value
+                        .parse()
+                        .map_err(|e| format!("Invalid inode count {value:?}: {e}"))?;
+                }
+                "--action" => {
+                    dedup_opts.action = LinkAction::parse(value)?;
+                }
+                "--keep" => {
+                    dedup_opts.keep_rule = KeepRule::parse(value)?;
+                }
+                "--tie-break" => {
+                    dedup_opts.tie_break = TieBreak::parse(value)?;
+                }
+                "--canonical-under" => {
+                    let dir = fs::canonicalize(value)
+                        .map_err(|e| format!("Invalid canonical directory {value:?}: {e}"))?;
+                    dedup_opts.canonical_under = Some(dir);
+                }
+                "--link-into" => {
+                    link_into = Some(PathBuf::from(value));
+                }
+                "--link-tiny" => {
+                    dedup_opts.link_tiny = true;
+                }
+                "--link-incomplete" => {
+                    dedup_opts.link_incomplete = true;
+                }
+                "--replace-symlinks" => {
+                    dedup_opts.replace_symlinks = true;
+                }
+                "--force" => {
+                    force = true;
+                }
+                "--auto-known" => {
+                    dedup_opts.auto_known = true;
+                }
+                "--auto-link" => {
+                    dedup_opts.auto_link = Some(Safety::parse(value)?);
+                }
+                "--never-link" => {
+                    dedup_opts.never_link = Some(Safety::parse(value)?);
+                }
+                "--sparse-mismatch" => {
+                    dedup_opts.sparse_policy = SparsePolicy::parse(value)?;
+                }
+                "--priority-ext" => {
+                    let priority = PriorityExtensions::parse(value);
+                    scan_opts.priority = priority.clone();
+                    dedup_opts.priority = priority;
+                }
+                "--readonly-dirs" => {
+                    dedup_opts.readonly_dir_policy = ReadOnlyDirPolicy::parse(value)?;
+                }
+                "--max-links" => {
+                    let count = value
+                        .parse()
+                        .map_err(|e| format!("Invalid link count {value:?}: {e}"))?;
+                    dedup_opts.max_links = Some(count);
+                }
+                "--max-new-links" => {
+                    let count = value
+                        .parse()
+                        .map_err(|e| format!("Invalid link count {value:?}: {e}"))?;
+                    dedup_opts.max_new_links = Some(count);
+                }
+                "--max-bytes-saved" => {
+                    dedup_opts.max_bytes_saved = Some(parse_size(value)?);
+                }
+                "--max-links-per-inode" => {
+                    let count = value
+                        .parse()
+                        .map_err(|e| format!("Invalid link count {value:?}: {e}"))?;
+                    dedup_opts.max_links_per_inode = Some(count);
+                }
+                "--same-fs-by" => {
+                    dedup_opts.fs_identity = FsIdentity::parse(value)?;
+                }
+                "--overlay" => {
+                    dedup_opts.overlay_policy = OverlayPolicy::parse(value)?;
+                }
+                "--journal" => {
+                    journal_path = Some(PathBuf::from(value));
+                }
+                "--fsync" => {
+                    dedup_opts.fsync_policy = FsyncPolicy::parse(value)?;
+                }
+                "--exclude" => {
+                    exclude.push(value.to_owned());
+                }
+                "--protect" => {
+                    protect.push(value.to_owned());
+                }
+                "--include" => {
+                    include.push(value.to_owned());
+                }
+                "--min-size" => {
+                    scan_opts.min_size = parse_size(value)?;
+                }
+                "--older-than" => {
+                    scan_opts.modified_before = Some(parse_time(value, SystemTime::now())?);
+                }
+                "--newer-than" => {
+                    scan_opts.modified_after = Some(parse_time(value, SystemTime::now())?);
+                }
+                "--max-size" => {
+                    scan_opts.max_size = Some(parse_size(value)?);
+                }
+                "--max-depth" => {
+                    scan_opts.max_depth = Some(
+                        value
+                            .parse()
+                            .map_err(|e| format!("Invalid depth {value:?}: {e}"))?,
+                    );
+                }
+                "--files-from" => {
+                    files_from.push(FileList {
+                        source: PathBuf::from(value),
+                        separator: b'\n',
+                        comments: true,
+                    });
+                }
+                "--files0-from" => {
+                    file_list = Some(FileList {
+                        source: PathBuf::from(value),
+                        separator: b'\0',
+                        comments: false,
+                    });
+                }
+                "--follow-symlinks" => {
+                    scan_opts.follow_symlinks = true;
+                }
+                "--plugin" => {
+                    plugins.push(PathBuf::from(value));
+                }
+                "--skip-hidden" => {
+                    scan_opts.skip_hidden = true;
+                }
+                "--skip-unchanged" => {
+                    scan_opts.skip_unchanged = true;
+                }
+                "-x" | "--one-file-system" => {
+                    scan_opts.one_file_system = true;
+                }
+                "--include-snapshots" => {
+                    scan_opts.include_snapshots = true;
+                }
+                "--ignore-permission-errors" => {
+                    scan_opts.ignore_permission_errors = true;
+                }
+                "--events" => {
+                    outputs.push(OutputSpec {
+                        kind: OutputKind::Events,
+                        path: PathBuf::from(value),
+                    });
+                }
+                "--report" => {
+                    outputs.push(OutputSpec {
+                        kind: OutputKind::Report,
+                        path: PathBuf::from(value),
+                    });
+                }
+                "--index" => {
+                    index_path = Some(PathBuf::from(value));
+                }
+                "--walk-checkpoint" => {
+                    walk_checkpoint = Some(PathBuf::from(value));
+                }
+                "--status-fifo" => {
+                    status_fifo = Some(PathBuf::from(value));
+                }
+                "--append-scan" => {
+                    append_scans.push(PathBuf::from(value));
+                }
+                "--save-scan" => {
+                    save_scan = Some(PathBuf::from(value));
+                }
+                "-o" | "--output" => {
+                    // merge-scans writes a single scan, while everything else can
+                    // write any number of outputs
+                    if let Command::MergeScans { output, .. } = &mut command {
+                        *output = PathBuf::from(value);
+                    } else if arg == "-o" {
+                        return Err("-o is only valid for the merge-scans command".to_owned());
+                    } else {
+                        outputs.push(OutputSpec::parse(value)?);
+                    }
+                }
+                "--socket" => {
+                    let Command::Serve { socket } = &mut command else {
+                        return Err("--socket is only valid for the serve command".to_owned());
+                    };
+                    *socket = PathBuf::from(value);
+                }
+                "--sample" => {
+                    let Command::VerifyHashing { sample } = &mut command else {
+                        return Err(
+                            "--sample is only valid for the verify-hashing command".to_owned()
+                        );
+                    };
+                    *sample = value
+                        .parse()
+                        .map_err(|e| format!("Invalid sample count {value:?}: {e}"))?;
+                }
+                "--du" => {
+                    let Command::Report(report_opts) = &mut command else {
+                        return Err("--du is only valid for the report command".to_owned());
+                    };
+                    report_opts.du = true;
+                }
+                "--ages" => {
+                    let Command::Report(report_opts) = &mut command else {
+                        return Err("--ages is only valid for the report command".to_owned());
+                    };
+                    report_opts.ages = true;
+                }
+                "--components" => {
+                    let Command::Report(report_opts) = &mut command else {
+                        return Err("--components is only valid for the report command".to_owned());
+                    };
+                    report_opts.components = true;
+                }
+                "--extensions" => {
+                    let Command::Report(report_opts) = &mut command else {
+                        return Err("--extensions is only valid for the report command".to_owned());
+                    };
+                    report_opts.extensions = true;
+                }
+                // Unknown flags were already rejected when looking up their values
+                other => {
+                    dirs.push(PathBuf::from(other));
+                }
+            }
+        }
+        // Plugins are only loaded once every flag has been checked
+        for plugin in &plugins {
+            load_plugin(plugin, &mut scan_opts, &mut dedup_opts)?;
+        }
+        if dirs.is_empty() && files_from.is_empty() && file_list.is_none() {
+            dirs = match env::var_os("HLDUP_DIRS") {
+                Some(var) if !var.is_empty() => env::split_paths(&var).collect(),
+                _ => profile.dirs,
+            };
+        }
+        match &mut command {
+            Command::Report(report_opts) if !report_opts.any() => {
+                return Err("The report command requires a report type, such as --du".to_owned());
+            }
+            Command::MergeScans { inputs, output } => {
+                if output.as_os_str().is_empty() {
+                    return Err("The merge-scans command requires an --output path".to_owned());
+                }
+                if dirs.len() < 2 {
+                    return Err("The merge-scans command requires at least 2 scans".to_owned());
+                }
+                *inputs = std::mem::take(&mut dirs);
+            }
+            Command::PlanDiff { old, new } => {
+                let [first, second] = std::mem::take(&mut dirs)
+                    .try_into()
+                    .map_err(|_| "The plan-diff command requires exactly 2 reports".to_owned())?;
+                (*old, *new) = (first, second);
+            }
+            Command::Serve { socket } if socket.as_os_str().is_empty() => {
+                return Err("The serve command requires a --socket path".to_owned());
+            }
+            Command::Recover if journal_path.is_none() => {
+                return Err("The recover command requires a --journal path".to_owned());
+            }
+            _ => {}
+        }
+        if scan_opts
+            .max_size
+            .is_some_and(|max| max < scan_opts.min_size)
+        {
+            return Err("--max-size must not be smaller than --min-size".to_owned());
+        }
+        if let (Some(before), Some(after)) = (scan_opts.modified_before, scan_opts.modified_after) {
+            if after >= before {
+                return Err("--newer-than must be earlier than --older-than".to_owned());
+            }
+        }
+        let cgroup = match cgroup_path {
+            Some(path) => Some(CgroupOptions {
+                path,
+                io_max: cgroup_io_max,
+                cpu_weight: cgroup_cpu_weight,
+            }),
+            None if !cgroup_io_max.is_empty() || cgroup_cpu_weight.is_some() => {
+                return Err("Cgroup limits require --cgroup".to_owned());
+            }
+            None => None,
+        };
+        // Deleting is the 1 action that can't be undone, so it's never done
+        // without someone either answering a prompt or asking for it outright
+        let unprompted = dedup_opts.prompt_mode == PromptUserMode::DefaultYes
+            || dedup_opts.auto_known
+            || dedup_opts.auto_link.is_some();
+        if dedup_opts.action == LinkAction::Delete && unprompted && !dedup_opts.dry_run && !force {
+            return Err("--action delete requires --force to delete without prompting".to_owned());
+        }
+        if scan_opts.skip_unchanged && state_path.is_none() {
+            return Err("--skip-unchanged requires a --state file".to_owned());
+        }
+        if outputs.iter().filter(|output| output.is_stdout()).count() > 1 {
+            return Err("Only 1 output can be written to stdout".to_owned());
+        }
+        if link_into.is_some() && dedup_opts.action == LinkAction::Symlink {
+            return Err("--link-into can't be used with --action symlink".to_owned());
+        }
+        if let Some(dir) = link_into {
+            // A dry run never adds anything to the store, so it isn't created
+            if !dedup_opts.dry_run {
+                fs::create_dir_all(&dir)
+                    .map_err(|e| format!("Error creating store {}: {e}", dir.display()))?;
+            }
+            let dir = canonicalize_missing(&dir)
+                .map_err(|e| format!("Invalid store directory {}: {e}", dir.display()))?;
+            dedup_opts.link_into = Some(dir);
+        }
+        scan_opts.exclude = PathPatterns::new(&exclude)?;
+        scan_opts.include = PathPatterns::new(&include)?;
+        dedup_opts.protect = PathPatterns::new(&protect)?;
+        let reads_stdin = file_list.iter().chain(&files_from).any(FileList::is_stdin);
+        if reads_stdin
+            && command == Command::Dedup
+            && dedup_opts.prompt_mode == PromptUserMode::Prompt
+        {
+            return Err(
+                "Prompts can't be answered while a file list is read from stdin; \
+                 pass --default-yes or --default-no"
+                    .to_owned(),
+            );
+        }
+        // Listed directories are walked like the positional ones, while listed
+        // files are scanned on their own
+        let mut files = Vec::new();
+        for list in &files_from {
+            let paths = list
+                .read()
+                .map_err(|e| format!("Error reading {}: {e}", list.source.display()))?;
+            for path in paths {
+                match fs::metadata(&path) {
+                    Ok(meta) if meta.is_dir() => dirs.push(path),
+                    Ok(_) => files.push(path),
+                    Err(e) => return Err(format!("Cannot scan {}: {e}", path.display())),
+                }
+            }
+        }
+        if file_list.is_some() && (!dirs.is_empty() || !files.is_empty()) {
+            return Err("Directories can't be passed along with a file list".to_owned());
+        }
+        if command.scans_dirs() {
+            for dir in &dirs {
+                match fs::metadata(dir) {
+                    Ok(meta) if meta.is_dir() => {}
+                    Ok(_) => return Err(format!("{} is not a directory", dir.display())),
+                    Err(e) => return Err(format!("Cannot scan {}: {e}", dir.display())),
+                }
+            }
+        }
+        if dirs.is_empty() && files.is_empty() {
+            let curdir =
+                std::env::current_dir().map_err(|e| format!("Error getting cwd: {e:?}"))?;
+            dirs.push(curdir);
+        }
+        // The store is scanned too, so that new copies of stored content are
+        // linked to the existing entry
+        if let Some(store) = dedup_opts
+            .link_into
+            .as_ref()
+            .filter(|_| command == Command::Dedup && file_list.is_none())
+            // A dry run's store may not exist yet, leaving nothing to scan
+            .filter(|store| store.exists())
+        {
+            let scanned = dirs
+                .iter()
+                .any(|dir| fs::canonicalize(dir).is_ok_and(|dir| store.starts_with(dir)));
+            if !scanned {
+                dirs.push(store.clone());
+            }
+        }
+        Ok(Self {
+            command,
+            dirs,
+            files,
+            dedup_opts,
+            state_path,
+            read_opts,
+            damaged_log,
+            scan_opts,
+            outputs,
+            journal_path,
+            save_scan,
+            tune_sampling,
+            sample_size,
+            max_samples,
+            hash_algo,
+            no_zero_resampling,
+            file_list,
+            index_path,
+            walk_checkpoint,
+            status_fifo,
+            append_scans,
+            quick_estimate,
+            cgroup,
+            verbosity,
+            learn_excludes,
+            profile: profile.source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// The flags mentioned anywhere in [USAGE].
+    fn usage_flags() -> HashSet<&'static str> {
+        USAGE
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+            .filter(|word| word.starts_with('-') && !matches!(*word, "-" | "--"))
+            .collect()
+    }
+
+    #[test]
+    fn every_flag_is_documented() {
+        let documented = usage_flags();
+        for (flag, _) in FLAGS.iter().chain(CLI_ONLY_FLAGS) {
+            assert!(documented.contains(flag), "{flag} is missing from USAGE");
+        }
+    }
+
+    #[test]
+    fn every_documented_flag_is_accepted() {
+        for flag in usage_flags() {
+            assert!(
+                is_verbose_flag(flag)
+                    || FLAGS
+                        .iter()
+                        .chain(CLI_ONLY_FLAGS)
+                        .any(|(known, _)| *known == flag),
+                "{flag} is in USAGE but not FLAGS"
+            );
+        }
+    }
+
+    #[test]
+    fn help_ignores_bad_environment() {
+        env::set_var("HLDUP_MIN_SIZE", "not a size");
+        let args = AppArgs::parse(&["--help"]);
+        let version = AppArgs::parse(&["--max-size", "1", "-V"]);
+        let dedup = AppArgs::parse(&["--dry-run"]);
+        env::remove_var("HLDUP_MIN_SIZE");
+        assert_eq!(args.map(|args| args.command), Ok(Command::Help));
+        assert_eq!(version.map(|args| args.command), Ok(Command::Version));
+        assert!(dedup.is_err());
+    }
+}
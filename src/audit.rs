@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dupchecks::Safety,
+    events::Event,
+    hashcache::{ContentDigest, GroupId},
+    output::open_output,
+    summary::RunSummary,
+    utils::format_size,
+};
+
+/// The version of the `--report` file's schema, following the same rules as
+/// [crate::events::EVENT_SCHEMA_VERSION].
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// A structured record of what deduplication found & did, written to the
+/// `--report` file at the end of the run for auditing.
+///
+/// It's built from the same [Event]s as the `--events` stream, but grouped by
+/// duplicate group & totalled rather than streamed.
+#[derive(Debug, Default, Serialize)]
+pub struct AuditReport {
+    version: u32,
+    /// Every group of possible duplicates, in the order they were handled.
+    groups: Vec<GroupReport>,
+    totals: ReportTotals,
+    /// The position of each group in `groups`.
+    #[serde(skip)]
+    positions: HashMap<GroupId, usize>,
+}
+
+/// Everything that happened to a single group of possible duplicates.
+///
+/// The group's ID comes from the sampled hashes it was found by, so it's only
+/// comparable between runs with the same sampling, and files whose contents
+/// differ can still share it; the `content` digests of the comparisons &
+/// actions identify the actual contents.
+#[derive(Debug, Serialize)]
+struct GroupReport {
+    group: GroupId,
+    size: u64,
+    paths: Vec<PathBuf>,
+    /// The least safe classification of any pair in the group, if any were
+    /// classified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    safety: Option<Safety>,
+    comparisons: Vec<ComparisonReport>,
+    actions: Vec<ActionReport>,
+}
+
+/// 2 files compared byte-for-byte.
+#[derive(Debug, Serialize)]
+struct ComparisonReport {
+    left: PathBuf,
+    right: PathBuf,
+    identical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<ContentDigest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// An action taken (or declined) on a pair of duplicates.
+#[derive(Debug, Serialize)]
+struct ActionReport {
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<ContentDigest>,
+    source: PathBuf,
+    target: PathBuf,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// The totals over the whole run.
+#[derive(Debug, Default, Serialize)]
+struct ReportTotals {
+    groups_found: u64,
+    pairs_compared: u64,
+    identical_pairs: u64,
+    compare_errors: u64,
+    links_made: u64,
+    links_not_made: u64,
+    would_link: u64,
+    files_deleted: u64,
+    deletes_failed: u64,
+    would_delete: u64,
+    reclaimable_bytes: u64,
+    freed_inodes: u64,
+    freed_bytes: u64,
+    would_free_bytes: u64,
+    damaged_files: usize,
+    permission_denied: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stopped_early: Option<String>,
+}
+
+impl AuditReport {
+    /// Constructs an empty [AuditReport].
+    pub fn new() -> Self {
+        Self {
+            version: REPORT_SCHEMA_VERSION,
+            ..Self::default()
+        }
+    }
+
+    /// Adds `event` to the report; events outside of deduplication are
+    /// ignored.
+    pub fn record(&mut self, event: &Event<'_>) {
+        match event {
+            Event::GroupFound { group, size, paths } => {
+                let mut paths = paths
+                    .iter()
+                    .map(|path| path.to_path_buf())
+                    .collect::<Vec<_>>();
+                paths.sort();
+                let report = self.group(*group);
+                report.size = *size;
+                report.paths = paths;
+                self.totals.groups_found += 1;
+            }
+            Event::CompareResult {
+                group,
+                content,
+                left,
+                right,
+                identical,
+                error,
+            } => {
+                self.group(*group).comparisons.push(ComparisonReport {
+                    left: left.to_path_buf(),
+                    right: right.to_path_buf(),
+                    identical: *identical,
+                    content: *content,
+                    error: error.clone(),
+                });
+                self.totals.pairs_compared += 1;
+                if *identical {
+                    self.totals.identical_pairs += 1;
+                }
+                if error.is_some() {
+                    self.totals.compare_errors += 1;
+                }
+            }
+            Event::Classified { group, safety, .. } => {
+                let report = self.group(*group);
+                report.safety = report.safety.max(Some(*safety));
+            }
+            Event::Action {
+                group,
+                content,
+                action,
+                source,
+                target,
+                success,
+                reason,
+            } => {
+                self.group(*group).actions.push(ActionReport {
+                    action,
+                    content: *content,
+                    source: source.to_path_buf(),
+                    target: target.to_path_buf(),
+                    success: *success,
+                    reason: reason.clone(),
+                });
+                match (*action, *success) {
+                    ("link" | "symlink" | "reflink", true) => self.totals.links_made += 1,
+                    ("link" | "symlink" | "reflink", false) => self.totals.links_not_made += 1,
+                    ("would-link" | "would-symlink" | "would-reflink", _) => {
+                        self.totals.would_link += 1
+                    }
+                    ("delete", true) => self.totals.files_deleted += 1,
+                    ("delete", false) => self.totals.deletes_failed += 1,
+                    ("would-delete", _) => self.totals.would_delete += 1,
+                    _ => {}
+                }
+            }
+            Event::ScanStart { .. }
+            | Event::PhaseStart { .. }
+            | Event::FileHashed { .. }
+            | Event::Summary { .. } => {}
+        }
+    }
+
+    /// Fills in the totals only known once the run is over.
+    pub fn finish(&mut self, summary: &RunSummary, stopped_early: Option<String>) {
+        for fs in summary.filesystems.values() {
+            self.totals.reclaimable_bytes += fs.reclaimable_bytes;
+            self.totals.freed_inodes += fs.freed_inodes;
+            self.totals.freed_bytes += fs.freed_bytes;
+        }
+        self.totals.would_free_bytes = summary.dry_run_bytes;
+        self.totals.damaged_files = summary.damaged.len();
+        self.totals.permission_denied = summary.permission_denied.len();
+        self.totals.stopped_early = stopped_early;
+    }
+
+    /// Writes this report to the file at `path`, or stdout if it's `-`, as
+    /// JSON.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = open_output(path)?;
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.write_all(b"\n")?;
+        out.flush()
+    }
+
+    /// Writes the actions in this report to the file at `path`, or stdout if
+    /// it's `-`, as CSV with 1 row per action.
+    pub fn save_csv(&self, path: &Path) -> io::Result<()> {
+        let mut out = open_output(path)?;
+        writeln!(
+            out,
+            "group,action,source,target,success,reason,safety,content"
+        )?;
+        for group in &self.groups {
+            for action in &group.actions {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{}",
+                    group.group,
+                    action.action,
+                    csv_field(&action.source.to_string_lossy()),
+                    csv_field(&action.target.to_string_lossy()),
+                    action.success,
+                    csv_field(action.reason.as_deref().unwrap_or_default()),
+                    group.safety.map_or("", Safety::name),
+                    action
+                        .content
+                        .map(|content| content.to_string())
+                        .unwrap_or_default()
+                )?;
+            }
+        }
+        out.flush()
+    }
+
+    /// The report for `group`, added if it's new.
+    fn group(&mut self, group: GroupId) -> &mut GroupReport {
+        let idx = *self.positions.entry(group).or_insert_with(|| {
+            self.groups.push(GroupReport {
+                group,
+                size: 0,
+                paths: Vec::new(),
+                safety: None,
+                comparisons: Vec::new(),
+                actions: Vec::new(),
+            });
+            self.groups.len() - 1
+        });
+        &mut self.groups[idx]
+    }
+}
+
+/// The parts of a saved `--report` that [diff_plans] compares.
+#[derive(Debug, Deserialize)]
+struct SavedReport {
+    version: u32,
+    groups: Vec<SavedGroup>,
+    totals: SavedTotals,
+}
+
+#[derive(Debug, Deserialize)]
+struct SavedGroup {
+    group: GroupId,
+    size: u64,
+    actions: Vec<SavedAction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SavedAction {
+    action: String,
+    source: PathBuf,
+    target: PathBuf,
+    success: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SavedTotals {
+    freed_bytes: u64,
+    would_free_bytes: u64,
+}
+
+/// A link made or planned by a run, identified by what it does rather than
+/// whether the run was a dry run.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PlanEntry {
+    action: String,
+    source: PathBuf,
+    target: PathBuf,
+}
+
+impl SavedReport {
+    /// Loads the report saved at `path`.
+    fn load(path: &Path) -> io::Result<Self> {
+        let fh = File::open(path)?;
+        let report: Self = serde_json::from_reader(BufReader::new(fh))?;
+        if report.version > REPORT_SCHEMA_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has report version {}, but only versions up to {} are understood",
+                    path.display(),
+                    report.version,
+                    REPORT_SCHEMA_VERSION
+                ),
+            ));
+        }
+        Ok(report)
+    }
+
+    /// The links the run made, or would have under `--dry-run`, each with its
+    /// group & the size of its content.
+    fn plan(&self) -> BTreeMap<PlanEntry, (GroupId, u64)> {
+        let mut plan = BTreeMap::new();
+        for group in &self.groups {
+            for action in &group.actions {
+                let name = action
+                    .action
+                    .strip_prefix("would-")
+                    .unwrap_or(&action.action);
+                // Storing content & reporting duplicates don't link anything
+                if !action.success || matches!(name, "store" | "report") {
+                    continue;
+                }
+                let entry = PlanEntry {
+                    action: name.to_owned(),
+                    source: action.source.clone(),
+                    target: action.target.clone(),
+                };
+                plan.insert(entry, (group.group, group.size));
+            }
+        }
+        plan
+    }
+
+    /// The bytes the run freed, or would have under `--dry-run`.
+    fn savings(&self) -> u64 {
+        self.totals.freed_bytes + self.totals.would_free_bytes
+    }
+}
+
+/// Compares the links made or planned by the runs whose `--report`s were
+/// saved to `old` & `new`, such as dry runs with different options, printing
+/// the links only 1 of them makes & how much more or less the new one frees.
+pub fn diff_plans(old: &Path, new: &Path) -> io::Result<()> {
+    let load = |path: &Path| {
+        SavedReport::load(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("error loading {}: {e}", path.display())))
+    };
+    let (old_report, new_report) = (load(old)?, load(new)?);
+    let (old_plan, new_plan) = (old_report.plan(), new_report.plan());
+    for (path, plan, other) in [(old, &old_plan, &new_plan), (new, &new_plan, &old_plan)] {
+        let unique = plan
+            .iter()
+            .filter(|(entry, _)| !other.contains_key(entry))
+            .collect::<Vec<_>>();
+        println!("{} link(s) only in {}:", unique.len(), path.display());
+        for (entry, (group, size)) in unique {
+            println!(
+                "    {} {} -> {} ({}, group {})",
+                entry.action,
+                entry.target.display(),
+                entry.source.display(),
+                format_size(*size),
+                group
+            );
+        }
+    }
+    let (old_savings, new_savings) = (old_report.savings(), new_report.savings());
+    let (sign, change) = if new_savings >= old_savings {
+        ('+', new_savings - old_savings)
+    } else {
+        ('-', old_savings - new_savings)
+    };
+    println!(
+        "{} frees {}; {} frees {} ({sign}{}).",
+        old.display(),
+        format_size(old_savings),
+        new.display(),
+        format_size(new_savings),
+        format_size(change)
+    );
+    Ok(())
+}
+
+/// Quotes `field` for a CSV file if it contains anything which would otherwise
+/// be misread.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
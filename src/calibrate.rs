@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    io::{self, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use walkdir::WalkDir;
+
+use crate::{
+    hashcache::{SampleParams, MIN_SAMPLES},
+    utils::{format_size, AlignedBuffer, BlockReader, ReadOptions, KB, MB, PAGE_ALIGN},
+};
+
+/// The maximum number of files looked at when calibrating.
+const CALIBRATION_FILES: usize = 2000;
+/// The maximum number of files read from when measuring the storage.
+const TIMED_FILES: usize = 8;
+/// The smallest file read from when measuring the storage, so that the
+/// scattered reads actually have to seek.
+const TIMED_MIN_FILESIZE: u64 = 64 * MB;
+/// The number of bytes read sequentially from each timed file.
+const SEQUENTIAL_READ_SIZE: usize = 4 * MB as usize;
+/// The number of scattered reads made in each timed file.
+const SCATTERED_READS: u64 = 8;
+/// The smallest & largest sample sizes calibration will choose.
+const SAMPLE_SIZE_RANGE: (usize, usize) = (4 * KB as usize, MB as usize);
+
+/// Chooses [SampleParams] suited to the storage & files under `roots` using a
+/// quick calibration pass, logging what was measured & chosen.
+///
+/// Each sample costs roughly a seek plus the time to read it, so samples are
+/// made about as large as what the storage can read sequentially in the time
+/// one seek takes, where they're nearly free. Many files sharing a size means
+/// the sampled hashes are all that keeps them apart, so more samples are taken.
+///
+/// Files already in the page cache make the storage look faster than it is,
+/// so the result is best on a cold cache.
+pub fn tune_sampling(roots: &[PathBuf], read_opts: ReadOptions) -> SampleParams {
+    let defaults = SampleParams::default();
+    let mut sizes: HashMap<u64, usize> = HashMap::new();
+    let mut timed = Vec::new();
+    let files = roots
+        .iter()
+        .flat_map(WalkDir::new)
+        .filter_map(Result::ok)
+        .filter(|ent| ent.file_type().is_file())
+        .take(CALIBRATION_FILES);
+    for ent in files {
+        let Ok(meta) = ent.metadata() else {
+            continue;
+        };
+        *sizes.entry(meta.len()).or_default() += 1;
+        if meta.len() >= TIMED_MIN_FILESIZE && timed.len() < TIMED_FILES {
+            timed.push((ent.into_path(), meta.len()));
+        }
+    }
+
+    let mut throughput = Vec::new();
+    let mut seek_times = Vec::new();
+    for (path, size) in &timed {
+        match time_reads(path, *size, read_opts) {
+            Ok((bytes_per_sec, seek_time)) => {
+                throughput.push(bytes_per_sec);
+                seek_times.push(seek_time);
+            }
+            Err(e) => debug!("Error timing reads of {}: {:?}", path.display(), e),
+        }
+    }
+    let sample_size = if throughput.is_empty() {
+        info!("Found no files large enough to measure the storage with.");
+        defaults.sample_size
+    } else {
+        let bytes_per_sec = median(&mut throughput);
+        let seek_time = median(&mut seek_times);
+        info!(
+            "Measured {}/s sequential reads and {:?} per seek.",
+            format_size(bytes_per_sec as u64),
+            seek_time
+        );
+        let per_seek = (bytes_per_sec * seek_time.as_secs_f64()) as usize;
+        per_seek
+            .next_power_of_two()
+            .clamp(SAMPLE_SIZE_RANGE.0, SAMPLE_SIZE_RANGE.1)
+    };
+
+    let seen = sizes.values().sum::<usize>();
+    let shared = sizes.values().filter(|count| **count > 1).sum::<usize>();
+    let shared_pct = if seen == 0 {
+        0.0
+    } else {
+        100.0 * shared as f64 / seen as f64
+    };
+    info!("{shared_pct:.1}% of the {seen} file(s) looked at share their size with another.");
+    let max_samples = if shared_pct >= 50.0 {
+        defaults.max_samples * 2
+    } else if shared_pct >= 10.0 {
+        defaults.max_samples + defaults.max_samples / 2
+    } else {
+        defaults.max_samples
+    };
+
+    SampleParams {
+        sample_size,
+        max_samples: max_samples.max(MIN_SAMPLES),
+        ..defaults
+    }
+}
+
+/// Measures the sequential throughput in bytes per second, and the time taken
+/// by each scattered read, of the file at `path`.
+fn time_reads(path: &Path, size: u64, read_opts: ReadOptions) -> io::Result<(f64, Duration)> {
+    let mut fh = BlockReader::open(path, read_opts)?;
+    let mut buffer = AlignedBuffer::new(SEQUENTIAL_READ_SIZE, PAGE_ALIGN);
+    let start = Instant::now();
+    let read = fh.read_block(&mut buffer)?;
+    let bytes_per_sec = read as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+
+    let mut small = AlignedBuffer::new(PAGE_ALIGN, PAGE_ALIGN);
+    let start = Instant::now();
+    for idx in 1..=SCATTERED_READS {
+        let offset = size / (SCATTERED_READS + 1) * idx / PAGE_ALIGN as u64 * PAGE_ALIGN as u64;
+        fh.seek(SeekFrom::Start(offset))?;
+        fh.read_block(&mut small)?;
+    }
+    Ok((bytes_per_sec, start.elapsed() / SCATTERED_READS as u32))
+}
+
+/// Finds the median of a non-empty list of values.
+fn median<T: PartialOrd + Copy>(values: &mut [T]) -> T {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values[values.len() / 2]
+}
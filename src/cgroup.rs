@@ -0,0 +1,67 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use log::{debug, info};
+
+use crate::fsinfo::is_cgroup2;
+
+/// The mount point of the cgroup v2 hierarchy, which relative cgroup paths
+/// are resolved against.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The range of weights accepted by `cpu.weight`.
+pub const CPU_WEIGHT_RANGE: std::ops::RangeInclusive<u32> = 1..=10000;
+
+/// A cgroup v2 group the process moves itself into at startup, so that the
+/// kernel enforces its resource limits whatever our own throttles are set to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupOptions {
+    /// The cgroup's directory; relative paths are under [CGROUP_ROOT].
+    pub path: PathBuf,
+    /// Lines written to the cgroup's `io.max`, each limiting 1 device, e.g.
+    /// `8:0 rbps=10485760`.
+    pub io_max: Vec<String>,
+    /// The value written to the cgroup's `cpu.weight`, if any.
+    pub cpu_weight: Option<u32>,
+}
+
+impl CgroupOptions {
+    /// Creates the cgroup if needed, applies its limits, and moves this
+    /// process into it.
+    ///
+    /// The limits are applied before moving, so that nothing runs outside of
+    /// them once we're inside.
+    pub fn join(&self) -> io::Result<()> {
+        // Anything else mounted there would happily accept the directory &
+        // files without limiting anything
+        if !is_cgroup2(Path::new(CGROUP_ROOT))? {
+            return Err(io::Error::other(format!(
+                "{CGROUP_ROOT} is not a cgroup v2 hierarchy"
+            )));
+        }
+        let dir = Path::new(CGROUP_ROOT).join(&self.path);
+        fs::create_dir_all(&dir)?;
+        for line in &self.io_max {
+            debug!("Setting io.max of {} to {line:?}.", dir.display());
+            write_control(&dir, "io.max", line)?;
+        }
+        if let Some(weight) = self.cpu_weight {
+            debug!("Setting cpu.weight of {} to {weight}.", dir.display());
+            write_control(&dir, "cpu.weight", &weight.to_string())?;
+        }
+        write_control(&dir, "cgroup.procs", &process::id().to_string())?;
+        info!("Running in cgroup {}.", dir.display());
+        Ok(())
+    }
+}
+
+/// Writes `value` to the control file `name` of the cgroup at `dir`, naming
+/// the file in any error since the kernel's errors don't.
+fn write_control(dir: &Path, name: &str, value: &str) -> io::Result<()> {
+    let path = dir.join(name);
+    fs::write(&path, value)
+        .map_err(|e| io::Error::new(e.kind(), format!("writing {}: {e}", path.display())))
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+/// How often the walk's progress is written to the checkpoint.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A single line of the checkpoint file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    /// The root being walked.
+    root: PathBuf,
+    /// The files found since the root's previous record.
+    files: Vec<PathBuf>,
+    /// The first entry that hadn't been walked yet; everything sorting before
+    /// it has been.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resume_from: Option<PathBuf>,
+    /// Whether the whole root has been walked.
+    #[serde(default)]
+    complete: bool,
+}
+
+/// How far an earlier run got walking a root.
+#[derive(Debug, Clone, Default)]
+pub struct RootProgress {
+    /// The files the earlier run found.
+    pub files: Vec<PathBuf>,
+    /// The first entry the earlier run hadn't walked yet, if it didn't finish.
+    pub resume_from: Option<PathBuf>,
+    /// Whether the earlier run walked the whole root.
+    pub complete: bool,
+}
+
+/// Periodically records how far the walk of each root has got, written by
+/// `--walk-checkpoint` so that an interrupted run resumes enumerating
+/// directories where it left off rather than starting over from the root.
+///
+/// Roots are walked in sorted order while checkpointing, so that everything
+/// sorting before an entry has been walked by the time it's reached. The file
+/// is only ever appended to, with each record written as a line of JSON; a
+/// line torn by a crash partway through writing it is ignored when resuming.
+///
+/// A checkpoint without a file records nothing, so callers can use it
+/// unconditionally.
+#[derive(Debug, Default)]
+pub struct WalkCheckpoint {
+    /// The checkpoint file & its path.
+    file: Option<(PathBuf, File)>,
+    /// What an earlier run recorded for each root.
+    resumed: HashMap<PathBuf, RootProgress>,
+    /// The files found since the last record was written.
+    pending: Vec<PathBuf>,
+    /// When the last record was written.
+    last_write: Option<Instant>,
+}
+
+impl WalkCheckpoint {
+    /// Constructs a [WalkCheckpoint] which records nothing.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Opens the checkpoint at `path`, creating it if needed, and reads
+    /// whatever an earlier interrupted run recorded in it.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut resumed: HashMap<PathBuf, RootProgress> = HashMap::new();
+        let records = match File::open(path) {
+            Ok(fh) => read_records(fh, path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for record in records {
+            let progress = resumed.entry(record.root).or_default();
+            progress.files.extend(record.files);
+            progress.complete |= record.complete;
+            if record.resume_from.is_some() {
+                progress.resume_from = record.resume_from;
+            }
+        }
+        for (root, progress) in &resumed {
+            info!(
+                "Resuming the walk of {} with {} file(s) found by an earlier run.",
+                root.display(),
+                progress.files.len()
+            );
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(path)?;
+        // End any line torn by a crash, so the next record starts afresh
+        let len = file.metadata()?.len();
+        let mut last = [0];
+        if len > 0 {
+            file.read_exact_at(&mut last, len - 1)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+        Ok(Self {
+            file: Some((path.to_owned(), file)),
+            resumed,
+            pending: Vec::new(),
+            last_write: Some(Instant::now()),
+        })
+    }
+
+    /// Whether progress is being recorded, in which case roots need to be
+    /// walked in sorted order.
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Takes what an earlier run recorded about walking `root`, if anything.
+    pub fn resume(&mut self, root: &Path) -> Option<RootProgress> {
+        self.resumed.remove(root)
+    }
+
+    /// Records that the file at `path` was found.
+    pub fn found(&mut self, path: &Path) {
+        if self.is_enabled() {
+            self.pending.push(path.to_owned());
+        }
+    }
+
+    /// Records that the walk of `root` has reached `entry`, having walked
+    /// everything before it, writing a checkpoint if one is due.
+    pub fn reached(&mut self, root: &Path, entry: &Path) {
+        let due = self
+            .last_write
+            .is_some_and(|last| last.elapsed() >= CHECKPOINT_INTERVAL);
+        if self.is_enabled() && due {
+            self.write(root, Some(entry), false);
+        }
+    }
+
+    /// Records that the whole of `root` has been walked.
+    pub fn finish(&mut self, root: &Path) {
+        if self.is_enabled() {
+            self.write(root, None, true);
+        }
+    }
+
+    /// Removes the checkpoint file once it's no longer needed, so that the
+    /// next run walks everything afresh.
+    pub fn remove(&mut self) {
+        if let Some((path, _)) = self.file.take() {
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Error removing walk checkpoint {}: {:?}", path.display(), e);
+            }
+        }
+    }
+
+    /// Appends a record of the pending files to the checkpoint file.
+    ///
+    /// Since a failed checkpoint only means more of the walk is redone,
+    /// errors are logged and checkpointing stops rather than the walk.
+    fn write(&mut self, root: &Path, resume_from: Option<&Path>, complete: bool) {
+        let Some((path, file)) = &mut self.file else {
+            return;
+        };
+        let record = Record {
+            root: root.to_owned(),
+            files: std::mem::take(&mut self.pending),
+            resume_from: resume_from.map(Path::to_owned),
+            complete,
+        };
+        let res = serde_json::to_string(&record)
+            .map_err(io::Error::from)
+            .and_then(|json| file.write_all(format!("{json}\n").as_bytes()))
+            .and_then(|()| file.sync_data());
+        if let Err(e) = res {
+            error!(
+                "Error writing walk checkpoint {}; no longer checkpointing: {:?}",
+                path.display(),
+                e
+            );
+            self.file = None;
+        }
+        self.last_write = Some(Instant::now());
+    }
+}
+
+/// Reads every intact record from the checkpoint file `fh` at `path`.
+fn read_records(fh: File, path: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (idx, line) in BufReader::new(fh).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(_) => warn!(
+                "Skipping corrupt record on line {} of the walk checkpoint {}.",
+                idx + 1,
+                path.display()
+            ),
+        }
+    }
+    Ok(records)
+}
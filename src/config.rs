@@ -0,0 +1,145 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use hlddup::utils::temp_sibling;
+
+/// A named set of options & roots from the config file, selected with
+/// `--profile`.
+///
+/// The config file is split into profiles by `[name]` headers. Within a
+/// profile, each line starting with `-` is a flag, optionally followed by
+/// whitespace and its value, and every other line is a directory to scan.
+/// Blank lines and lines starting with `#` are ignored:
+///
+/// ```text
+/// [photos]
+/// --default-yes
+/// --exclude **/.thumbnails/**
+/// /srv/photos
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// The profile's flags & their values, as if passed on the command line.
+    pub args: Vec<String>,
+    /// The directories the profile scans when none are passed on the command
+    /// line.
+    pub dirs: Vec<PathBuf>,
+    /// The config file the profile was loaded from & its name, or `None` if no
+    /// profile was selected.
+    pub source: Option<(PathBuf, String)>,
+}
+
+impl Profile {
+    /// Loads the profile called `name` from the config file at `path`.
+    pub fn load(path: &Path, name: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading config {}: {e}", path.display()))?;
+        let mut names = Vec::new();
+        let mut found = None;
+        let mut current: Option<&str> = None;
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = profile_header(line) {
+                names.push(header);
+                current = Some(header);
+                if header == name {
+                    found.get_or_insert_with(Profile::default);
+                }
+                continue;
+            }
+            let Some(current) = current else {
+                return Err(format!(
+                    "{}:{}: options must follow a [profile] header",
+                    path.display(),
+                    idx + 1
+                ));
+            };
+            if current != name {
+                continue;
+            }
+            let profile = found.get_or_insert_with(Profile::default);
+            if line.starts_with('-') {
+                match line.split_once(char::is_whitespace) {
+                    Some((flag, value)) => {
+                        profile.args.push(flag.to_owned());
+                        profile.args.push(value.trim().to_owned());
+                    }
+                    None => profile.args.push(line.to_owned()),
+                }
+            } else {
+                profile.dirs.push(PathBuf::from(line));
+            }
+        }
+        found
+            .map(|profile| Profile {
+                source: Some((path.to_owned(), name.to_owned())),
+                ..profile
+            })
+            .ok_or_else(|| {
+                format!(
+                    "No profile named {name:?} in {}; found {}",
+                    path.display(),
+                    if names.is_empty() {
+                        "none".to_owned()
+                    } else {
+                        names.join(", ")
+                    }
+                )
+            })
+    }
+
+    /// Adds `lines` to the end of the profile called `name` in the config file
+    /// at `path`, leaving the rest of the file as it was.
+    pub fn append(path: &Path, name: &str, lines: &[String]) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading config {}: {e}", path.display()))?;
+        let mut file_lines = contents.lines().collect::<Vec<_>>();
+        let start = file_lines
+            .iter()
+            .position(|line| profile_header(line) == Some(name))
+            .ok_or_else(|| format!("No profile named {name:?} in {}", path.display()))?;
+        let mut end = file_lines[start + 1..]
+            .iter()
+            .position(|line| profile_header(line).is_some())
+            .map_or(file_lines.len(), |idx| start + 1 + idx);
+        // Keep the blank lines separating the profile from the next one
+        while end > start + 1 && file_lines[end - 1].trim().is_empty() {
+            end -= 1;
+        }
+        file_lines.splice(end..end, lines.iter().map(String::as_str));
+        let mut updated = file_lines.join("\n");
+        updated.push('\n');
+        // Written aside & renamed into place, so that a crash can't leave the
+        // config half written
+        let tmp = temp_sibling(path);
+        fs::write(&tmp, updated)
+            .and_then(|()| fs::rename(&tmp, path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&tmp);
+                format!("Error writing config {}: {e}", path.display())
+            })
+    }
+}
+
+/// The name in `line` if it's a `[name]` header starting a profile.
+fn profile_header(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix('[')
+        .and_then(|l| l.strip_suffix(']'))
+        .map(str::trim)
+}
+
+/// The config file used when `--config` isn't passed: `hldup/config` in
+/// `$XDG_CONFIG_HOME`, or in `~/.config` if that isn't set.
+pub fn default_config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("hldup").join("config"))
+}
@@ -0,0 +1,1924 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, IsTerminal},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info, trace, warn};
+
+use crate::{
+    confirm,
+    dupchecks::{
+        boundary, check_link, same_file_digest, CompareProgress, LinkGroup, LinkNotes, Safety,
+        ShouldNotRelinkReason,
+    },
+    events::Event,
+    filters::{CustomFilters, PairFilter, PathPatterns},
+    fsinfo::{link_max, BlockSizes, FsStats},
+    hashcache::{content_digest, ContentDigest, FileRecord, GroupId, HashCache},
+    journal::Outcome,
+    prompt_answer,
+    state::{FileIdentity, FilePair},
+    summary::RunSummary,
+    utils::{
+        format_age, format_size, hard_link, is_writable_dir, phase_span, read_exact_or_end,
+        reflink, symlink_relative, sync_dir, temp_sibling, with_writable_dir, LinkSource,
+        PriorityExtensions, ReadOptions,
+    },
+    PromptAnswer, PromptUserMode, RunContext,
+};
+
+/// Options controlling how duplicates are handled once found.
+#[derive(Debug, Clone, Default)]
+pub struct DedupOptions {
+    /// How to decide whether to link a pair of duplicates.
+    pub prompt_mode: PromptUserMode,
+    /// The free space, in bytes, that must remain on a filesystem for us to
+    /// keep modifying it.
+    pub min_free_bytes: u64,
+    /// The free inode count that must remain on a filesystem for us to keep
+    /// modifying it.
+    pub min_free_inodes: u64,
+    /// Which copy of each set of duplicates is kept.
+    pub keep_rule: KeepRule,
+    /// How copies the [KeepRule] ranks equally are ordered.
+    pub tie_break: TieBreak,
+    /// A directory whose files' inodes should be the ones kept when linking,
+    /// so the surviving content's "primary" location is inside it.
+    pub canonical_under: Option<PathBuf>,
+    /// A content-addressed store directory: the content of every set of
+    /// duplicates is also linked into it, named by its [ContentDigest], and
+    /// copies already in it are the ones kept.
+    pub link_into: Option<PathBuf>,
+    /// Whether symlinks to duplicates may be replaced by hard links; by default
+    /// they're left alone, since that would change their semantics.
+    pub replace_symlinks: bool,
+    /// Whether duplicates of content consolidated in a previous run are linked
+    /// without prompting.
+    pub auto_known: bool,
+    /// The least safe class of duplicates linked without prompting, if any.
+    pub auto_link: Option<Safety>,
+    /// The safest class of duplicates which are never linked, if any.
+    pub never_link: Option<Safety>,
+    /// What to do with duplicates where one copy is sparse and the other isn't.
+    pub sparse_policy: SparsePolicy,
+    /// Extensions whose duplicates are compared & linked first.
+    pub priority: PriorityExtensions,
+    /// What to do with duplicates whose directory we can't write to.
+    pub readonly_dir_policy: ReadOnlyDirPolicy,
+    /// When the directories we've modified are flushed to disk.
+    pub fsync_policy: FsyncPolicy,
+    /// The maximum number of links per inode, overriding what the filesystem
+    /// reports.
+    pub max_links: Option<u64>,
+    /// The maximum number of links we'll give any inode, even if the
+    /// filesystem allows more; larger groups are split across several inodes.
+    pub max_links_per_inode: Option<u64>,
+    /// How to tell whether 2 files are on the same filesystem.
+    pub fs_identity: FsIdentity,
+    /// What to do with duplicates on overlay filesystems.
+    pub overlay_policy: OverlayPolicy,
+    /// Whether to only report the links that would be made, without touching
+    /// the filesystem.
+    pub dry_run: bool,
+    /// What duplicates are replaced with.
+    pub action: LinkAction,
+    /// The action supplied by an embedding program, which takes the place of
+    /// [DedupOptions::action] when set.
+    pub custom_action: Option<CustomAction>,
+    /// Whether to link files smaller than a filesystem block, which are
+    /// skipped by default.
+    pub link_tiny: bool,
+    /// Whether to link files which look like unfinished downloads, which are
+    /// skipped by default.
+    pub link_incomplete: bool,
+    /// The most paths to replace or delete before stopping.
+    pub max_new_links: Option<u64>,
+    /// The bytes to free before stopping.
+    pub max_bytes_saved: Option<u64>,
+    /// Whether to ask the user to confirm the totals of every approved link
+    /// before making any of them, when stdin is a terminal.
+    pub confirm_plan: bool,
+    /// Paths which are never replaced, only linked to, matched against the
+    /// whole path as scanned.
+    pub protect: PathPatterns,
+    /// Filters supplied by an embedding program, which every pair must pass to
+    /// be linked.
+    pub pair_filters: CustomFilters<dyn PairFilter>,
+}
+
+/// What's done with each duplicate once it's been approved, with the copy
+/// that's kept as its source.
+///
+/// The built-in actions are the [LinkAction]s; an embedding program can supply
+/// its own through [DedupOptions::custom_action]. Every action runs after the
+/// same checks, prompts & budgets, and has its operations journaled unless it
+/// doesn't [DedupAction::modify] anything.
+pub trait DedupAction: Send + Sync {
+    /// The name of the action in the event stream & journal, e.g. `link`, or
+    /// e.g. `would-link` for a dry run.
+    fn event_name(&self, dry_run: bool) -> &'static str;
+
+    /// The action as a verb, for prompts & logs.
+    fn verb(&self) -> &'static str;
+
+    /// How the replaced path relates to the kept copy, for logging.
+    fn preposition(&self) -> &'static str {
+        "to"
+    }
+
+    /// Does the action to the duplicate at `path`, keeping `source`, which was
+    /// opened from `target` & verified to still be the file compared.
+    fn replace(&self, source: &LinkSource, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// The temporary path the replacement for `path` is made at before being
+    /// swapped into place, if it's made at one, for the journal.
+    fn temp_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// The paths of `group` that the action needs doing to.
+    fn targets<'a>(&self, group: &'a LinkGroup) -> &'a [PathBuf] {
+        &group.paths
+    }
+
+    /// Whether doing the action to `group` frees its inode, and whether it
+    /// frees the space of its contents.
+    fn frees(&self, group: &LinkGroup) -> (bool, bool) {
+        (group.frees_inode(), group.frees_inode())
+    }
+
+    /// Whether the action touches the filesystem at all, rather than only
+    /// reporting each duplicate.
+    fn modifies(&self) -> bool {
+        true
+    }
+
+    /// Whether the action removes duplicates outright, rather than leaving
+    /// something in their place.
+    fn deletes(&self) -> bool {
+        false
+    }
+
+    /// Whether the action destroys the duplicate's contents in a way that
+    /// can't be undone, so that it must first be checked to still be the file
+    /// that was compared.
+    fn destroys_target(&self) -> bool {
+        true
+    }
+
+    /// Whether the action adds a link to the kept copy's inode, and so is
+    /// bound by the filesystem's limit on links.
+    fn adds_links(&self) -> bool {
+        false
+    }
+
+    /// Whether the action works across filesystems & mounts.
+    fn crosses_filesystems(&self) -> bool {
+        false
+    }
+}
+
+/// A [DedupAction] supplied by an embedding program in place of the
+/// [LinkAction].
+#[derive(Clone)]
+pub struct CustomAction(pub Arc<dyn DedupAction>);
+
+impl fmt::Debug for CustomAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomAction(..)")
+    }
+}
+
+/// What each duplicate is replaced with, pointing at the copy that's kept, or
+/// whether it's deleted outright or only reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LinkAction {
+    /// A hard link, so both paths share a single inode.
+    #[default]
+    Hardlink,
+    /// A relative symlink, which can also point across filesystems.
+    Symlink,
+    /// A copy-on-write clone, which shares the kept copy's data blocks but
+    /// stays a separate file, on filesystems supporting it.
+    Reflink,
+    /// Nothing: the duplicate is removed, leaving only the kept copy.
+    Delete,
+    /// Nothing changes: each duplicate is only logged & emitted as a `report`
+    /// event, without pretending to be any particular action.
+    Report,
+}
+
+impl LinkAction {
+    /// Parses a [LinkAction] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "hardlink" => Ok(Self::Hardlink),
+            "symlink" => Ok(Self::Symlink),
+            "reflink" => Ok(Self::Reflink),
+            "delete" => Ok(Self::Delete),
+            "report" => Ok(Self::Report),
+            other => Err(format!(
+                "Unknown action {other:?}; expected hardlink, symlink, reflink, delete, or report"
+            )),
+        }
+    }
+}
+
+impl DedupAction for LinkAction {
+    fn event_name(&self, dry_run: bool) -> &'static str {
+        match (self, dry_run) {
+            (Self::Hardlink, false) => "link",
+            (Self::Hardlink, true) => "would-link",
+            (Self::Symlink, false) => "symlink",
+            (Self::Symlink, true) => "would-symlink",
+            (Self::Reflink, false) => "reflink",
+            (Self::Reflink, true) => "would-reflink",
+            (Self::Delete, false) => "delete",
+            (Self::Delete, true) => "would-delete",
+            (Self::Report, _) => "report",
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            Self::Hardlink => "hard-link",
+            Self::Symlink => "symlink",
+            Self::Reflink => "reflink",
+            Self::Delete => "delete",
+            Self::Report => "report",
+        }
+    }
+
+    fn preposition(&self) -> &'static str {
+        match self {
+            Self::Hardlink | Self::Symlink | Self::Reflink => "to",
+            Self::Delete | Self::Report => "as a duplicate of",
+        }
+    }
+
+    /// Atomically replaces the file at `path` with a link to `source`, or
+    /// deletes it.
+    fn replace(&self, source: &LinkSource, target: &Path, path: &Path) -> io::Result<()> {
+        match self {
+            Self::Hardlink => hard_link(source, path),
+            Self::Symlink => symlink_relative(target, path),
+            Self::Reflink => reflink(source, path),
+            Self::Delete => fs::remove_file(path),
+            Self::Report => Ok(()),
+        }
+    }
+
+    fn temp_path(&self, path: &Path) -> Option<PathBuf> {
+        match self {
+            Self::Hardlink | Self::Symlink => Some(temp_sibling(path)),
+            Self::Reflink | Self::Delete | Self::Report => None,
+        }
+    }
+
+    /// A reflink clones into the group's inode itself, so every other path
+    /// sharing it sees the clone through the first.
+    fn targets<'a>(&self, group: &'a LinkGroup) -> &'a [PathBuf] {
+        match self {
+            Self::Reflink => &group.paths[..1],
+            Self::Hardlink | Self::Symlink | Self::Delete | Self::Report => &group.paths,
+        }
+    }
+
+    /// A reflink keeps the inode but shares its blocks with the keeper, even
+    /// when it has links outside of the scanned paths. A report counts what
+    /// hard-linking would free.
+    fn frees(&self, group: &LinkGroup) -> (bool, bool) {
+        match self {
+            Self::Reflink => (false, true),
+            Self::Hardlink | Self::Symlink | Self::Delete | Self::Report => {
+                (group.frees_inode(), group.frees_inode())
+            }
+        }
+    }
+
+    fn modifies(&self) -> bool {
+        *self != Self::Report
+    }
+
+    fn deletes(&self) -> bool {
+        *self == Self::Delete
+    }
+
+    /// A reflink overwrites the duplicate's inode in place, so whatever was
+    /// written to it since it was compared would be lost.
+    fn destroys_target(&self) -> bool {
+        matches!(self, Self::Delete | Self::Reflink)
+    }
+
+    fn adds_links(&self) -> bool {
+        *self == Self::Hardlink
+    }
+
+    fn crosses_filesystems(&self) -> bool {
+        *self == Self::Symlink
+    }
+}
+
+/// Which copy of a set of duplicates is kept, with the others replaced by
+/// links to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum KeepRule {
+    /// The copy with the most links among the scanned paths, so that the
+    /// fewest links need to be made.
+    #[default]
+    MostLinks,
+    /// The copy with the fewest directories in its path.
+    ShallowestPath,
+    /// The copy with the shortest file name.
+    ShortestName,
+    /// The copy whose path sorts first.
+    LexicographicallyFirst,
+}
+
+impl KeepRule {
+    /// Parses a [KeepRule] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "most-links" => Ok(Self::MostLinks),
+            "shallowest-path" => Ok(Self::ShallowestPath),
+            "shortest-name" => Ok(Self::ShortestName),
+            "lexicographically-first" => Ok(Self::LexicographicallyFirst),
+            other => Err(format!(
+                "Unknown keep rule {other:?}; expected most-links, shallowest-path, \
+                 shortest-name, or lexicographically-first"
+            )),
+        }
+    }
+
+    /// Reorders `groups` so that the one to keep comes first, with each
+    /// group's paths ordered so that its representative is the path this rule
+    /// prefers. Groups this rule ranks equally are ordered by `tie_break`, and
+    /// paths within a group by their order.
+    fn apply(self, tie_break: TieBreak, groups: &mut [LinkGroup]) {
+        let rank = |path: &Path| match self {
+            Self::MostLinks | Self::LexicographicallyFirst => 0,
+            Self::ShallowestPath => path.components().count(),
+            Self::ShortestName => path.file_name().map_or(0, |name| name.len()),
+        };
+        for group in groups.iter_mut() {
+            group
+                .paths
+                .sort_by_cached_key(|path| (rank(path), path.to_owned()));
+        }
+        groups.sort_by_cached_key(|group| {
+            let links = match self {
+                Self::MostLinks => group.paths.len(),
+                _ => 0,
+            };
+            let inode = match tie_break {
+                TieBreak::Path => (0, 0),
+                TieBreak::Inode => (group.dev, group.ino),
+            };
+            let representative = group.representative();
+            (
+                Reverse(links),
+                rank(representative),
+                inode,
+                representative.to_owned(),
+            )
+        });
+    }
+}
+
+/// How copies of a set of duplicates that the [KeepRule] ranks equally are
+/// ordered, so that the same copy is kept on every run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TieBreak {
+    /// The copy whose path sorts first.
+    #[default]
+    Path,
+    /// The copy with the lowest inode number, so that renaming files doesn't
+    /// change which is kept.
+    Inode,
+}
+
+impl TieBreak {
+    /// Parses a [TieBreak] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "path" => Ok(Self::Path),
+            "inode" => Ok(Self::Inode),
+            other => Err(format!(
+                "Unknown tie-break {other:?}; expected path or inode"
+            )),
+        }
+    }
+}
+
+/// How 2 files are determined to be on the same filesystem, and therefore
+/// linkable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FsIdentity {
+    /// The files must have the same device ID, and be on the same mount if
+    /// the kernel reports mount IDs.
+    #[default]
+    Device,
+    /// The files must be on the same mount, regardless of their device IDs;
+    /// for overlay & container setups where the device IDs of files don't
+    /// match the mount they're accessed through.
+    Mount,
+}
+
+impl FsIdentity {
+    /// Parses a [FsIdentity] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "device" => Ok(Self::Device),
+            "mount" => Ok(Self::Mount),
+            other => Err(format!(
+                "Unknown filesystem identity {other:?}; expected device or mount"
+            )),
+        }
+    }
+}
+
+/// When directories modified by linking are `fsync`ed, making the links
+/// durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FsyncPolicy {
+    /// After each batch of links on a filesystem.
+    #[default]
+    Batch,
+    /// After every link.
+    Each,
+    /// Never; the kernel flushes them whenever it gets around to it.
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Parses a [FsyncPolicy] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "batch" => Ok(Self::Batch),
+            "each" => Ok(Self::Each),
+            "never" => Ok(Self::Never),
+            other => Err(format!(
+                "Unknown fsync policy {other:?}; expected batch, each, or never"
+            )),
+        }
+    }
+}
+
+/// The number of links made on a filesystem between each `fsync` under
+/// [FsyncPolicy::Batch].
+const LINK_BATCH_SIZE: usize = 64;
+
+/// The number of bytes read from the start of a file to decide whether it's
+/// text & preview it in prompts.
+const PREVIEW_BYTES: usize = 4096;
+/// The maximum number of lines of a text file previewed in prompts.
+const PREVIEW_LINES: usize = 4;
+
+/// How to handle a duplicate whose parent directory isn't writable, which
+/// prevents us from replacing it even if the file itself is writable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ReadOnlyDirPolicy {
+    /// Don't link it.
+    #[default]
+    Skip,
+    /// Temporarily make the directory writable, restoring its permissions once
+    /// the link is done.
+    Chmod,
+}
+
+impl ReadOnlyDirPolicy {
+    /// Parses a [ReadOnlyDirPolicy] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "skip" => Ok(Self::Skip),
+            "chmod" => Ok(Self::Chmod),
+            other => Err(format!(
+                "Unknown read-only directory policy {other:?}; expected skip or chmod"
+            )),
+        }
+    }
+}
+
+/// How to handle a pair of duplicates where one file is sparse and the other is
+/// fully allocated.
+///
+/// Linking them makes both paths share the allocation of whichever file is
+/// kept, which can be surprising for e.g. VM disk images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SparsePolicy {
+    /// Link them, but point out the difference when prompting & logging.
+    #[default]
+    Warn,
+    /// Never link them.
+    Skip,
+    /// Link them without comment.
+    Link,
+}
+
+impl SparsePolicy {
+    /// Parses a [SparsePolicy] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "warn" => Ok(Self::Warn),
+            "skip" => Ok(Self::Skip),
+            "link" => Ok(Self::Link),
+            other => Err(format!(
+                "Unknown sparse policy {other:?}; expected warn, skip, or link"
+            )),
+        }
+    }
+}
+
+/// What to do with duplicates on an overlay filesystem.
+///
+/// Linking a file that lives in a lower layer makes the overlay copy it up
+/// into the upper layer first, so the link can end up using more space than it
+/// frees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverlayPolicy {
+    /// Never link them.
+    #[default]
+    Skip,
+    /// Link them with a warning, without counting them as freeing anything.
+    Link,
+}
+
+impl OverlayPolicy {
+    /// Parses an [OverlayPolicy] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "skip" => Ok(Self::Skip),
+            "link" => Ok(Self::Link),
+            other => Err(format!(
+                "Unknown overlay policy {other:?}; expected skip or link"
+            )),
+        }
+    }
+}
+
+/// The reason the deduplication phase was stopped before it finished.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StopReason {
+    /// The filesystem containing the path has less free space than the
+    /// configured minimum.
+    LowFreeSpace { path: PathBuf, free: u64, min: u64 },
+    /// The filesystem containing the path has fewer free inodes than the
+    /// configured minimum.
+    LowFreeInodes { path: PathBuf, free: u64, min: u64 },
+    /// The run has read as many bytes as `--max-io` allows.
+    ReadBudget { max: u64 },
+    /// An operation couldn't be recorded in the `--journal`, so it wouldn't be
+    /// recoverable after a crash.
+    Journal { error: String },
+    /// The user answered a prompt with quit.
+    UserQuit,
+    /// Replacing the next group would make more than `--max-new-links`
+    /// changes.
+    LinkBudget { max: u64 },
+    /// The run has freed the `--max-bytes-saved` it was asked to.
+    BytesSaved { max: u64 },
+    /// The user didn't confirm the plan, so nothing was changed.
+    NotConfirmed,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::LowFreeSpace { path, free, min } => write!(
+                f,
+                "The filesystem containing {} only has {free} bytes free, below the minimum of {min}.",
+                path.display()
+            ),
+            StopReason::LowFreeInodes { path, free, min } => write!(
+                f,
+                "The filesystem containing {} only has {free} inodes free, below the minimum of {min}.",
+                path.display()
+            ),
+            StopReason::ReadBudget { max } => {
+                write!(f, "Read the --max-io budget of {}.", format_size(*max))
+            }
+            StopReason::Journal { error } => write!(f, "Could not write to the journal: {error}."),
+            StopReason::UserQuit => write!(f, "The user quit."),
+            StopReason::LinkBudget { max } => {
+                write!(f, "Reached the --max-new-links limit of {max} paths.")
+            }
+            StopReason::BytesSaved { max } => {
+                write!(f, "Freed the --max-bytes-saved of {}.", format_size(*max))
+            }
+            StopReason::NotConfirmed => write!(f, "The user didn't confirm the plan."),
+        }
+    }
+}
+
+impl DedupOptions {
+    /// The action done with each approved duplicate.
+    pub fn dedup_action(&self) -> &dyn DedupAction {
+        match &self.custom_action {
+            Some(CustomAction(action)) => action.as_ref(),
+            None => &self.action,
+        }
+    }
+
+    /// Checks that replacing `targets` more paths stays within the
+    /// `--max-new-links` & `--max-bytes-saved` budgets, given what's been done
+    /// so far, or would have been in a dry run.
+    pub fn check_budget(&self, targets: usize, summary: &RunSummary) -> Result<(), StopReason> {
+        let (links, bytes) = if self.dry_run {
+            (
+                summary.dry_run_links + summary.dry_run_deletes,
+                summary.dry_run_bytes,
+            )
+        } else {
+            let freed = summary.filesystems.values().map(|fs| fs.freed_bytes).sum();
+            (summary.linked_files + summary.deleted_files, freed)
+        };
+        if let Some(max) = self.max_new_links {
+            if links.saturating_add(targets as u64) > max {
+                return Err(StopReason::LinkBudget { max });
+            }
+        }
+        if let Some(max) = self.max_bytes_saved {
+            if bytes >= max {
+                return Err(StopReason::BytesSaved { max });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the filesystem containing `path` still has the headroom
+    /// needed to safely relink it.
+    ///
+    /// Relinking temporarily needs an extra directory entry, and running out
+    /// of space partway through fails in confusing ways, so we'd rather stop
+    /// cleanly beforehand.
+    pub fn check_headroom(&self, path: &Path) -> Result<(), StopReason> {
+        if self.min_free_bytes == 0 && self.min_free_inodes == 0 {
+            return Ok(());
+        }
+        let dir = path.parent().unwrap_or(path);
+        let stats = match FsStats::for_path(dir) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Could not check free space for {}: {:?}", dir.display(), e);
+                return Ok(());
+            }
+        };
+        if stats.free_bytes < self.min_free_bytes {
+            return Err(StopReason::LowFreeSpace {
+                path: path.to_owned(),
+                free: stats.free_bytes,
+                min: self.min_free_bytes,
+            });
+        }
+        if stats.free_inodes < self.min_free_inodes {
+            return Err(StopReason::LowFreeInodes {
+                path: path.to_owned(),
+                free: stats.free_inodes,
+                min: self.min_free_inodes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A pair of byte-for-byte identical [LinkGroup]s, where every path in `group`
+/// is to be relinked to the inode of `keeper`.
+#[derive(Debug, Clone)]
+struct PlannedLink {
+    /// The duplicate group both link groups belong to.
+    id: GroupId,
+    /// The size of the content being deduplicated.
+    size: u64,
+    /// The group whose inode is kept.
+    keeper: LinkGroup,
+    /// The group whose paths are replaced.
+    group: LinkGroup,
+    /// The identity of the group's inode as it was compared, to check it's
+    /// still the same file before destroying it.
+    identity: Option<FileIdentity>,
+    /// Anything the user should know before agreeing to the link.
+    notes: LinkNotes,
+    /// The digest of the content, if known, which names its entry in the
+    /// [DedupOptions::link_into] store.
+    digest: Option<ContentDigest>,
+}
+
+impl PlannedLink {
+    fn left(&self) -> &Path {
+        self.keeper.representative()
+    }
+
+    fn right(&self) -> &Path {
+        self.group.representative()
+    }
+
+    /// Records that this link won't be made, and why.
+    fn reject(
+        &self,
+        reason: &ShouldNotRelinkReason,
+        action: &dyn DedupAction,
+        ctx: &mut RunContext,
+    ) {
+        error!(
+            "Not linking {} and {} in group {}. Reason: {}",
+            self.left().display(),
+            self.right().display(),
+            self.id,
+            reason.msg()
+        );
+        ctx.events.emit(Event::Action {
+            group: self.id,
+            content: self.digest,
+            action: action.event_name(false),
+            source: self.left(),
+            target: self.right(),
+            success: false,
+            reason: Some(reason.msg().to_owned()),
+        });
+    }
+}
+
+/// Finds & links the duplicates in `cache`.
+///
+/// This happens in 3 phases: first every possible duplicate is compared to
+/// build up a plan of which files to link, then the plan is checked against
+/// our policies & the user's wishes, and finally the approved links are made,
+/// or just reported under [DedupOptions::dry_run].
+pub fn dedup_files(
+    cache: &HashCache,
+    opts: &DedupOptions,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> Result<(), StopReason> {
+    let plan = plan_links(cache, opts, read_opts, ctx);
+    let mut prompts = PromptState::new(opts.prompt_mode);
+    let approved = approve_links(plan, opts, &mut prompts, ctx);
+    let action = opts.dedup_action();
+    if !opts.dry_run && action.modifies() && !confirm_plan(&approved, opts) {
+        return Err(StopReason::NotConfirmed);
+    }
+    ctx.events.emit(Event::PhaseStart {
+        phase: "link",
+        total: approved
+            .iter()
+            .map(|link| action.targets(&link.group).len())
+            .sum(),
+    });
+    // The links we did plan are still made when we run out of read budget
+    if opts.dry_run || !action.modifies() {
+        let mut budget = Ok(());
+        let mut reported = 0;
+        for link in &approved {
+            budget = opts.check_budget(action.targets(&link.group).len(), &ctx.summary);
+            if budget.is_err() {
+                break;
+            }
+            report_link(link, action, opts.dry_run, ctx);
+            reported += 1;
+        }
+        if opts.dry_run {
+            report_store_entries(&approved[..reported], opts, ctx);
+        }
+        budget?;
+    } else {
+        execute_links(approved, opts, ctx)?;
+    }
+    // The links approved before quitting are still made
+    if prompts.quit {
+        return Err(StopReason::UserQuit);
+    }
+    match read_opts.max_io {
+        Some(max) if ctx.summary.uncompared_groups > 0 => Err(StopReason::ReadBudget { max }),
+        _ => Ok(()),
+    }
+}
+
+/// Compares every set of possible duplicates in `cache`, returning the links
+/// needed to consolidate the ones that are actually identical.
+fn plan_links(
+    cache: &HashCache,
+    opts: &DedupOptions,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> Vec<PlannedLink> {
+    phase_span!(INFO, "compare");
+    let mut dups = cache.duplicates();
+    info!("Found {} possible dupes.", dups.len());
+    ctx.events.emit(Event::PhaseStart {
+        phase: "compare",
+        total: dups.len(),
+    });
+    // High-priority groups go first, then the largest, since those are the
+    // biggest wins if the run is interrupted; groups of the same size are
+    // ordered by ID rather than left in the cache's order, so that repeated
+    // runs handle them in the same order
+    dups.sort_by_cached_key(|dup| {
+        let priority = dup.paths.iter().any(|path| opts.priority.matches(path));
+        (!priority, Reverse(dup.hashes.size()), dup.id())
+    });
+    let mut plan = Vec::new();
+    let mut link_limits: HashMap<u64, u64> = HashMap::new();
+    let mut block_sizes = BlockSizes::default();
+    let total = dups.len();
+    for (idx, dup) in dups.into_iter().enumerate() {
+        if read_opts.io_exhausted() {
+            ctx.summary.uncompared_groups = (total - idx) as u64;
+            break;
+        }
+        let id = dup.id();
+        let size = dup.hashes.size();
+        if !opts.link_tiny && is_tiny(size, &dup.paths, cache.records(), &mut block_sizes) {
+            trace!("Files in group {id} are smaller than a filesystem block; skipping.");
+            ctx.summary.tiny_groups += 1;
+            continue;
+        }
+        ctx.events.emit(Event::GroupFound {
+            group: id,
+            size,
+            paths: dup.paths.iter().map(PathBuf::as_path).collect(),
+        });
+        // Paths which are already hard-linked to each other are treated as a
+        // single logical file, so that we only ever need to compare & link
+        // once per inode rather than once per path.
+        let (mut groups, errors) =
+            LinkGroup::split(&dup.paths, cache.records(), opts.replace_symlinks);
+        for (path, e) in errors {
+            error!("Error reading metadata for {}: {:?}", path.display(), e);
+        }
+        if groups.len() < 2 {
+            if groups.first().is_some_and(|group| group.paths.len() >= 2) {
+                ctx.state.consolidated.insert(id);
+            }
+            trace!("All candidates in group {id} already share an inode; skipping.");
+            continue;
+        }
+
+        for group in &mut groups {
+            group.protected |= group
+                .paths
+                .iter()
+                .any(|path| opts.protect.matches_file(path));
+        }
+        opts.keep_rule.apply(opts.tie_break, &mut groups);
+        // The store takes precedence over the canonical directory, since its
+        // entries must keep the content they're named after
+        for dir in [&opts.canonical_under, &opts.link_into]
+            .into_iter()
+            .flatten()
+        {
+            // The first matching group is the one kept, so move the groups in
+            // the directory to the front; the sort is stable, so the
+            // largest-first order is otherwise preserved
+            groups.sort_by_key(|group| !group.has_path_under(dir));
+            for group in &mut groups {
+                group.prefer_paths_under(dir);
+            }
+        }
+        // Protected files are never replaced, so they take precedence over
+        // everything else
+        groups.sort_by_key(|group| !group.protected);
+
+        // Each group is compared against the groups we've already decided to
+        // keep; if it matches one it gets linked into it, otherwise it becomes
+        // a keeper itself. This handles the case where, say, 3 groups share a
+        // hash but only 2 of them are identical, or where we actually have 2
+        // pairs of identical groups.
+        //
+        // Each keeper also tracks the link count its inode will have once the
+        // planned links are made, so that we never plan more links than the
+        // filesystem allows; a group that would go over the limit becomes
+        // another keeper instead. Groups are only linked into keepers within
+        // the same dataset, so each dataset gets its own keeper.
+        let mut keepers: Vec<(LinkGroup, u64, Option<PathBuf>)> = Vec::new();
+        for group in groups {
+            let limit = *link_limits
+                .entry(group.dev)
+                .or_insert_with(|| link_limit(group.representative(), opts));
+            let dataset = boundary(group.representative()).map(Path::to_owned);
+            let mut limited = false;
+            let mut found = None;
+            for (idx, (keeper, links, keeper_dataset)) in keepers.iter().enumerate() {
+                // Protected groups can't be linked into anything, so each is
+                // kept without comparing it
+                if *keeper_dataset != dataset || group.protected {
+                    continue;
+                }
+                let same = compare_groups(
+                    id,
+                    keeper.representative(),
+                    group.representative(),
+                    cache.records(),
+                    read_opts,
+                    ctx,
+                );
+                if !same {
+                    continue;
+                }
+                if links.saturating_add(group.paths.len() as u64) > limit {
+                    limited = true;
+                    continue;
+                }
+                found = Some(idx);
+                break;
+            }
+            let Some(idx) = found else {
+                if limited {
+                    info!(
+                        "Every identical file in group {id} has reached the limit of {limit} \
+                         links; keeping {} as an additional canonical copy.",
+                        group.representative().display()
+                    );
+                    let fs = ctx.summary.filesystem(group.dev, group.representative());
+                    fs.link_limited_groups += 1;
+                    fs.link_max = Some(limit);
+                }
+                // Adding the content to the store takes another link
+                let stored = opts
+                    .link_into
+                    .as_ref()
+                    .is_some_and(|store| !group.has_path_under(store));
+                let links = group.nlink + u64::from(stored);
+                keepers.push((group, links, dataset));
+                continue;
+            };
+            let (keeper, links, _) = &mut keepers[idx];
+            *links += group.paths.len() as u64;
+            info!(
+                "Found candidates {} and {} in group {id}.",
+                keeper.representative().display(),
+                group.representative().display()
+            );
+            let (frees_inode, frees_bytes) = opts.dedup_action().frees(&group);
+            // The kept copy is sampled, since the duplicate may be deleted
+            let fs = ctx.summary.filesystem(group.dev, keeper.representative());
+            if frees_inode {
+                fs.reclaimable_inodes += 1;
+            }
+            if frees_bytes {
+                fs.reclaimable_bytes += size;
+            }
+            plan.push(PlannedLink {
+                id,
+                size,
+                keeper: keeper.clone(),
+                identity: file_identity(group.representative(), cache.records()),
+                group,
+                notes: LinkNotes::default(),
+                // Comparing the groups just recorded their digest
+                digest: ctx
+                    .state
+                    .verified_content
+                    .get(&id)
+                    .map(|content| content.digest),
+            });
+        }
+    }
+    plan
+}
+
+/// Checks whether files of `size` bytes at `paths` are smaller than a block of
+/// their filesystem.
+fn is_tiny(
+    size: u64,
+    paths: &HashSet<PathBuf>,
+    records: &HashMap<PathBuf, FileRecord>,
+    block_sizes: &mut BlockSizes,
+) -> bool {
+    paths
+        .iter()
+        .find_map(|path| Some((path, records.get(path)?)))
+        .is_some_and(|(path, record)| size < block_sizes.get(record.dev, path))
+}
+
+/// Retrieves the maximum number of links we'll give an inode on the filesystem
+/// containing `path`, or [u64::MAX] if it's unlimited or unknown.
+fn link_limit(path: &Path, opts: &DedupOptions) -> u64 {
+    // Only hard links add links to the kept file
+    if !opts.dedup_action().adds_links() {
+        return u64::MAX;
+    }
+    let per_inode = opts.max_links_per_inode.unwrap_or(u64::MAX);
+    if let Some(max) = opts.max_links {
+        return max.min(per_inode);
+    }
+    let fs_limit = match link_max(path) {
+        Ok(v) => v.unwrap_or(u64::MAX),
+        Err(e) => {
+            debug!(
+                "Could not read the link limit for {}: {:?}",
+                path.display(),
+                e
+            );
+            u64::MAX
+        }
+    };
+    fs_limit.min(per_inode)
+}
+
+/// Retrieves the identity of the file at `path` from its scanned record, or
+/// from the file itself if it has none.
+fn file_identity(path: &Path, records: &HashMap<PathBuf, FileRecord>) -> Option<FileIdentity> {
+    match records.get(path) {
+        Some(record) => Some(FileIdentity::from(record)),
+        None => fs::metadata(path)
+            .ok()
+            .map(|meta| FileIdentity::from_metadata(&meta)),
+    }
+}
+
+/// Checks that the file at `path` is still the one with `identity` that was
+/// compared, rather than one rewritten or swapped into its place since.
+pub(crate) fn verify_target(path: &Path, identity: Option<FileIdentity>) -> io::Result<()> {
+    let found = FileIdentity::from_metadata(&fs::metadata(path)?);
+    match identity {
+        Some(identity) if identity == found => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "{} was changed or replaced after it was compared",
+            path.display()
+        ))),
+    }
+}
+
+/// Checks whether `left` and `right` are byte-for-byte identical, logging any
+/// errors.
+fn compare_groups(
+    id: GroupId,
+    left: &Path,
+    right: &Path,
+    records: &HashMap<PathBuf, FileRecord>,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> bool {
+    phase_span!(DEBUG, "compare_pair", group = %id);
+    // Pairs found to differ in a previous run don't need to be reread, as long
+    // as neither file has changed since
+    let left_id = file_identity(left, records);
+    let right_id = file_identity(right, records);
+    let pair = left_id
+        .zip(right_id)
+        .map(|(left, right)| FilePair::new(left, right));
+    if pair.is_some_and(|pair| ctx.state.known_different.contains(&pair)) {
+        trace!(
+            "Files {} and {} were found to differ in a previous run; not linking.",
+            left.display(),
+            right.display()
+        );
+        return false;
+    }
+
+    // Files already verified to hold the group's content don't need to be read
+    // again; the other file just needs to match the content's digest
+    let verified = ctx.state.verified_content.get(&id);
+    let is_verified = |identity: Option<FileIdentity>| {
+        identity
+            .zip(verified)
+            .is_some_and(|(identity, content)| content.files.contains(&identity))
+    };
+    let digest = match (verified, is_verified(left_id), is_verified(right_id)) {
+        (Some(content), true, true) => Ok(Some(content.digest)),
+        (Some(content), true, false) => check_digest(right, content.digest, read_opts),
+        (Some(content), false, true) => check_digest(left, content.digest, read_opts),
+        // Large comparisons record their progress in the journal, so that one
+        // cut short is picked up where it left off by the next run
+        _ => match pair {
+            Some(pair) => {
+                let start = ctx.journal.compared_prefix(pair);
+                let journal = Mutex::new(&mut ctx.journal);
+                let report = |offset| {
+                    let mut journal = journal.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Err(e) = journal.compared(left, right, pair, offset) {
+                        warn!(
+                            "Error recording the progress comparing {} and {} in the journal: {:?}",
+                            left.display(),
+                            right.display(),
+                            e
+                        );
+                    }
+                };
+                let progress = CompareProgress {
+                    start,
+                    report: &report,
+                };
+                let res = same_file_digest(left, right, read_opts, Some(&progress));
+                // Only a comparison cut short by an error is worth resuming
+                if res.is_ok() {
+                    if let Err(e) = ctx.journal.compare_done(pair) {
+                        warn!(
+                            "Error recording the end of comparing {} and {} in the journal: {:?}",
+                            left.display(),
+                            right.display(),
+                            e
+                        );
+                    }
+                }
+                res
+            }
+            None => same_file_digest(left, right, read_opts, None),
+        },
+    };
+    if let Ok(Some(digest)) = digest {
+        ctx.state
+            .record_verified(id, digest, left_id.into_iter().chain(right_id));
+    }
+    let content = digest.as_ref().ok().copied().flatten();
+    let compared = digest.map(|digest| digest.is_some());
+    ctx.events.emit(Event::CompareResult {
+        group: id,
+        content,
+        left,
+        right,
+        identical: matches!(compared, Ok(true)),
+        error: compared.as_ref().err().map(ToString::to_string),
+    });
+    match compared {
+        Ok(v) => {
+            if !v {
+                if let Some(pair) = pair {
+                    ctx.state.known_different.insert(pair);
+                }
+                trace!(
+                    "Files {} and {} differ; not linking.",
+                    left.display(),
+                    right.display()
+                );
+            }
+            v
+        }
+        Err(e) => {
+            ctx.summary.record_read_error(&e);
+            error!(
+                "Error comparing files {} and {}: {:?}",
+                left.display(),
+                right.display(),
+                e
+            );
+            false
+        }
+    }
+}
+
+/// Checks whether the file at `path` has the content with `digest`, for
+/// [compare_groups], returning the digest if so.
+fn check_digest(
+    path: &Path,
+    digest: ContentDigest,
+    read_opts: ReadOptions,
+) -> Result<Option<ContentDigest>, io::Error> {
+    trace!(
+        "Checking {} against the verified content digest {digest}.",
+        path.display()
+    );
+    let actual = content_digest(path, read_opts)?;
+    Ok((actual == digest).then_some(digest))
+}
+
+/// How the remaining prompts are answered, as changed by the user's answers to
+/// earlier ones.
+#[derive(Debug)]
+struct PromptState {
+    /// How duplicates without an answer for their group are handled.
+    mode: PromptUserMode,
+    /// The answer for the remaining duplicates of each group the user answered
+    /// for as a whole.
+    groups: HashMap<GroupId, bool>,
+    /// Whether the user quit, leaving every remaining duplicate alone.
+    quit: bool,
+}
+
+impl PromptState {
+    /// Constructs a [PromptState] starting in `mode`.
+    fn new(mode: PromptUserMode) -> Self {
+        Self {
+            mode,
+            groups: HashMap::new(),
+            quit: false,
+        }
+    }
+
+    /// Whether a duplicate in group `id` is linked, or `None` if the user
+    /// needs to be asked.
+    fn decided(&self, id: GroupId) -> Option<bool> {
+        if self.quit {
+            return Some(false);
+        }
+        self.groups
+            .get(&id)
+            .copied()
+            .or_else(|| self.mode.as_default())
+    }
+
+    /// Applies the user's `answer` to a prompt about duplicates in the groups
+    /// `ids`, returning whether they're linked.
+    fn answer(&mut self, answer: PromptAnswer, ids: impl IntoIterator<Item = GroupId>) -> bool {
+        match answer {
+            PromptAnswer::Yes => true,
+            PromptAnswer::No => false,
+            PromptAnswer::All => {
+                self.mode = PromptUserMode::DefaultYes;
+                true
+            }
+            PromptAnswer::None => {
+                self.mode = PromptUserMode::DefaultNo;
+                false
+            }
+            PromptAnswer::Group | PromptAnswer::SkipGroup => {
+                let link = answer == PromptAnswer::Group;
+                self.groups.extend(ids.into_iter().map(|id| (id, link)));
+                link
+            }
+            PromptAnswer::Quit => {
+                self.quit = true;
+                false
+            }
+        }
+    }
+}
+
+/// Filters `plan` down to the links which pass our policy checks and that the
+/// user agrees to.
+///
+/// Links needing the user's input are aggregated by the directory of the file
+/// being replaced, so that a directory full of identical files results in a
+/// single prompt rather than 1 per file.
+fn approve_links(
+    plan: Vec<PlannedLink>,
+    opts: &DedupOptions,
+    prompts: &mut PromptState,
+    ctx: &mut RunContext,
+) -> Vec<PlannedLink> {
+    let mut approved = Vec::with_capacity(plan.len());
+    let mut needs_prompt: BTreeMap<PathBuf, Vec<PlannedLink>> = BTreeMap::new();
+    for mut link in plan {
+        link.notes = match check_link(link.left(), link.right(), opts) {
+            Err(e) => {
+                error!(
+                    "IO Error checking candidacy of {} and {}: {:?}",
+                    link.left().display(),
+                    link.right().display(),
+                    e
+                );
+                continue;
+            }
+            Ok(Err(reason)) => {
+                link.reject(&reason, opts.dedup_action(), ctx);
+                continue;
+            }
+            Ok(Ok(notes)) => notes,
+        };
+        ctx.events.emit(Event::Classified {
+            group: link.id,
+            left: link.left(),
+            right: link.right(),
+            safety: link.notes.safety,
+        });
+        if opts
+            .never_link
+            .is_some_and(|class| link.notes.safety >= class)
+        {
+            link.reject(&ShouldNotRelinkReason::Unsafe, opts.dedup_action(), ctx);
+            continue;
+        }
+        if opts.readonly_dir_policy == ReadOnlyDirPolicy::Skip {
+            let readonly = link
+                .group
+                .paths
+                .iter()
+                .filter_map(|path| path.parent())
+                .find(|dir| !is_writable_dir(dir));
+            if let Some(dir) = readonly {
+                debug!("Directory {} is not writable.", dir.display());
+                link.reject(
+                    &ShouldNotRelinkReason::ReadOnlyDirectory,
+                    opts.dedup_action(),
+                    ctx,
+                );
+                continue;
+            }
+        }
+        if link.notes.sparse_mismatch {
+            warn!(
+                "One of {} and {} is sparse and the other is not; after linking both will \
+                 share the allocation of {}.",
+                link.left().display(),
+                link.right().display(),
+                link.left().display()
+            );
+        }
+        if link.notes.overlay {
+            warn!(
+                "{} and {} are on an overlay filesystem; linking may copy them up from a \
+                 lower layer and use more space than it frees.",
+                link.left().display(),
+                link.right().display()
+            );
+        }
+
+        let known = opts.auto_known && ctx.state.consolidated.contains(&link.id);
+        if known {
+            info!(
+                "Content of group {} was consolidated in a previous run; linking without prompting.",
+                link.id
+            );
+        }
+        // An action that changes nothing has nothing to ask about
+        let default = if known
+            || !opts.dedup_action().modifies()
+            || opts
+                .auto_link
+                .is_some_and(|class| link.notes.safety <= class)
+        {
+            Some(true)
+        } else {
+            opts.prompt_mode.as_default()
+        };
+        match default {
+            Some(true) => approved.push(link),
+            Some(false) => {
+                link.reject(&ShouldNotRelinkReason::UserSaidNo, opts.dedup_action(), ctx)
+            }
+            None => {
+                let dir = link.right().parent().unwrap_or(Path::new("/")).to_owned();
+                needs_prompt.entry(dir).or_default().push(link);
+            }
+        }
+    }
+
+    for (dir, links) in needs_prompt {
+        // Earlier answers may have decided some of these already
+        let (links, decided): (Vec<_>, Vec<_>) = links
+            .into_iter()
+            .partition(|link| prompts.decided(link.id).is_none());
+        for link in decided {
+            if prompts.decided(link.id) == Some(true) {
+                approved.push(link);
+            } else {
+                link.reject(&ShouldNotRelinkReason::UserSaidNo, opts.dedup_action(), ctx);
+            }
+        }
+        if links.is_empty() {
+            continue;
+        }
+        let msg = if let [link] = links.as_slice() {
+            format!(
+                "Found candidates {} and {}.{}\n{}{}{}",
+                link.left().display(),
+                link.right().display(),
+                link.notes.prompt_note(),
+                file_preview(link.left(), "    "),
+                file_preview(link.right(), "    "),
+                // Make it clear only 1 of the 2 goes
+                if opts.dedup_action().deletes() {
+                    format!(
+                        "Should we delete {}, keeping {}?",
+                        link.right().display(),
+                        link.left().display()
+                    )
+                } else {
+                    format!("Should we {} them?", opts.dedup_action().verb())
+                }
+            )
+        } else {
+            let mut msg = format!(
+                "Found {} duplicates within {}:\n",
+                links.len(),
+                dir.display()
+            );
+            for link in &links {
+                msg.push_str(&format!(
+                    "    {} -> {}{}\n",
+                    link.right().display(),
+                    link.left().display(),
+                    link.notes.prompt_note()
+                ));
+                msg.push_str(&file_preview(link.right(), "        "));
+                msg.push_str(&file_preview(link.left(), "        "));
+            }
+            msg.push_str(&format!(
+                "Should we {} all {} duplicates within {}?",
+                opts.dedup_action().verb(),
+                links.len(),
+                dir.display()
+            ));
+            msg
+        };
+        let answer = prompt_answer(&msg);
+        if prompts.answer(answer, links.iter().map(|link| link.id)) {
+            approved.extend(links);
+        } else {
+            // Declining everything left says nothing about these duplicates in
+            // particular
+            if !matches!(answer, PromptAnswer::None | PromptAnswer::Quit) {
+                ctx.summary
+                    .declined
+                    .extend(links.iter().map(|link| link.right().to_owned()));
+            }
+            for link in links {
+                link.reject(&ShouldNotRelinkReason::UserSaidNo, opts.dedup_action(), ctx);
+            }
+        }
+    }
+    approved
+}
+
+/// Describes the file at `path` for a prompt: its size, age, owner & mode,
+/// followed by its first few lines if it looks like text. Every line is
+/// prefixed with `indent`.
+fn file_preview(path: &Path, indent: &str) -> String {
+    let meta = match fs::metadata(path) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Error reading metadata for {}: {:?}", path.display(), e);
+            return String::new();
+        }
+    };
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64)
+        .saturating_sub(meta.mtime())
+        .max(0) as u64;
+    let mut preview = format!(
+        "{indent}{}: {}, modified {} ago, owner {}:{}, mode {:o}\n",
+        path.display(),
+        format_size(meta.len()),
+        format_age(age),
+        meta.uid(),
+        meta.gid(),
+        meta.mode() & 0o7777
+    );
+
+    let mut buffer = vec![0; PREVIEW_BYTES];
+    let read = match File::open(path).and_then(|mut fh| read_exact_or_end(&mut fh, &mut buffer)) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Error reading a preview of {}: {:?}", path.display(), e);
+            return preview;
+        }
+    };
+    let Some(text) = as_text(&buffer[..read]) else {
+        return preview;
+    };
+    for line in text.lines().take(PREVIEW_LINES) {
+        preview.push_str(&format!("{indent}  | {line}\n"));
+    }
+    preview
+}
+
+/// Interprets the start of a file as text, or `None` if it looks binary.
+///
+/// The start may end partway through a multi-byte character, which is
+/// trimmed off rather than treated as binary.
+fn as_text(start: &[u8]) -> Option<&str> {
+    if start.contains(&0) {
+        return None;
+    }
+    match std::str::from_utf8(start) {
+        Ok(text) => Some(text),
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&start[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    }
+}
+
+/// Makes the approved links, grouped by filesystem & split into batches.
+///
+/// At the end of each batch the modified directories are synced according to
+/// the [FsyncPolicy] and the event stream is flushed, so that if we're cut off
+/// partway through, everything up to the last batch boundary is both durable
+/// and recorded.
+fn execute_links(
+    approved: Vec<PlannedLink>,
+    opts: &DedupOptions,
+    ctx: &mut RunContext,
+) -> Result<(), StopReason> {
+    phase_span!(INFO, "link", groups = approved.len());
+    let mut by_filesystem: BTreeMap<u64, Vec<PlannedLink>> = BTreeMap::new();
+    for link in approved {
+        by_filesystem.entry(link.group.dev).or_default().push(link);
+    }
+    for (dev, links) in by_filesystem {
+        debug!("Linking {} group(s) on device {dev}.", links.len());
+        for batch in links.chunks(LINK_BATCH_SIZE) {
+            let mut touched = BTreeSet::new();
+            let res = batch
+                .iter()
+                .try_for_each(|link| execute_link(link, opts, &mut touched, ctx));
+            if opts.fsync_policy == FsyncPolicy::Batch {
+                for dir in &touched {
+                    sync_dir_logged(dir);
+                }
+            }
+            ctx.events.flush();
+            res?;
+        }
+    }
+    Ok(())
+}
+
+/// Asks the user to confirm the totals of the `approved` links before any are
+/// made, under [DedupOptions::confirm_plan], returning whether to go ahead.
+///
+/// This only asks when stdin is a terminal, so that scripts passing
+/// `--default-yes` aren't left waiting on an answer.
+fn confirm_plan(approved: &[PlannedLink], opts: &DedupOptions) -> bool {
+    if !opts.confirm_plan || approved.is_empty() || !io::stdin().is_terminal() {
+        return true;
+    }
+    let action = opts.dedup_action();
+    let paths = approved
+        .iter()
+        .map(|link| action.targets(&link.group).len())
+        .sum::<usize>();
+    let bytes = approved
+        .iter()
+        .filter(|link| action.frees(&link.group).1 && !link.notes.overlay)
+        .map(|link| link.size)
+        .sum::<u64>();
+    let filesystems = approved
+        .iter()
+        .map(|link| link.group.dev)
+        .collect::<BTreeSet<_>>()
+        .len();
+    confirm(&format!(
+        "About to {} {paths} path(s) reclaiming ~{} across {filesystems} filesystem(s); proceed?",
+        action.verb(),
+        format_size(bytes)
+    ))
+}
+
+/// Reports the links that would be made for `link` under
+/// [DedupOptions::dry_run], or the duplicates found by an action that doesn't
+/// modify anything, without making them.
+fn report_link(link: &PlannedLink, action: &dyn DedupAction, dry_run: bool, ctx: &mut RunContext) {
+    let left = link.left();
+    let targets = action.targets(&link.group);
+    for path in targets {
+        info!(
+            "{} {} {} {} in group {}.",
+            if dry_run {
+                format!("Would {}", action.verb())
+            } else {
+                "Found".to_owned()
+            },
+            path.display(),
+            action.preposition(),
+            left.display(),
+            link.id
+        );
+        ctx.events.emit(Event::Action {
+            group: link.id,
+            content: link.digest,
+            action: action.event_name(dry_run),
+            source: left,
+            target: path,
+            success: true,
+            reason: None,
+        });
+    }
+    if !dry_run {
+        return;
+    }
+    if action.deletes() {
+        ctx.summary.dry_run_deletes += targets.len() as u64;
+    } else {
+        ctx.summary.dry_run_links += targets.len() as u64;
+    }
+    if action.frees(&link.group).1 && !link.notes.overlay {
+        ctx.summary.dry_run_bytes += link.size;
+    }
+}
+
+/// Reports the entries that would be added to the [DedupOptions::link_into]
+/// store under [DedupOptions::dry_run], without adding them.
+fn report_store_entries(approved: &[PlannedLink], opts: &DedupOptions, ctx: &mut RunContext) {
+    // Every link into the same keeper shares its store entry
+    let entries = approved
+        .iter()
+        .filter_map(|link| Some((store_entry(link, opts)?, link)))
+        .collect::<BTreeMap<_, _>>();
+    for (entry, link) in entries {
+        info!(
+            "Would store {} as {} in group {}.",
+            link.left().display(),
+            entry.display(),
+            link.id
+        );
+        ctx.events.emit(Event::Action {
+            group: link.id,
+            content: link.digest,
+            action: "would-store",
+            source: link.left(),
+            target: &entry,
+            success: true,
+            reason: None,
+        });
+    }
+}
+
+/// The path that the content kept by `link` needs to be added to the
+/// [DedupOptions::link_into] store at, if it isn't there yet.
+///
+/// Contents on a different filesystem than the store can't be linked into it,
+/// so they're left out.
+fn store_entry(link: &PlannedLink, opts: &DedupOptions) -> Option<PathBuf> {
+    let store = opts.link_into.as_ref()?;
+    if link.keeper.has_path_under(store) {
+        return None;
+    }
+    let digest = link.digest?;
+    // A dry run doesn't create the store, so it's judged by where it would be
+    let meta = match fs::metadata(store) {
+        Err(e) if opts.dry_run && e.kind() == io::ErrorKind::NotFound => store
+            .ancestors()
+            .skip(1)
+            .find_map(|dir| fs::metadata(dir).ok())
+            .ok_or(e),
+        res => res,
+    };
+    match meta {
+        Ok(meta) if meta.dev() == link.keeper.dev => {}
+        Ok(_) => {
+            debug!(
+                "{} is on a different filesystem than the store; not storing it.",
+                link.left().display()
+            );
+            return None;
+        }
+        Err(e) => {
+            error!("Error reading the store {}: {:?}", store.display(), e);
+            return None;
+        }
+    }
+    let entry = store.join(digest.to_string());
+    match fs::symlink_metadata(&entry) {
+        // Already added for another link into the same keeper
+        Ok(meta) if meta.dev() == link.keeper.dev && meta.ino() == link.keeper.ino => None,
+        Ok(_) => {
+            warn!(
+                "Store entry {} exists but wasn't scanned as a copy of {}; not storing it.",
+                entry.display(),
+                link.left().display()
+            );
+            None
+        }
+        Err(_) => Some(entry),
+    }
+}
+
+/// Adds the content kept by `link` to the [DedupOptions::link_into] store if
+/// it isn't there yet, adding the store to `touched` if it's modified.
+fn store_content(
+    link: &PlannedLink,
+    source: &LinkSource,
+    opts: &DedupOptions,
+    touched: &mut BTreeSet<PathBuf>,
+    ctx: &mut RunContext,
+) {
+    let Some(entry) = store_entry(link, opts) else {
+        return;
+    };
+    let res = source.link_to(&entry);
+    ctx.events.emit(Event::Action {
+        group: link.id,
+        content: link.digest,
+        action: "store",
+        source: link.left(),
+        target: &entry,
+        success: res.is_ok(),
+        reason: res.as_ref().err().map(ToString::to_string),
+    });
+    match res {
+        Ok(()) => {
+            info!(
+                "Stored {} as {} in group {}.",
+                link.left().display(),
+                entry.display(),
+                link.id
+            );
+            if let Some(store) = entry.parent() {
+                match opts.fsync_policy {
+                    FsyncPolicy::Each => sync_dir_logged(store),
+                    FsyncPolicy::Batch => {
+                        touched.insert(store.to_owned());
+                    }
+                    FsyncPolicy::Never => {}
+                }
+            }
+        }
+        Err(e) => error!(
+            "Failed storing {} as {}: {:?}.",
+            link.left().display(),
+            entry.display(),
+            e
+        ),
+    }
+}
+
+/// Syncs `dir`, logging rather than returning any error since the links
+/// themselves already succeeded.
+fn sync_dir_logged(dir: &Path) {
+    if let Err(e) = sync_dir(dir) {
+        warn!("Error syncing directory {}: {:?}", dir.display(), e);
+    }
+}
+
+/// Relinks every path in the link's group to the inode of its keeper, adding
+/// the directories modified to `touched`.
+fn execute_link(
+    link: &PlannedLink,
+    opts: &DedupOptions,
+    touched: &mut BTreeSet<PathBuf>,
+    ctx: &mut RunContext,
+) -> Result<(), StopReason> {
+    phase_span!(DEBUG, "link_group", group = %link.id);
+    let action = opts.dedup_action();
+    let id = link.id;
+    let left = link.left();
+    let right = link.right();
+    let targets = action.targets(&link.group);
+    opts.check_budget(targets.len(), &ctx.summary)?;
+
+    // Make sure the file we compared is still the one we're about to link to,
+    // in case it was deleted or replaced in the meantime
+    let source = match LinkSource::open_verified(left, link.keeper.dev, link.keeper.ino) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "Not linking {} and {} in group {id}: could not verify the link source: {:?}",
+                left.display(),
+                right.display(),
+                e
+            );
+            ctx.events.emit(Event::Action {
+                group: id,
+                content: link.digest,
+                action: action.event_name(false),
+                source: left,
+                target: right,
+                success: false,
+                reason: Some(e.to_string()),
+            });
+            return Ok(());
+        }
+    };
+    store_content(link, &source, opts, touched, ctx);
+    let mut linked = 0;
+    for path in targets {
+        opts.check_headroom(path)?;
+        let temp = action.temp_path(path);
+        let op = ctx
+            .journal
+            .begin(action.event_name(false), left, path, temp.as_deref())
+            .map_err(|e| StopReason::Journal {
+                error: e.to_string(),
+            })?;
+        // Whatever was written to the target since it was compared would be
+        // lost along with it
+        let verified = if action.destroys_target() {
+            verify_target(path, link.identity)
+        } else {
+            Ok(())
+        };
+        let res = verified.and_then(|()| match (opts.readonly_dir_policy, path.parent()) {
+            (ReadOnlyDirPolicy::Chmod, Some(dir)) => {
+                with_writable_dir(dir, || action.replace(&source, left, path))
+            }
+            _ => action.replace(&source, left, path),
+        });
+        ctx.events.emit(Event::Action {
+            group: id,
+            content: link.digest,
+            action: action.event_name(false),
+            source: left,
+            target: path,
+            success: res.is_ok(),
+            reason: res.as_ref().err().map(ToString::to_string),
+        });
+        let outcome = if res.is_ok() {
+            Outcome::Done
+        } else {
+            Outcome::Failed
+        };
+        ctx.journal
+            .end(op, outcome)
+            .map_err(|e| StopReason::Journal {
+                error: e.to_string(),
+            })?;
+        match res {
+            Ok(()) => {
+                linked += 1;
+                if action.deletes() {
+                    ctx.summary.deleted_files += 1;
+                } else {
+                    ctx.summary.linked_files += 1;
+                }
+                if let Some(dir) = path.parent() {
+                    ctx.state.touch(dir);
+                    match opts.fsync_policy {
+                        FsyncPolicy::Each => sync_dir_logged(dir),
+                        FsyncPolicy::Batch => {
+                            touched.insert(dir.to_owned());
+                        }
+                        FsyncPolicy::Never => {}
+                    }
+                }
+            }
+            Err(e) if is_out_of_space(&e) => {
+                error!(
+                    "Failed linking files {} and {}: the filesystem is out of space or inodes, \
+                     or a quota was exceeded ({:?}).",
+                    left.display(),
+                    path.display(),
+                    e
+                );
+            }
+            Err(e) => {
+                error!(
+                    "Failed linking files {} and {}: {:?}.",
+                    left.display(),
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    if linked == 0 {
+        return Ok(());
+    }
+    if linked == targets.len() {
+        // Only content that's fully linked counts as consolidated, so that
+        // the paths left behind are still prompted for next time
+        ctx.state.consolidated.insert(id);
+        let (frees_inode, frees_bytes) = action.frees(&link.group);
+        if link.notes.overlay {
+            // Whatever was freed in the upper layer may have been offset by
+            // copy-ups, and nothing is freed in the lower layers
+            ctx.summary.filesystem(link.group.dev, left).overlay_links += 1;
+        } else {
+            let fs = ctx.summary.filesystem(link.group.dev, left);
+            if frees_inode {
+                fs.freed_inodes += 1;
+            }
+            if frees_bytes {
+                fs.freed_bytes += link.size;
+            }
+        }
+        if action.deletes() {
+            info!(
+                "Deleted {} path(s) sharing {}, a duplicate of {} in group {id}.",
+                linked,
+                right.display(),
+                left.display()
+            );
+        } else {
+            info!(
+                "Linked {} path(s) sharing {} into {} in group {id}.",
+                linked,
+                right.display(),
+                left.display()
+            );
+        }
+    } else {
+        error!(
+            "Only linked {} of {} path(s) sharing {} into {} in group {id}.",
+            linked,
+            targets.len(),
+            right.display(),
+            left.display()
+        );
+    }
+    Ok(())
+}
+
+fn is_out_of_space(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_link_actions() {
+        assert_eq!(LinkAction::parse("hardlink"), Ok(LinkAction::Hardlink));
+        assert_eq!(LinkAction::parse("symlink"), Ok(LinkAction::Symlink));
+        assert_eq!(LinkAction::parse("reflink"), Ok(LinkAction::Reflink));
+        assert_eq!(LinkAction::parse("delete"), Ok(LinkAction::Delete));
+        assert_eq!(LinkAction::parse("report"), Ok(LinkAction::Report));
+        assert!(LinkAction::parse("link").is_err());
+        assert!(LinkAction::parse("Hardlink").is_err());
+    }
+
+    #[test]
+    fn only_reporting_changes_nothing() {
+        assert!(!LinkAction::Report.modifies());
+        assert!(LinkAction::Delete.deletes());
+        assert!(LinkAction::Delete.destroys_target());
+        assert!(!LinkAction::Hardlink.deletes());
+    }
+}
@@ -0,0 +1,251 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use crate::{
+    checkpoint::WalkCheckpoint,
+    dedup::{
+        dedup_files, CustomAction, DedupAction, DedupOptions, KeepRule, LinkAction, StopReason,
+    },
+    events::{EventSink, Observer},
+    filters::PathPatterns,
+    hashcache::{ContentHasher, CustomHasher},
+    journal::Journal,
+    scan::hash_roots,
+    state::PersistentState,
+    summary::RunSummary,
+    utils::ReadOptions,
+    PromptUserMode, RunContext, ScanOptions,
+};
+
+/// Runs the whole scan, compare & link pipeline over a set of directories, for
+/// programs which want to deduplicate files without going through the command
+/// line.
+///
+/// ```no_run
+/// use hlddup::{dedup::LinkAction, Deduper};
+///
+/// let outcome = Deduper::builder()
+///     .roots(["/srv/media", "/srv/backup"])
+///     .min_size(1 << 20)
+///     .action(LinkAction::Hardlink)
+///     .build()?
+///     .run();
+/// println!("Linked {} path(s).", outcome.summary.linked_files);
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Clone)]
+pub struct Deduper {
+    roots: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    scan_opts: ScanOptions,
+    dedup_opts: DedupOptions,
+    read_opts: ReadOptions,
+    observers: Vec<Arc<dyn Observer>>,
+}
+
+impl fmt::Debug for Deduper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deduper")
+            .field("roots", &self.roots)
+            .field("files", &self.files)
+            .field("scan_opts", &self.scan_opts)
+            .field("dedup_opts", &self.dedup_opts)
+            .field("read_opts", &self.read_opts)
+            .field("observers", &self.observers.len())
+            .finish()
+    }
+}
+
+/// What a [Deduper] run found & did.
+#[derive(Debug)]
+pub struct DedupOutcome {
+    /// The statistics collected over the run, including the links made & the
+    /// files which couldn't be read.
+    pub summary: RunSummary,
+    /// Why linking stopped before every duplicate was handled, if it did.
+    pub stopped_early: Option<StopReason>,
+}
+
+impl Deduper {
+    /// Constructs a [DeduperBuilder] with nothing to scan yet.
+    ///
+    /// Unlike the command line, a [Deduper] links every duplicate it finds
+    /// without prompting unless told otherwise with
+    /// [DeduperBuilder::prompt_mode].
+    pub fn builder() -> DeduperBuilder {
+        DeduperBuilder::default()
+    }
+
+    /// Scans every root, then compares & links the duplicates found.
+    ///
+    /// Nothing is carried over between runs, so each run hashes every file
+    /// afresh.
+    pub fn run(&self) -> DedupOutcome {
+        let mut state = PersistentState::default();
+        state.use_sampling(self.read_opts.sampling);
+        let mut events = EventSink::disabled();
+        for observer in &self.observers {
+            events.add_observer(observer.clone());
+        }
+        let mut ctx = RunContext {
+            state,
+            summary: RunSummary::new(),
+            events,
+            journal: Journal::disabled(),
+            checkpoint: WalkCheckpoint::disabled(),
+        };
+        let cache = hash_roots(
+            &self.roots,
+            &self.files,
+            &self.scan_opts,
+            self.read_opts,
+            &mut ctx,
+        );
+        let res = dedup_files(&cache, &self.dedup_opts, self.read_opts, &mut ctx);
+        DedupOutcome {
+            summary: ctx.summary,
+            stopped_early: res.err(),
+        }
+    }
+}
+
+/// A builder for [Deduper]s; anything not set is left as the command line's
+/// default, other than the [PromptUserMode].
+#[derive(Debug, Clone)]
+pub struct DeduperBuilder {
+    deduper: Deduper,
+}
+
+impl Default for DeduperBuilder {
+    fn default() -> Self {
+        Self {
+            deduper: Deduper {
+                roots: Vec::new(),
+                files: Vec::new(),
+                scan_opts: ScanOptions::default(),
+                dedup_opts: DedupOptions {
+                    prompt_mode: PromptUserMode::DefaultYes,
+                    ..DedupOptions::default()
+                },
+                read_opts: ReadOptions::default(),
+                observers: Vec::new(),
+            },
+        }
+    }
+}
+
+impl DeduperBuilder {
+    /// Adds directories to walk & deduplicate.
+    pub fn roots<P: Into<PathBuf>>(mut self, roots: impl IntoIterator<Item = P>) -> Self {
+        self.deduper.roots.extend(roots.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds individual files to deduplicate alongside the roots.
+    pub fn files<P: Into<PathBuf>>(mut self, files: impl IntoIterator<Item = P>) -> Self {
+        self.deduper.files.extend(files.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets the size in bytes of the smallest file to scan.
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.deduper.scan_opts.min_size = size;
+        self
+    }
+
+    /// Sets the size in bytes of the largest file to scan.
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.deduper.scan_opts.max_size = Some(size);
+        self
+    }
+
+    /// Sets the paths to skip, relative to the root being scanned.
+    pub fn exclude(mut self, patterns: PathPatterns) -> Self {
+        self.deduper.scan_opts.exclude = patterns;
+        self
+    }
+
+    /// Sets what duplicates are replaced with.
+    pub fn action(mut self, action: LinkAction) -> Self {
+        self.deduper.dedup_opts.action = action;
+        self
+    }
+
+    /// Sets an action of the program's own to do with each duplicate, in place
+    /// of the [LinkAction].
+    pub fn custom_action(mut self, action: impl DedupAction + 'static) -> Self {
+        self.deduper.dedup_opts.custom_action = Some(CustomAction(Arc::new(action)));
+        self
+    }
+
+    /// Sets which copy of each set of duplicates is kept.
+    pub fn keep_rule(mut self, rule: KeepRule) -> Self {
+        self.deduper.dedup_opts.keep_rule = rule;
+        self
+    }
+
+    /// Sets how to decide whether to link each set of duplicates.
+    pub fn prompt_mode(mut self, mode: PromptUserMode) -> Self {
+        self.deduper.dedup_opts.prompt_mode = mode;
+        self
+    }
+
+    /// Sets whether to only count the links that would be made, without
+    /// touching the filesystem.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.deduper.dedup_opts.dry_run = dry_run;
+        self
+    }
+
+    /// Adds an observer notified as the run progresses.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.deduper.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Sets the hasher used to find possible duplicates in place of sampling
+    /// each file.
+    pub fn hasher(mut self, hasher: impl ContentHasher + 'static) -> Self {
+        self.deduper.scan_opts.hasher = Some(CustomHasher(Arc::new(hasher)));
+        self
+    }
+
+    /// Replaces every option controlling how the roots are walked.
+    pub fn scan_options(mut self, opts: ScanOptions) -> Self {
+        self.deduper.scan_opts = opts;
+        self
+    }
+
+    /// Replaces every option controlling how duplicates are handled once
+    /// found.
+    pub fn dedup_options(mut self, opts: DedupOptions) -> Self {
+        self.deduper.dedup_opts = opts;
+        self
+    }
+
+    /// Replaces every option controlling how file contents are read.
+    pub fn read_options(mut self, opts: ReadOptions) -> Self {
+        self.deduper.read_opts = opts;
+        self
+    }
+
+    /// Checks the options are consistent & constructs the [Deduper].
+    pub fn build(self) -> Result<Deduper, String> {
+        let deduper = self.deduper;
+        if deduper.roots.is_empty() && deduper.files.is_empty() {
+            return Err("Nothing to deduplicate; add at least 1 root or file".to_owned());
+        }
+        let scan_opts = &deduper.scan_opts;
+        if scan_opts
+            .max_size
+            .is_some_and(|max| max < scan_opts.min_size)
+        {
+            return Err("The maximum size must not be smaller than the minimum size".to_owned());
+        }
+        if deduper.dedup_opts.link_into.is_some()
+            && deduper.dedup_opts.action == LinkAction::Symlink
+        {
+            return Err("A store directory can't be used with symlinks".to_owned());
+        }
+        Ok(deduper)
+    }
+}
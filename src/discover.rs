@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{
+    fsinfo::{mounts, FsStats, Mount},
+    utils::{format_size, shell_quote},
+};
+
+/// Filesystem types holding kernel interfaces or memory-backed files rather
+/// than anything worth deduplicating, which aren't listed at all.
+const PSEUDO_FS_TYPES: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "fuse.gvfsd-fuse",
+    "fuse.lxcfs",
+    "fuse.portal",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "ramfs",
+    "rpc_pipefs",
+    "securityfs",
+    "selinuxfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+/// Filesystem types served over the network, which aren't local & so aren't
+/// listed.
+const NETWORK_FS_TYPES: &[&str] = &[
+    "9p",
+    "afs",
+    "ceph",
+    "cifs",
+    "davfs",
+    "fuse.rclone",
+    "fuse.s3fs",
+    "fuse.sshfs",
+    "glusterfs",
+    "nfs",
+    "nfs4",
+    "smb3",
+    "smbfs",
+];
+
+/// Mount points holding the operating system or container images, whose files
+/// belong to package managers & shouldn't be linked behind their backs.
+const SYSTEM_MOUNTS: &[&str] = &[
+    "/boot",
+    "/efi",
+    "/nix",
+    "/snap",
+    "/usr",
+    "/var/lib/containers",
+    "/var/lib/docker",
+    "/var/lib/snapd",
+];
+
+/// Directories on the root filesystem suggested in place of the root itself,
+/// which also holds the operating system.
+const ROOT_FS_DATA_DIRS: &[&str] = &["/home", "/srv"];
+
+/// How a listed filesystem could be deduplicated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Suitability {
+    /// The directories to pass as roots.
+    Candidate(Vec<PathBuf>),
+    /// Why it shouldn't be scanned.
+    Unsuitable(String),
+}
+
+/// Lists the mounted local filesystems with their types, sizes, and free
+/// space to stdout, along with the roots which look safe to deduplicate, for
+/// `hldup discover`.
+///
+/// Pseudo & network filesystems aren't listed, while read-only, overlay, and
+/// operating system mounts are listed but never suggested. Each filesystem is
+/// only suggested once, under the first place it's mounted.
+pub fn print_discovery() -> io::Result<()> {
+    let mut seen: HashMap<u64, PathBuf> = HashMap::new();
+    let mut suggested = Vec::new();
+    println!("{:>12}\t{:>12}\t{:<8}\tMOUNT\tNOTE", "SIZE", "FREE", "TYPE");
+    for mount in mounts()? {
+        let fs_type = mount.fs_type.as_str();
+        if PSEUDO_FS_TYPES.contains(&fs_type) || NETWORK_FS_TYPES.contains(&fs_type) {
+            continue;
+        }
+        let (stats, dev) = match FsStats::for_path(&mount.path)
+            .and_then(|stats| Ok((stats, fs::metadata(&mount.path)?.dev())))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("Could not query {}: {:?}", mount.path.display(), e);
+                continue;
+            }
+        };
+        let suitability = match seen.get(&dev) {
+            Some(first) => Suitability::Unsuitable(format!("also mounted at {}", first.display())),
+            None => suitability(&mount, stats),
+        };
+        seen.entry(dev).or_insert_with(|| mount.path.clone());
+        let note = match &suitability {
+            Suitability::Candidate(roots) => {
+                let roots = roots.iter().map(|root| root.display().to_string());
+                format!("candidate: {}", roots.collect::<Vec<_>>().join(", "))
+            }
+            Suitability::Unsuitable(reason) => reason.clone(),
+        };
+        println!(
+            "{:>12}\t{:>12}\t{:<8}\t{}\t{}",
+            format_size(stats.total_bytes),
+            format_size(stats.free_bytes),
+            fs_type,
+            mount.path.display(),
+            note
+        );
+        if let Suitability::Candidate(roots) = suitability {
+            suggested.extend(roots);
+        }
+    }
+    println!();
+    if suggested.is_empty() {
+        println!("No candidate roots found.");
+        return Ok(());
+    }
+    // Links never cross filesystems, so each root is deduplicated on its own
+    // even when they're passed together
+    let roots = suggested.iter().map(shell_quote);
+    println!(
+        "Suggested invocation, to preview what would be linked:\n    hldup --dry-run {}",
+        roots.collect::<Vec<_>>().join(" ")
+    );
+    Ok(())
+}
+
+/// Decides whether the filesystem mounted at `mount` looks safe to
+/// deduplicate, and from which roots.
+fn suitability(mount: &Mount, stats: FsStats) -> Suitability {
+    let unsuitable = |reason: &str| Suitability::Unsuitable(reason.to_owned());
+    if mount.read_only() || stats.read_only {
+        return unsuitable("read-only");
+    }
+    if mount.fs_type == "overlay" {
+        return unsuitable("overlay, where linking may use more space");
+    }
+    if SYSTEM_MOUNTS
+        .iter()
+        .any(|system| mount.path.starts_with(system))
+    {
+        return unsuitable("operating system files");
+    }
+    if mount.path != Path::new("/") {
+        return Suitability::Candidate(vec![mount.path.clone()]);
+    }
+    // Data directories which are separate mounts are listed on their own
+    let root_dev = fs::metadata("/").map(|meta| meta.dev()).ok();
+    let roots = ROOT_FS_DATA_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .filter(|dir| fs::metadata(dir).is_ok_and(|meta| Some(meta.dev()) == root_dev))
+        .collect::<Vec<_>>();
+    if roots.is_empty() {
+        unsuitable("operating system files")
+    } else {
+        Suitability::Candidate(roots)
+    }
+}
@@ -1,20 +1,119 @@
 use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
-    io,
-    os::unix::fs::MetadataExt,
-    path::Path,
+    io::{self, Seek, SeekFrom},
+    ops::Range,
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use log::{debug, trace};
+use log::{debug, info, trace};
+use serde::Serialize;
 
-use crate::{prompt_bool, read_exact_or_end, utils::MB, PromptUserMode};
+use crate::{
+    dedup::{DedupOptions, FsIdentity, OverlayPolicy, SparsePolicy},
+    fsinfo::{is_overlay, mount_id},
+    hashcache::{content_digest, ContentDigest, FileRecord},
+    utils::{
+        copy_marker, parallel_map, AlignedBuffer, BlockReader, ReadOptions, DAY, GB,
+        HUGE_PAGE_ALIGN, MB, PAGE_ALIGN,
+    },
+};
 
 /// The size of the buffer used when reading files for checking that they are
 /// the same.
 const COMPARE_READ_BUFFSIZE: usize = (32 * MB) as usize;
 
+/// The name of the file marking a directory as the root of a separate dataset,
+/// whose files are never linked with files outside it.
+pub const BOUNDARY_MARKER: &str = ".hldup-boundary";
+
+/// The extensions browsers, download managers & torrent clients give files
+/// they're still downloading, compared case-insensitively.
+const INCOMPLETE_EXTENSIONS: &[&str] = &[
+    "part",
+    "partial",
+    "crdownload",
+    "download",
+    "opdownload",
+    "!ut",
+    "!qb",
+    "!bt",
+];
+
+/// The extensions of the control files some download managers keep next to a
+/// file they're still downloading, e.g. `movie.mkv.aria2` for `movie.mkv`.
+const INCOMPLETE_CONTROL_FILES: &[&str] = &["aria2"];
+
+/// How often a comparison reports the length of the prefix it has verified
+/// equal.
+pub const PROGRESS_INTERVAL: u64 = GB;
+
+/// Where a comparison starts & how it reports its progress, so that one
+/// interrupted partway through a large pair of files can be picked up from
+/// where it left off.
+pub struct CompareProgress<'a> {
+    /// The length of the prefix already verified equal, which isn't reread.
+    pub start: u64,
+    /// Called with the length of the prefix verified equal so far, each time
+    /// it passes another multiple of [PROGRESS_INTERVAL].
+    pub report: &'a (dyn Fn(u64) + Sync),
+}
+
+impl CompareProgress<'_> {
+    /// Reports that the verified prefix grew from `previous` to `verified`
+    /// bytes, if it passed a multiple of [PROGRESS_INTERVAL] in doing so.
+    fn advance(&self, previous: u64, verified: u64) {
+        if verified / PROGRESS_INTERVAL > previous / PROGRESS_INTERVAL {
+            (self.report)(verified);
+        }
+    }
+}
+
 /// Check if 2 files are byte-for-byte identical.
-pub fn is_same_file(left: &Path, right: &Path) -> Result<bool, io::Error> {
+pub fn is_same_file(left: &Path, right: &Path, opts: ReadOptions) -> Result<bool, io::Error> {
+    compare_files(left, right, opts, &mut None, None)
+}
+
+/// Like [is_same_file], but also calculates the [ContentDigest] of the files
+/// if they're identical.
+///
+/// Where possible `left` is hashed as it's compared, so that it doesn't need
+/// to be read a second time. With `progress`, the comparison starts partway
+/// through, and `left` is hashed separately afterwards.
+pub fn same_file_digest(
+    left: &Path,
+    right: &Path,
+    opts: ReadOptions,
+    progress: Option<&CompareProgress>,
+) -> Result<Option<ContentDigest>, io::Error> {
+    let mut hasher = Some(blake3::Hasher::new());
+    if !compare_files(left, right, opts, &mut hasher, progress)? {
+        return Ok(None);
+    }
+    match hasher {
+        Some(hasher) => Ok(Some(hasher.finalize().into())),
+        None => content_digest(left, opts).map(Some),
+    }
+}
+
+/// Compares 2 files for [is_same_file], feeding all of `left` into `hasher` if
+/// they're identical.
+///
+/// If the comparison can't hash `left` as it goes, `hasher` is taken instead.
+fn compare_files(
+    left: &Path,
+    right: &Path,
+    opts: ReadOptions,
+    hasher: &mut Option<blake3::Hasher>,
+    progress: Option<&CompareProgress>,
+) -> Result<bool, io::Error> {
     debug!("Checking if paths {left:?} and {right:?} are the same file.");
 
     let left_meta = fs::symlink_metadata(left)?;
@@ -31,8 +130,10 @@ pub fn is_same_file(left: &Path, right: &Path) -> Result<bool, io::Error> {
         left_meta.size()
     );
 
-    // The same file is always identical to itself
-    if left_meta.ino() == right_meta.ino() {
+    // The same file is always identical to itself; inode numbers are only
+    // unique within a filesystem, so the device has to match too
+    if (left_meta.dev(), left_meta.ino()) == (right_meta.dev(), right_meta.ino()) {
+        hasher.take();
         return Ok(true);
     }
     trace!(
@@ -48,26 +149,218 @@ pub fn is_same_file(left: &Path, right: &Path) -> Result<bool, io::Error> {
         left.display(),
         right.display()
     );
-    let mut left_fh = File::open(left)?;
-    let mut left_buff = vec![0; COMPARE_READ_BUFFSIZE].into_boxed_slice();
-    let mut right_fh = File::open(right)?;
-    let mut right_buff = vec![0; COMPARE_READ_BUFFSIZE].into_boxed_slice();
+    if let Some(offset) = probe_difference(left, right, left_meta.size(), opts)? {
+        debug!(
+            "Probes found a difference between {} and {} at offset {offset}.",
+            left.display(),
+            right.display()
+        );
+        if opts.debug_compare {
+            log_difference(left, right, offset);
+        }
+        return Ok(false);
+    }
+
+    // The prefix verified by an earlier run isn't read, so it can't be hashed
+    let start = progress.map_or(0, |progress| progress.start.min(left_meta.size()));
+    if start > 0 {
+        info!(
+            "Resuming the comparison of {} and {} at offset {start}, verified equal by an earlier run.",
+            left.display(),
+            right.display()
+        );
+        hasher.take();
+    }
+
+    let difference = if opts.thread_count() > 1 && left_meta.size() >= SEGMENTED_MIN_FILESIZE {
+        hasher.take();
+        compare_segments(left, right, start..left_meta.size(), opts, progress)?
+    } else {
+        let mut left_fh = BlockReader::open_sequential(left, opts)?;
+        let mut right_fh = BlockReader::open_sequential(right, opts)?;
+        left_fh.seek(SeekFrom::Start(start))?;
+        right_fh.seek(SeekFrom::Start(start))?;
+        let mut verified = start;
+        COMPARE_BUFFERS.with_borrow_mut(|(left_buff, right_buff)| {
+            compare_readers(
+                left,
+                &mut left_fh,
+                left_buff,
+                right,
+                &mut right_fh,
+                right_buff,
+                |block| {
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(block);
+                    }
+                    let previous = verified;
+                    verified += block.len() as u64;
+                    if let Some(progress) = progress {
+                        progress.advance(previous, verified);
+                    }
+                },
+            )
+        })?
+    };
+    match difference {
+        Some(offset) if opts.debug_compare => log_difference(left, right, offset),
+        _ => {}
+    }
+    Ok(difference.is_none())
+}
 
-    let mut idx = 0;
+/// The number of bytes either side of a difference shown by [log_difference].
+const DIFFERENCE_CONTEXT: u64 = 32;
+/// The number of bytes on each line of the hexdumps shown by [log_difference].
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Logs a hexdump of both files around the differing `offset`, for
+/// `--debug-compare`.
+fn log_difference(left: &Path, right: &Path, offset: u64) {
+    let start =
+        offset.saturating_sub(DIFFERENCE_CONTEXT) / HEXDUMP_WIDTH as u64 * HEXDUMP_WIDTH as u64;
+    let len = (offset - start + DIFFERENCE_CONTEXT) as usize;
+    info!(
+        "{} and {} first differ at offset {offset:#x}:",
+        left.display(),
+        right.display()
+    );
+    for path in [left, right] {
+        let mut buffer = vec![0; len];
+        let read = File::open(path).and_then(|fh| {
+            let mut total = 0;
+            while total < len {
+                match fh.read_at(&mut buffer[total..], start + total as u64)? {
+                    0 => break,
+                    count => total += count,
+                }
+            }
+            Ok(total)
+        });
+        let read = match read {
+            Ok(v) => v,
+            Err(e) => {
+                info!("    Error reading {}: {:?}", path.display(), e);
+                continue;
+            }
+        };
+        info!("    {}:", path.display());
+        for (idx, line) in buffer[..read].chunks(HEXDUMP_WIDTH).enumerate() {
+            let line_offset = start + (idx * HEXDUMP_WIDTH) as u64;
+            let hex = line
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = line
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            info!(
+                "    {line_offset:08x}: {hex:<width$}  |{ascii}|",
+                width = HEXDUMP_WIDTH * 3 - 1
+            );
+        }
+    }
+}
+
+thread_local! {
+    /// The buffers used by [is_same_file], kept around between comparisons
+    /// since they're large enough that reallocating them adds up.
+    static COMPARE_BUFFERS: RefCell<(AlignedBuffer, AlignedBuffer)> = RefCell::new((
+        AlignedBuffer::new(COMPARE_READ_BUFFSIZE, HUGE_PAGE_ALIGN),
+        AlignedBuffer::new(COMPARE_READ_BUFFSIZE, HUGE_PAGE_ALIGN),
+    ));
+}
+
+/// The number of scattered probes, in addition to the tail, read by
+/// [probe_difference].
+const PROBE_COUNT: u64 = 8;
+/// The size of each probe read by [probe_difference].
+const PROBE_SIZE: usize = 4 * 1024;
+/// The smallest file that's worth probing before a full comparison; below this
+/// the full comparison is only a couple of reads anyway.
+const PROBE_MIN_FILESIZE: u64 = 4 * MB;
+
+/// Checks a handful of scattered offsets in 2 files of the same `size`,
+/// returning the offset of the first difference found.
+///
+/// Files with the same sampled hashes which still differ usually differ in
+/// enough places that a few probes will find it, saving us reading both files
+/// in full; if every probe matches the files still need a full comparison.
+fn probe_difference(
+    left: &Path,
+    right: &Path,
+    size: u64,
+    opts: ReadOptions,
+) -> Result<Option<u64>, io::Error> {
+    if size < PROBE_MIN_FILESIZE {
+        return Ok(None);
+    }
+    let probe_size = PROBE_SIZE as u64;
+    // Evenly spaced offsets, aligned to the probe size, plus the tail since
+    // appended-to files are a common kind of near-duplicate
+    let offsets = (1..=PROBE_COUNT)
+        .map(|idx| (size / (PROBE_COUNT + 1) * idx) / probe_size * probe_size)
+        .chain(std::iter::once(size - probe_size));
+
+    let mut left_fh = BlockReader::open(left, opts)?;
+    let mut right_fh = BlockReader::open(right, opts)?;
+    let mut left_buff = [0; PROBE_SIZE];
+    let mut right_buff = [0; PROBE_SIZE];
+    for offset in offsets {
+        left_fh.seek(SeekFrom::Start(offset))?;
+        right_fh.seek(SeekFrom::Start(offset))?;
+        let read_left = left_fh.read_block(&mut left_buff)?;
+        let read_right = right_fh.read_block(&mut right_buff)?;
+        if let Some(diff) = first_difference(&left_buff[..read_left], &right_buff[..read_right]) {
+            return Ok(Some(offset + diff as u64));
+        }
+    }
+    trace!(
+        "All probes of {} and {} matched.",
+        left.display(),
+        right.display()
+    );
+    Ok(None)
+}
+
+/// Compares 2 open files block-by-block from their current positions,
+/// returning the offset of the first difference if there is one.
+///
+/// Each block found equal up to the first difference is passed to `on_equal`.
+fn compare_readers(
+    left: &Path,
+    left_fh: &mut BlockReader,
+    left_buff: &mut [u8],
+    right: &Path,
+    right_fh: &mut BlockReader,
+    right_buff: &mut [u8],
+    mut on_equal: impl FnMut(&[u8]),
+) -> Result<Option<u64>, io::Error> {
+    let mut idx = left_fh.stream_position()?;
 
     loop {
-        let read_left = read_exact_or_end(&mut left_fh, &mut left_buff)?;
+        let read_left = left_fh.read_block(left_buff)?;
         let left_subbuf = &left_buff[..read_left];
-        let read_right = read_exact_or_end(&mut right_fh, &mut right_buff)?;
+        let read_right = right_fh.read_block(right_buff)?;
         let right_subbuf = &right_buff[..read_right];
-        if left_subbuf != right_subbuf {
+        if let Some(offset) = first_difference(left_subbuf, right_subbuf) {
             debug!(
-                "Found difference between {} and {} at offset {idx}.",
+                "Found difference between {} and {} at offset {}.",
                 left.display(),
-                right.display()
+                right.display(),
+                idx + offset as u64
             );
-            return Ok(false);
+            return Ok(Some(idx + offset as u64));
         }
+        on_equal(left_subbuf);
 
         // If the read byte count for the current iteration is smaller than the
         // buffer size, then we finished reading the file
@@ -77,12 +370,275 @@ pub fn is_same_file(left: &Path, right: &Path) -> Result<bool, io::Error> {
                 left.display(),
                 right.display()
             );
-            return Ok(true);
+            return Ok(None);
+        }
+        idx += read_left as u64;
+    }
+}
+
+/// The smallest file that's compared in segments on multiple threads, when
+/// we're allowed more than 1.
+const SEGMENTED_MIN_FILESIZE: u64 = GB;
+/// The size of each segment compared by [compare_segments].
+const COMPARE_SEGMENT_SIZE: u64 = 256 * MB;
+/// The size of the reads made while comparing a segment.
+const SEGMENT_READ_SIZE: usize = (4 * MB) as usize;
+
+/// Compares the `range` of 2 files of the same size by splitting it into
+/// segments which are compared on separate threads, each with its own file
+/// handles, returning the offset of the first difference if there is one.
+///
+/// As soon as any segment is found to differ, the others stop early. Segments
+/// finish out of order, so `progress` is told about the segments found equal
+/// once every segment before them has been too.
+fn compare_segments(
+    left: &Path,
+    right: &Path,
+    range: Range<u64>,
+    opts: ReadOptions,
+    progress: Option<&CompareProgress>,
+) -> Result<Option<u64>, io::Error> {
+    let size = range.end;
+    let segments = (0..(range.end - range.start).div_ceil(COMPARE_SEGMENT_SIZE))
+        .map(|idx| range.start + idx * COMPARE_SEGMENT_SIZE)
+        .collect::<Vec<_>>();
+    debug!(
+        "Comparing {} and {} in {} segments.",
+        left.display(),
+        right.display(),
+        segments.len()
+    );
+    let differs = AtomicBool::new(false);
+    // The length of the prefix verified equal, & the segments verified equal
+    // after it, keyed by their start
+    let verified = Mutex::new((range.start, BTreeMap::new()));
+    let results = parallel_map(&segments, opts.thread_count(), |&start| {
+        let end = (start + COMPARE_SEGMENT_SIZE).min(size);
+        let res = compare_segment(left, right, start..end, opts, &differs);
+        match (&res, progress) {
+            (Ok(Some(_)), _) => differs.store(true, Ordering::Relaxed),
+            // A segment given up on because another differs wasn't verified
+            (Ok(None), Some(progress)) if !differs.load(Ordering::Relaxed) => {
+                let mut verified = verified.lock().unwrap_or_else(|e| e.into_inner());
+                let (prefix, segments) = &mut *verified;
+                segments.insert(start, end);
+                let previous = *prefix;
+                while let Some(end) = segments.remove(prefix) {
+                    *prefix = end;
+                }
+                progress.advance(previous, *prefix);
+            }
+            _ => {}
+        }
+        res
+    });
+
+    // A difference anywhere settles it, even if other segments failed
+    let mut first_error = None;
+    let mut first_difference = None;
+    for res in results {
+        match res {
+            Ok(Some(offset)) => {
+                first_difference = Some(first_difference.map_or(offset, |cur: u64| cur.min(offset)))
+            }
+            Ok(None) => {}
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
         }
-        idx += read_left;
+    }
+    match (first_difference, first_error) {
+        (Some(offset), _) => Ok(Some(offset)),
+        (None, Some(e)) => Err(e),
+        (None, None) => Ok(None),
     }
 }
 
+/// Compares a single segment of 2 files for [compare_segments], giving up
+/// early once `differs` is set by another segment.
+fn compare_segment(
+    left: &Path,
+    right: &Path,
+    segment: Range<u64>,
+    opts: ReadOptions,
+    differs: &AtomicBool,
+) -> Result<Option<u64>, io::Error> {
+    let mut left_fh = BlockReader::open_sequential(left, opts)?;
+    let mut right_fh = BlockReader::open_sequential(right, opts)?;
+    left_fh.seek(SeekFrom::Start(segment.start))?;
+    right_fh.seek(SeekFrom::Start(segment.start))?;
+    let mut left_buff = AlignedBuffer::new(SEGMENT_READ_SIZE, PAGE_ALIGN);
+    let mut right_buff = AlignedBuffer::new(SEGMENT_READ_SIZE, PAGE_ALIGN);
+    let mut pos = segment.start;
+    while pos < segment.end {
+        if differs.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let len = SEGMENT_READ_SIZE.min((segment.end - pos) as usize);
+        let read_left = left_fh.read_block(&mut left_buff[..len])?;
+        let read_right = right_fh.read_block(&mut right_buff[..len])?;
+        if let Some(offset) = first_difference(&left_buff[..read_left], &right_buff[..read_right]) {
+            return Ok(Some(pos + offset as u64));
+        }
+        if read_left == 0 {
+            // Both files ended early, so they must have been truncated since
+            // we checked their sizes
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} was truncated while being compared", left.display()),
+            ));
+        }
+        pos += read_left as u64;
+    }
+    Ok(None)
+}
+
+/// The size of the chunks [first_difference] compares at a time.
+const COMPARE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Finds the offset of the first byte that differs between 2 buffers, treating
+/// the end of the shorter buffer as a difference.
+///
+/// Comparing a whole 32 MiB buffer at once means reading all of it even when
+/// the difference is in the first page, so instead we compare in chunks (each
+/// of which is a vectorized `memcmp`) and stop at the first mismatching chunk,
+/// only then scanning it byte-by-byte to find the exact offset.
+fn first_difference(left: &[u8], right: &[u8]) -> Option<usize> {
+    let common = left.len().min(right.len());
+    let chunks = left[..common]
+        .chunks(COMPARE_CHUNK_SIZE)
+        .zip(right[..common].chunks(COMPARE_CHUNK_SIZE));
+    for (chunk_idx, (left_chunk, right_chunk)) in chunks.enumerate() {
+        if left_chunk == right_chunk {
+            continue;
+        }
+        let offset = left_chunk
+            .iter()
+            .zip(right_chunk)
+            .position(|(l, r)| l != r)
+            .unwrap_or(0);
+        return Some(chunk_idx * COMPARE_CHUNK_SIZE + offset);
+    }
+    (left.len() != right.len()).then_some(common)
+}
+
+/// A set of paths which are all hard links to the same inode, treated as a
+/// single logical file when deduplicating.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LinkGroup {
+    /// The device the shared inode lives on.
+    pub dev: u64,
+    /// The inode shared by every path in the group.
+    pub ino: u64,
+    /// The total number of links to the inode, including any outside of the
+    /// scanned paths.
+    pub nlink: u64,
+    /// The paths linked to the inode, in sorted order.
+    pub paths: Vec<PathBuf>,
+    /// Whether any of the paths came from a scan of an archive or are
+    /// otherwise protected, in which case the group is only ever kept.
+    pub protected: bool,
+}
+
+impl LinkGroup {
+    /// The path used to stand in for the whole group when comparing it to
+    /// other groups.
+    pub fn representative(&self) -> &Path {
+        &self.paths[0]
+    }
+
+    /// Whether any of this group's paths are inside `dir`.
+    pub fn has_path_under(&self, dir: &Path) -> bool {
+        self.paths.iter().any(|path| path.starts_with(dir))
+    }
+
+    /// Reorders this group's paths so that any inside `dir` come first, making
+    /// one of them the [LinkGroup::representative].
+    pub fn prefer_paths_under(&mut self, dir: &Path) {
+        self.paths.sort_by_key(|path| !path.starts_with(dir));
+    }
+
+    /// Whether relinking every path in this group would free the underlying
+    /// inode, which is only true when there are no links to it outside of the
+    /// scanned paths.
+    pub fn frees_inode(&self) -> bool {
+        self.nlink <= self.paths.len() as u64
+    }
+
+    /// Splits a set of paths into the [LinkGroup]s they belong to.
+    ///
+    /// Paths in `known` are grouped by the inode in their [FileRecord] from the
+    /// scan; any others are `stat`ed, and those that cannot be are returned
+    /// alongside their error instead of being placed in a group. The groups are ordered largest-first so that
+    /// the group requiring the fewest relinks is the one kept.
+    ///
+    /// Symlinks are grouped with the file they point to if `include_symlinks`
+    /// is set, meaning they may be replaced by a hard link; otherwise they are
+    /// left out entirely.
+    pub fn split<'a>(
+        paths: impl IntoIterator<Item = &'a PathBuf>,
+        known: &HashMap<PathBuf, FileRecord>,
+        include_symlinks: bool,
+    ) -> (Vec<LinkGroup>, Vec<(&'a PathBuf, io::Error)>) {
+        let mut groups: HashMap<(u64, u64), (u64, bool, Vec<PathBuf>)> = HashMap::new();
+        let mut errors = Vec::new();
+        for path in paths {
+            let inode = match known.get(path) {
+                Some(inode) => *inode,
+                None => match stat_inode(path) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        errors.push((path, e));
+                        continue;
+                    }
+                },
+            };
+            if inode.symlink && !include_symlinks {
+                debug!("Path {} is a symlink; leaving it alone.", path.display());
+                continue;
+            }
+            let entry = groups.entry((inode.dev, inode.ino)).or_default();
+            entry.0 = inode.nlink;
+            entry.1 |= inode.archived;
+            entry.2.push(path.clone());
+        }
+        let mut groups = groups
+            .into_iter()
+            .map(|((dev, ino), (nlink, protected, mut paths))| {
+                paths.sort();
+                LinkGroup {
+                    dev,
+                    ino,
+                    nlink,
+                    paths,
+                    protected,
+                }
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| {
+            b.paths
+                .len()
+                .cmp(&a.paths.len())
+                .then_with(|| a.representative().cmp(b.representative()))
+        });
+        (groups, errors)
+    }
+}
+
+/// Builds a [FileRecord] without hashes for `path`, following it if it's a
+/// symlink.
+fn stat_inode(path: &Path) -> io::Result<FileRecord> {
+    let meta = fs::symlink_metadata(path)?;
+    let record = if meta.is_symlink() {
+        FileRecord::builder()
+            .metadata(&fs::metadata(path)?)
+            .symlink(true)
+    } else {
+        FileRecord::builder().metadata(&meta)
+    };
+    Ok(record.build())
+}
+
 /// The reason we shouldn't link 2 byte-for-byte identical files.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ShouldNotRelinkReason {
@@ -92,6 +648,32 @@ pub enum ShouldNotRelinkReason {
     AlreadyLinked,
     /// The user told the application not to hardlink the files.
     UserSaidNo,
+    /// The file to be replaced is a symlink, and replacing it with a hard link
+    /// would change its semantics.
+    Symlink,
+    /// One file is sparse and the other is fully allocated, and the
+    /// [SparsePolicy] says not to link them.
+    DifferentAllocation,
+    /// The directory containing the file to be replaced isn't writable.
+    ReadOnlyDirectory,
+    /// The files are on the same filesystem, but are accessed through
+    /// different mounts of it.
+    DifferentMounts(u64, u64),
+    /// The files are on an overlay filesystem, and the [OverlayPolicy] says
+    /// not to link them.
+    Overlay,
+    /// The files belong to different datasets, as marked by a
+    /// [BOUNDARY_MARKER].
+    DifferentBoundaries,
+    /// A filter supplied by an embedding program rejected the pair.
+    Filtered,
+    /// The file to be replaced matches a `--protect` pattern.
+    Protected,
+    /// The files' [Safety] is at or past the `--never-link` class.
+    Unsafe,
+    /// One of the files looks like a download that hasn't finished, whose
+    /// content is still going to change.
+    IncompleteDownload,
 }
 
 impl ShouldNotRelinkReason {
@@ -105,41 +687,329 @@ impl ShouldNotRelinkReason {
                 "The files are on different filesystems."
             }
             ShouldNotRelinkReason::UserSaidNo => "The user said no.",
+            ShouldNotRelinkReason::Symlink => "The file to be replaced is a symlink.",
+            ShouldNotRelinkReason::DifferentAllocation => {
+                "One file is sparse and the other is fully allocated."
+            }
+            ShouldNotRelinkReason::DifferentMounts(_, _) => {
+                "The files are on different mounts of the same filesystem."
+            }
+            ShouldNotRelinkReason::Overlay => {
+                "The files are on an overlay filesystem, where linking may use more space."
+            }
+            ShouldNotRelinkReason::ReadOnlyDirectory => {
+                "The directory containing the file to be replaced is not writable."
+            }
+            ShouldNotRelinkReason::DifferentBoundaries => {
+                "The files are in different datasets marked by .hldup-boundary files."
+            }
+            ShouldNotRelinkReason::Filtered => "A custom filter rejected the files.",
+            ShouldNotRelinkReason::Protected => "The file to be replaced is protected.",
+            ShouldNotRelinkReason::Unsafe => "The files were classified as too risky to link.",
+            ShouldNotRelinkReason::IncompleteDownload => {
+                "One of the files looks like an incomplete download."
+            }
         }
     }
 }
 
-/// Checks if we should link a file, prompting the user if needed.
-pub fn should_link(
+/// Details about a pair of files that are eligible to be linked that the user
+/// should know about before agreeing to link them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct LinkNotes {
+    /// One file is sparse while the other is fully allocated; after linking,
+    /// both paths will share the allocation of the kept file.
+    pub sparse_mismatch: bool,
+    /// The files are on an overlay filesystem, so linking may copy them up
+    /// from a lower layer instead of freeing space.
+    pub overlay: bool,
+    /// How safe linking the files looks.
+    pub safety: Safety,
+}
+
+impl LinkNotes {
+    /// A note to append to the prompt for the link, or an empty string if
+    /// there's nothing to note.
+    pub fn prompt_note(&self) -> String {
+        let mut notes = Vec::new();
+        if self.sparse_mismatch {
+            notes.push("one copy is sparse and the other is fully allocated");
+        }
+        if self.overlay {
+            notes.push("on an overlay filesystem, so linking may use more space");
+        }
+        match self.safety {
+            Safety::Safe => {}
+            Safety::Review => notes.push("review recommended"),
+            Safety::Risky => notes.push("risky"),
+        }
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join("; "))
+        }
+    }
+}
+
+/// Checks if the files are eligible to be linked, without asking the user.
+pub fn check_link(
     left: &Path,
     right: &Path,
-    prompt_mode: PromptUserMode,
-) -> Result<Result<(), ShouldNotRelinkReason>, io::Error> {
+    opts: &DedupOptions,
+) -> Result<Result<LinkNotes, ShouldNotRelinkReason>, io::Error> {
+    if opts.protect.matches_file(right) {
+        return Ok(Err(ShouldNotRelinkReason::Protected));
+    }
+    if !opts.replace_symlinks && fs::symlink_metadata(right)?.is_symlink() {
+        return Ok(Err(ShouldNotRelinkReason::Symlink));
+    }
+
     let left_meta = std::fs::metadata(left)?;
     let right_meta = std::fs::metadata(right)?;
 
-    if left_meta.ino() == right_meta.ino() {
+    if (left_meta.dev(), left_meta.ino()) == (right_meta.dev(), right_meta.ino()) {
         return Ok(Err(ShouldNotRelinkReason::AlreadyLinked));
     }
+    if !opts.link_incomplete && (is_incomplete_download(left) || is_incomplete_download(right)) {
+        return Ok(Err(ShouldNotRelinkReason::IncompleteDownload));
+    }
+    if boundary(left) != boundary(right) {
+        return Ok(Err(ShouldNotRelinkReason::DifferentBoundaries));
+    }
+    if !opts.pair_filters.allow(left, right) {
+        return Ok(Err(ShouldNotRelinkReason::Filtered));
+    }
 
-    if left_meta.dev() != right_meta.dev() {
-        return Ok(Err(ShouldNotRelinkReason::DifferentFilesystems(
-            left_meta.dev(),
-            right_meta.dev(),
+    // Links can't cross mounts even within the same filesystem, so the mount
+    // IDs are checked as well when the kernel provides them
+    // Symlinks can point anywhere, though
+    let mounts = mount_id(left)?.zip(mount_id(right)?);
+    let symlink = opts.dedup_action().crosses_filesystems();
+    let different_mounts = mounts.filter(|(left_mnt, right_mnt)| left_mnt != right_mnt);
+    match opts.fs_identity {
+        _ if symlink => {}
+        FsIdentity::Mount if mounts.is_some() => {}
+        FsIdentity::Device | FsIdentity::Mount => {
+            if left_meta.dev() != right_meta.dev() {
+                return Ok(Err(ShouldNotRelinkReason::DifferentFilesystems(
+                    left_meta.dev(),
+                    right_meta.dev(),
+                )));
+            }
+        }
+    }
+    if let Some((left_mnt, right_mnt)) = different_mounts.filter(|_| !symlink) {
+        return Ok(Err(ShouldNotRelinkReason::DifferentMounts(
+            left_mnt, right_mnt,
         )));
     }
 
-    let user_resp = prompt_mode.as_default().unwrap_or_else(|| {
-        let msg = format!(
-            "Found candidates {} and {}. Should we hard-link them?",
-            left.display(),
-            right.display()
-        );
-        prompt_bool(&msg)
-    });
-    if user_resp {
-        Ok(Ok(()))
+    let mut notes = LinkNotes::default();
+    if is_overlay(left)? || is_overlay(right)? {
+        match opts.overlay_policy {
+            OverlayPolicy::Skip => return Ok(Err(ShouldNotRelinkReason::Overlay)),
+            OverlayPolicy::Link => notes.overlay = true,
+        }
+    }
+    if is_sparse(&left_meta) != is_sparse(&right_meta) {
+        match opts.sparse_policy {
+            SparsePolicy::Skip => return Ok(Err(ShouldNotRelinkReason::DifferentAllocation)),
+            SparsePolicy::Warn => notes.sparse_mismatch = true,
+            SparsePolicy::Link => {}
+        }
+    }
+    notes.safety = classify(left, &left_meta, right, &right_meta);
+    Ok(Ok(notes))
+}
+
+/// How safe it looks to link a pair of identical files without anyone
+/// reviewing them, judging by how they seem to have become duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Safety {
+    /// The files look like plain copies: they have the same modification
+    /// time, and the same name or a name marked as a copy.
+    #[default]
+    Safe,
+    /// The files are identical but otherwise look unrelated, e.g. with
+    /// different names or modification times.
+    Review,
+    /// The files have different owners or permissions, or one was modified
+    /// within the last day and may still be changing.
+    Risky,
+}
+
+impl Safety {
+    /// Parses a [Safety] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "safe" => Ok(Self::Safe),
+            "review" => Ok(Self::Review),
+            "risky" => Ok(Self::Risky),
+            other => Err(format!(
+                "Unknown safety class {other:?}; expected safe, review, or risky"
+            )),
+        }
+    }
+
+    /// The command line name of this [Safety].
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Safe => "safe",
+            Self::Review => "review",
+            Self::Risky => "risky",
+        }
+    }
+}
+
+/// Classifies how safe it looks to link the identical files at `left` &
+/// `right`, with the given metadata.
+pub fn classify(
+    left: &Path,
+    left_meta: &fs::Metadata,
+    right: &Path,
+    right_meta: &fs::Metadata,
+) -> Safety {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    let recent = |meta: &fs::Metadata| now.saturating_sub(meta.mtime()) < DAY as i64;
+    let ownership = |meta: &fs::Metadata| (meta.uid(), meta.gid(), meta.mode() & 0o7777);
+    if ownership(left_meta) != ownership(right_meta) || recent(left_meta) || recent(right_meta) {
+        return Safety::Risky;
+    }
+    let same_name = match (left.file_name(), right.file_name()) {
+        (Some(left), Some(right)) => {
+            left == right || copy_marker(left).is_some() || copy_marker(right).is_some()
+        }
+        _ => false,
+    };
+    if same_name && left_meta.mtime() == right_meta.mtime() {
+        Safety::Safe
     } else {
-        Ok(Err(ShouldNotRelinkReason::UserSaidNo))
+        Safety::Review
+    }
+}
+
+/// Finds the dataset the file at `path` belongs to: the closest directory
+/// above it containing a [BOUNDARY_MARKER], if any.
+pub fn boundary(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .skip(1)
+        .find(|dir| fs::symlink_metadata(dir.join(BOUNDARY_MARKER)).is_ok())
+}
+
+/// Checks if the file at `path` looks like a download that hasn't finished.
+///
+/// Clients pre-allocate the full size of a download before filling it in, so
+/// unfinished downloads of the same size look identical until then. They're
+/// recognised by the extension or control file the client marks them with.
+///
+/// A file with no blocks allocated isn't taken as unfinished, since complete
+/// files stored inline in their inode or on network filesystems report none.
+fn is_incomplete_download(path: &Path) -> bool {
+    let marked = path.extension().is_some_and(|ext| {
+        let ext = ext.to_string_lossy();
+        INCOMPLETE_EXTENSIONS
+            .iter()
+            .any(|marker| ext.eq_ignore_ascii_case(marker))
+    });
+    let has_control_file = || {
+        INCOMPLETE_CONTROL_FILES.iter().any(|ext| {
+            let mut control = path.as_os_str().to_owned();
+            control.push(".");
+            control.push(ext);
+            fs::symlink_metadata(control).is_ok()
+        })
+    };
+    marked || has_control_file()
+}
+
+/// Checks if a file has fewer blocks allocated than its size requires.
+fn is_sparse(meta: &fs::Metadata) -> bool {
+    // st_blocks is always in 512-byte units, regardless of the block size
+    meta.blocks().saturating_mul(512) < meta.size()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process, sync::Mutex};
+
+    use super::*;
+
+    /// Creates a sparse file of `size` bytes at a path unique to this test,
+    /// with `byte` written at each of the `changes` offsets.
+    fn sparse_file(name: &str, size: u64, changes: &[(u64, u8)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hldup-test-{}-{name}", process::id()));
+        let file = File::create(&path).unwrap();
+        file.set_len(size).unwrap();
+        for (offset, byte) in changes {
+            file.write_all_at(&[*byte], *offset).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn finds_the_first_difference() {
+        let base = vec![7u8; 3 * COMPARE_CHUNK_SIZE];
+        assert_eq!(first_difference(&base, &base), None);
+        let mut changed = base.clone();
+        changed[COMPARE_CHUNK_SIZE + 5] = 0;
+        changed[2 * COMPARE_CHUNK_SIZE] = 0;
+        assert_eq!(
+            first_difference(&base, &changed),
+            Some(COMPARE_CHUNK_SIZE + 5)
+        );
+        // The end of the shorter buffer counts as a difference
+        assert_eq!(first_difference(&base[..10], &base), Some(10));
+        assert_eq!(first_difference(&[], &[]), None);
+    }
+
+    #[test]
+    fn compares_segments() {
+        let size = 2 * COMPARE_SEGMENT_SIZE + 100;
+        let offset = COMPARE_SEGMENT_SIZE + 12345;
+        let left = sparse_file("segments-left", size, &[(offset, 1), (size - 1, 2)]);
+        let right = sparse_file("segments-right", size, &[(size - 1, 2)]);
+        let same = sparse_file("segments-same", size, &[(offset, 1), (size - 1, 2)]);
+        let opts = ReadOptions::default();
+        let res = compare_segments(&left, &right, 0..size, opts, None);
+        let res_same = compare_segments(&left, &same, 0..size, opts, None);
+        // Only the range given is compared
+        let res_after = compare_segments(&left, &right, offset + 1..size, opts, None);
+        for path in [&left, &right, &same] {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(res.unwrap(), Some(offset));
+        assert_eq!(res_same.unwrap(), None);
+        assert_eq!(res_after.unwrap(), None);
+    }
+
+    #[test]
+    fn reports_contiguous_progress() {
+        let size = PROGRESS_INTERVAL + COMPARE_SEGMENT_SIZE;
+        let left = sparse_file("progress-left", size, &[]);
+        let right = sparse_file("progress-right", size, &[]);
+        let reported = Mutex::new(Vec::new());
+        let report = |offset| reported.lock().unwrap().push(offset);
+        let progress = CompareProgress {
+            start: 0,
+            report: &report,
+        };
+        let res = compare_segments(
+            &left,
+            &right,
+            0..size,
+            ReadOptions::default(),
+            Some(&progress),
+        );
+        fs::remove_file(&left).unwrap();
+        fs::remove_file(&right).unwrap();
+        assert_eq!(res.unwrap(), None);
+        // Progress is only reported once everything before it is verified
+        let reported = reported.into_inner().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0] >= PROGRESS_INTERVAL);
+        assert_eq!(reported[0] % COMPARE_SEGMENT_SIZE, 0);
     }
 }
@@ -0,0 +1,261 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use serde::Serialize;
+
+use crate::{
+    audit::AuditReport,
+    dupchecks::Safety,
+    hashcache::{ContentDigest, GroupId},
+    output::open_output,
+    status::StatusFifo,
+};
+
+/// The version of the event stream's schema.
+///
+/// This must be bumped whenever an existing event or field is changed or
+/// removed, so that consumers can detect streams they don't understand; adding
+/// new events or fields is backwards compatible and does not require a bump.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// A lifecycle event emitted to the `--events` stream.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// We started walking a root directory.
+    ScanStart { root: &'a Path },
+    /// A phase of the run started, which will work through `total` files
+    /// (`hash`), possible duplicate groups (`compare`), or paths (`link`).
+    PhaseStart { phase: &'static str, total: usize },
+    /// A file was hashed, or had its hash loaded from the persistent state.
+    FileHashed {
+        path: &'a Path,
+        size: u64,
+        group: GroupId,
+    },
+    /// A set of files with the same hashes was found.
+    GroupFound {
+        group: GroupId,
+        size: u64,
+        paths: Vec<&'a Path>,
+    },
+    /// 2 files were compared byte-for-byte.
+    CompareResult {
+        group: GroupId,
+        /// The digest of the files' full contents, once they're found
+        /// identical; unlike `group`, it doesn't depend on how the files were
+        /// sampled.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<ContentDigest>,
+        left: &'a Path,
+        right: &'a Path,
+        identical: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// A pair of identical files passed the checks for linking them, and was
+    /// classified by how safe that looks.
+    Classified {
+        group: GroupId,
+        left: &'a Path,
+        right: &'a Path,
+        safety: Safety,
+    },
+    /// An action was taken (or declined) on a pair of duplicates.
+    Action {
+        group: GroupId,
+        /// The digest of the duplicates' full contents, if known.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<ContentDigest>,
+        action: &'static str,
+        source: &'a Path,
+        target: &'a Path,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// The run finished.
+    Summary {
+        damaged_files: usize,
+        permission_denied: usize,
+        freed_inodes: u64,
+        freed_bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stopped_early: Option<String>,
+    },
+}
+
+/// An observer supplied by a program embedding hldup, notified as the scan &
+/// dedup phases progress so it can drive its own UI.
+///
+/// Every method does nothing by default, so implementations only need the ones
+/// they care about. Since observers are shared, any state they keep needs
+/// interior mutability, e.g. a `Cell` counter or a `Mutex` around a channel.
+pub trait Observer {
+    /// A file was hashed, or had its hash loaded from the persistent state.
+    fn on_file_hashed(&self, _path: &Path, _size: u64, _group: GroupId) {}
+
+    /// A set of files with the same hashes was found.
+    fn on_group_found(&self, _group: GroupId, _size: u64, _paths: &[&Path]) {}
+
+    /// 2 files were compared byte-for-byte; `identical` is `false` if the
+    /// comparison failed.
+    fn on_pair_compared(&self, _group: GroupId, _left: &Path, _right: &Path, _identical: bool) {}
+
+    /// `target` was successfully replaced by `action` (`link`, `symlink`,
+    /// `reflink`, `delete`, or a custom action's name) with `source` as the
+    /// kept copy, or linked into the `--link-into` store (`store`). Dry runs &
+    /// `report` actions never call this.
+    fn on_link_created(&self, _group: GroupId, _action: &str, _source: &Path, _target: &Path) {}
+
+    /// Any event at all, including those the other methods cover, called
+    /// before them.
+    fn on_event(&self, _event: &Event<'_>) {}
+}
+
+/// Passes `event` on to the matching methods of `observer`.
+fn notify(observer: &dyn Observer, event: &Event<'_>) {
+    observer.on_event(event);
+    match event {
+        Event::FileHashed { path, size, group } => observer.on_file_hashed(path, *size, *group),
+        Event::GroupFound { group, size, paths } => observer.on_group_found(*group, *size, paths),
+        Event::CompareResult {
+            group,
+            left,
+            right,
+            identical,
+            ..
+        } => observer.on_pair_compared(*group, left, right, *identical),
+        Event::Action {
+            group,
+            action,
+            source,
+            target,
+            success: true,
+            ..
+        } if !action.starts_with("would-") && *action != "report" => {
+            observer.on_link_created(*group, action, source, target)
+        }
+        _ => {}
+    }
+}
+
+/// The envelope each [Event] is written in.
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    version: u32,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u128,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// The destinations of the `--events` streams, each written 1 JSON object per
+/// line, and of the `--report` built from the same events.
+///
+/// A sink without a destination silently discards everything, so callers can
+/// emit events unconditionally.
+#[derive(Default)]
+pub struct EventSink {
+    /// Each stream & the path it was opened from, for errors.
+    outs: Vec<(PathBuf, Box<dyn Write>)>,
+    report: Option<AuditReport>,
+    status: Option<StatusFifo>,
+    observers: Vec<Arc<dyn Observer>>,
+}
+
+impl EventSink {
+    /// Constructs an [EventSink] which discards all events.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Adds a stream writing to the file at `path`, or to stdout if `path` is
+    /// `-`.
+    pub fn add_stream(&mut self, path: &Path) -> io::Result<()> {
+        self.outs.push((path.to_owned(), open_output(path)?));
+        Ok(())
+    }
+
+    /// Writes the run's progress to the FIFO at `path` from now on, creating
+    /// it if needed.
+    pub fn add_status_fifo(&mut self, path: &Path) -> io::Result<()> {
+        self.status = Some(StatusFifo::open(path)?);
+        Ok(())
+    }
+
+    /// Notifies `observer` of every event emitted from now on.
+    pub fn add_observer(&mut self, observer: Arc<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Starts building an [AuditReport] from every event emitted from now on.
+    pub fn start_report(&mut self) {
+        self.report = Some(AuditReport::new());
+    }
+
+    /// Takes the [AuditReport] built so far, if one was started.
+    pub fn take_report(&mut self) -> Option<AuditReport> {
+        self.report.take()
+    }
+
+    /// Writes `event` to every stream.
+    ///
+    /// If writing fails the error is logged and that stream is closed, since a
+    /// truncated event stream is still more useful than aborting the run.
+    pub fn emit(&mut self, event: Event<'_>) {
+        if let Some(report) = &mut self.report {
+            report.record(&event);
+        }
+        if let Some(status) = &mut self.status {
+            status.record(&event);
+        }
+        for observer in &self.observers {
+            notify(observer.as_ref(), &event);
+        }
+        if self.outs.is_empty() {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_millis())
+            .unwrap_or_default();
+        let record = EventRecord {
+            version: EVENT_SCHEMA_VERSION,
+            timestamp,
+            event: &event,
+        };
+        let line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Error serializing an event: {e:?}");
+                return;
+            }
+        };
+        self.outs.retain_mut(|(path, out)| {
+            let res = out.write_all(&line).and_then(|()| out.write_all(b"\n"));
+            if let Err(e) = &res {
+                error!(
+                    "Error writing to the event stream {}: {e:?}; no more events will be \
+                     written to it.",
+                    path.display()
+                );
+            }
+            res.is_ok()
+        });
+    }
+
+    /// Flushes any buffered events.
+    pub fn flush(&mut self) {
+        for (path, out) in &mut self.outs {
+            if let Err(e) = out.flush() {
+                error!("Error flushing the event stream {}: {e:?}", path.display());
+            }
+        }
+    }
+}
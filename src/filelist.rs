@@ -0,0 +1,53 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// A list of paths to scan instead of walking directories, such as one
+/// produced by `find -print0`.
+#[derive(Debug, Clone)]
+pub struct FileList {
+    /// The file the list is read from, or `-` for stdin.
+    pub source: PathBuf,
+    /// The byte separating the paths in the list.
+    pub separator: u8,
+    /// Whether lines starting with `#` are comments, and surrounding
+    /// whitespace is ignored.
+    pub comments: bool,
+}
+
+impl FileList {
+    /// Whether the list is read from stdin.
+    pub fn is_stdin(&self) -> bool {
+        self.source == Path::new("-")
+    }
+
+    /// Reads the paths in the list, skipping any empty entries such as the one
+    /// after a trailing separator, along with any comments.
+    pub fn read(&self) -> io::Result<Vec<PathBuf>> {
+        let reader: Box<dyn BufRead> = if self.is_stdin() {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(BufReader::new(File::open(&self.source)?))
+        };
+        let mut paths = Vec::new();
+        for entry in reader.split(self.separator) {
+            let entry = entry?;
+            let entry = if self.comments {
+                entry.trim_ascii()
+            } else {
+                &entry
+            };
+            if self.comments && entry.starts_with(b"#") {
+                continue;
+            }
+            if !entry.is_empty() {
+                paths.push(PathBuf::from(OsStr::from_bytes(entry)));
+            }
+        }
+        Ok(paths)
+    }
+}
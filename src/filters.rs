@@ -0,0 +1,147 @@
+use std::{fmt, fs::Metadata, path::Path, sync::Arc};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// A set of glob patterns, such as `*.iso` or `**/node_modules/**`, matched
+/// against paths relative to the root being scanned.
+#[derive(Debug, Clone, Default)]
+pub struct PathPatterns {
+    /// The patterns as given.
+    files: GlobSet,
+    /// The directories whose entire contents match a pattern ending in `/**`,
+    /// so that they can be skipped without descending into them.
+    dirs: GlobSet,
+    /// The patterns as given, before compiling.
+    patterns: Vec<String>,
+}
+
+impl PathPatterns {
+    /// Compiles the given glob patterns.
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        let mut files = GlobSetBuilder::new();
+        let mut dirs = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = |pattern: &str| {
+                Glob::new(pattern).map_err(|e| format!("Invalid pattern {pattern:?}: {e}"))
+            };
+            files.add(glob(pattern)?);
+            if let Some(dir) = pattern.strip_suffix("/**") {
+                dirs.add(glob(dir)?);
+            }
+        }
+        let build = |builder: GlobSetBuilder| {
+            builder
+                .build()
+                .map_err(|e| format!("Invalid patterns {patterns:?}: {e}"))
+        };
+        Ok(Self {
+            files: build(files)?,
+            dirs: build(dirs)?,
+            patterns: patterns.to_vec(),
+        })
+    }
+
+    /// Whether any patterns were given.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// The patterns as given.
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Checks if the file at `relative` matches any of the patterns.
+    pub fn matches_file(&self, relative: &Path) -> bool {
+        self.files.is_match(relative)
+    }
+
+    /// Checks if everything inside the directory at `relative` matches the
+    /// patterns.
+    pub fn matches_dir(&self, relative: &Path) -> bool {
+        self.dirs.is_match(relative)
+    }
+}
+
+/// Whether the file or directory at `path` is hidden, i.e. its name starts
+/// with a `.`.
+pub fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+/// A filter supplied by a program embedding hldup, deciding which walked
+/// entries are scanned.
+///
+/// Closures taking the entry's path & metadata implement it, so policies the
+/// command line can't express, like skipping files some database refers to,
+/// can be plugged in directly.
+pub trait EntryFilter {
+    /// Whether to scan the entry at `path`; rejecting a directory skips
+    /// everything inside it.
+    fn keep(&self, path: &Path, meta: &Metadata) -> bool;
+}
+
+impl<F: Fn(&Path, &Metadata) -> bool> EntryFilter for F {
+    fn keep(&self, path: &Path, meta: &Metadata) -> bool {
+        self(path, meta)
+    }
+}
+
+/// A filter supplied by a program embedding hldup, deciding which pairs of
+/// identical files may be linked.
+///
+/// Closures taking the paths of the kept copy & the duplicate implement it.
+pub trait PairFilter {
+    /// Whether `duplicate` may be replaced by a link to `keeper`.
+    fn allow(&self, keeper: &Path, duplicate: &Path) -> bool;
+}
+
+impl<F: Fn(&Path, &Path) -> bool> PairFilter for F {
+    fn allow(&self, keeper: &Path, duplicate: &Path) -> bool {
+        self(keeper, duplicate)
+    }
+}
+
+/// The custom filters of a single kind set on a set of options, all of which
+/// must accept something for it to go ahead.
+pub struct CustomFilters<T: ?Sized>(pub Vec<Arc<T>>);
+
+impl CustomFilters<dyn EntryFilter> {
+    /// Whether every filter keeps the entry at `path`.
+    pub fn keep(&self, path: &Path, meta: &Metadata) -> bool {
+        self.0.iter().all(|filter| filter.keep(path, meta))
+    }
+}
+
+impl CustomFilters<dyn PairFilter> {
+    /// Whether every filter allows linking `duplicate` to `keeper`.
+    pub fn allow(&self, keeper: &Path, duplicate: &Path) -> bool {
+        self.0.iter().all(|filter| filter.allow(keeper, duplicate))
+    }
+}
+
+impl<T: ?Sized> CustomFilters<T> {
+    /// Whether there are no filters.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T: ?Sized> Default for CustomFilters<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: ?Sized> Clone for CustomFilters<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for CustomFilters<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CustomFilters({} filter(s))", self.0.len())
+    }
+}
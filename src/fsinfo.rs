@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File, Metadata},
+    io,
+    mem::MaybeUninit,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::MetadataExt,
+        },
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use log::debug;
+
+/// The `f_type` of ZFS filesystems, which libc doesn't define.
+const ZFS_SUPER_MAGIC: i64 = 0x2fc12fc1;
+/// The inode number of the root directory of every btrfs subvolume.
+const BTRFS_SUBVOL_ROOT_INO: u64 = 256;
+/// `BTRFS_IOC_SUBVOL_GETFLAGS`, i.e. `_IOR(0x94, 25, u64)`.
+const BTRFS_IOC_SUBVOL_GETFLAGS: u64 = 0x8008_9419;
+/// The subvolume flag marking a btrfs subvolume as read-only.
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+
+/// The table of the mounts visible to this process.
+const MOUNTS_PATH: &str = "/proc/self/mounts";
+
+/// A mounted filesystem, as listed in [MOUNTS_PATH].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Mount {
+    /// What's mounted, e.g. a block device.
+    pub source: String,
+    /// Where it's mounted.
+    pub path: PathBuf,
+    /// The filesystem type, e.g. `ext4`.
+    pub fs_type: String,
+    /// The mount options, e.g. `ro`.
+    pub options: Vec<String>,
+}
+
+impl Mount {
+    /// Whether the filesystem is mounted read-only.
+    pub fn read_only(&self) -> bool {
+        self.options.iter().any(|option| option == "ro")
+    }
+}
+
+/// Lists every mounted filesystem, in the order they were mounted.
+pub fn mounts() -> io::Result<Vec<Mount>> {
+    let table = fs::read_to_string(MOUNTS_PATH)?;
+    let mounts = table
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_ascii_whitespace().map(unescape_mount_field);
+            let (source, path, fs_type, options) = (
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            );
+            let text = |field: Vec<u8>| String::from_utf8_lossy(&field).into_owned();
+            Some(Mount {
+                source: text(source),
+                path: PathBuf::from(OsString::from_vec(path)),
+                fs_type: text(fs_type),
+                options: text(options).split(',').map(ToOwned::to_owned).collect(),
+            })
+        })
+        .collect();
+    Ok(mounts)
+}
+
+/// Undoes the octal escapes, like `\040` for a space, the kernel uses for
+/// whitespace & backslashes in the fields of [MOUNTS_PATH].
+fn unescape_mount_field(field: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..3)
+            .filter(|digits| byte == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)))
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match escaped {
+            Some(unescaped) => {
+                bytes.push(unescaped);
+                rest = &tail[3..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    bytes
+}
+
+/// Usage statistics for the filesystem a path lives on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FsStats {
+    /// The filesystem's fundamental block size.
+    pub block_size: u64,
+    /// The total size of the filesystem in bytes.
+    pub total_bytes: u64,
+    /// The number of bytes available to unprivileged users.
+    pub free_bytes: u64,
+    /// The total number of inodes on the filesystem.
+    pub total_inodes: u64,
+    /// The number of inodes available to unprivileged users.
+    pub free_inodes: u64,
+    /// Whether the filesystem is mounted read-only.
+    pub read_only: bool,
+}
+
+impl FsStats {
+    /// Retrieves the [FsStats] for the filesystem containing `path`.
+    // The statvfs field types vary in width between platforms
+    #[allow(clippy::unnecessary_cast)]
+    pub fn for_path(path: &Path) -> io::Result<Self> {
+        let raw_path = CString::new(path.as_os_str().as_bytes())?;
+        let mut buf = MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: `raw_path` is a valid NUL-terminated string and `buf` is a
+        // correctly sized out-parameter which is only read on success.
+        let res = unsafe { libc::statvfs(raw_path.as_ptr(), buf.as_mut_ptr()) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `statvfs` succeeded, so it has initialized `buf`.
+        let raw = unsafe { buf.assume_init() };
+        let fragment_size = raw.f_frsize as u64;
+        Ok(Self {
+            block_size: fragment_size,
+            total_bytes: raw.f_blocks as u64 * fragment_size,
+            free_bytes: raw.f_bavail as u64 * fragment_size,
+            total_inodes: raw.f_files as u64,
+            free_inodes: raw.f_favail as u64,
+            read_only: raw.f_flag & libc::ST_RDONLY != 0,
+        })
+    }
+}
+
+/// The block size of each filesystem seen, keyed by device ID, so that each is
+/// only queried once.
+#[derive(Debug, Clone, Default)]
+pub struct BlockSizes(HashMap<u64, u64>);
+
+impl BlockSizes {
+    /// Retrieves the block size of the filesystem with device ID `dev`, which
+    /// contains `path`, or 0 if it can't be read.
+    pub fn get(&mut self, dev: u64, path: &Path) -> u64 {
+        *self.0.entry(dev).or_insert_with(|| {
+            let dir = path.parent().unwrap_or(path);
+            match FsStats::for_path(dir) {
+                Ok(stats) => stats.block_size,
+                Err(e) => {
+                    debug!(
+                        "Could not read the block size for {}: {:?}",
+                        dir.display(),
+                        e
+                    );
+                    0
+                }
+            }
+        })
+    }
+}
+
+/// Retrieves the maximum number of hard links an inode may have on the
+/// filesystem containing `path`, or `None` if there is no limit.
+// The pathconf return type varies in width between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn link_max(path: &Path) -> io::Result<Option<u64>> {
+    let raw_path = CString::new(path.as_os_str().as_bytes())?;
+    // pathconf only signals an error by setting errno, since -1 also means
+    // "no limit"
+    // SAFETY: `__errno_location` always returns a valid pointer to this
+    // thread's errno.
+    unsafe { *libc::__errno_location() = 0 };
+    // SAFETY: `raw_path` is a valid NUL-terminated string that outlives the call.
+    let res = unsafe { libc::pathconf(raw_path.as_ptr(), libc::_PC_LINK_MAX) };
+    if res >= 0 {
+        return Ok(Some(res as u64));
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(0) {
+        Ok(None)
+    } else {
+        Err(err)
+    }
+}
+
+/// Checks if `path` is on an overlay filesystem, such as a container's root.
+// The statfs constant types vary in width between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn is_overlay(path: &Path) -> io::Result<bool> {
+    Ok(fs_magic(path)? == libc::OVERLAYFS_SUPER_MAGIC as i64)
+}
+
+/// Checks if `path` is in a cgroup v2 hierarchy.
+// The statfs constant types vary in width between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn is_cgroup2(path: &Path) -> io::Result<bool> {
+    Ok(fs_magic(path)? == libc::CGROUP2_SUPER_MAGIC as i64)
+}
+
+/// Checks if `path` is on a filesystem which supports snapshots, where files
+/// held by a snapshot keep using space after they're replaced.
+// The statfs constant types vary in width between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn supports_snapshots(path: &Path) -> io::Result<bool> {
+    let magic = fs_magic(path)?;
+    Ok(magic == ZFS_SUPER_MAGIC || magic == libc::BTRFS_SUPER_MAGIC as i64)
+}
+
+/// Checks if the directory at `path`, with metadata `meta`, is a filesystem
+/// snapshot: either a ZFS snapshot under `.zfs/snapshot`, or a read-only btrfs
+/// subvolume.
+// The statfs constant types vary in width between platforms
+#[allow(clippy::unnecessary_cast)]
+pub fn is_snapshot(path: &Path, meta: &Metadata) -> io::Result<bool> {
+    let mut components = path.components().map(Component::as_os_str);
+    if components.any(|c| c == ".zfs") && components.next() == Some(OsStr::new("snapshot")) {
+        return Ok(true);
+    }
+    if meta.ino() != BTRFS_SUBVOL_ROOT_INO || fs_magic(path)? != libc::BTRFS_SUPER_MAGIC as i64 {
+        return Ok(false);
+    }
+    let dir = File::open(path)?;
+    let mut flags = 0u64;
+    // SAFETY: `dir` is an open file descriptor for the duration of the call,
+    // and `flags` is the u64 out-parameter this ioctl expects.
+    let res = unsafe { libc::ioctl(dir.as_raw_fd(), BTRFS_IOC_SUBVOL_GETFLAGS as _, &mut flags) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & BTRFS_SUBVOL_RDONLY != 0)
+}
+
+/// Retrieves the magic number identifying the type of the filesystem
+/// containing `path`.
+// The statfs field types vary in width between platforms
+#[allow(clippy::unnecessary_cast)]
+fn fs_magic(path: &Path) -> io::Result<i64> {
+    let raw_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `raw_path` is a valid NUL-terminated string and `buf` is a
+    // correctly sized out-parameter which is only read on success.
+    let res = unsafe { libc::statfs(raw_path.as_ptr(), buf.as_mut_ptr()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `statfs` succeeded, so it has initialized `buf`.
+    let raw = unsafe { buf.assume_init() };
+    Ok(raw.f_type as i64)
+}
+
+/// Retrieves the ID of the mount containing `path`, following symlinks, or
+/// `None` if the kernel can't report it.
+///
+/// Unlike the device ID, this tells apart different mounts of the same
+/// filesystem, such as bind mounts into a container, which can't be hard-linked
+/// across.
+pub fn mount_id(path: &Path) -> io::Result<Option<u64>> {
+    let raw_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = MaybeUninit::<libc::statx>::uninit();
+    // SAFETY: `raw_path` is a valid NUL-terminated string and `buf` is a
+    // correctly sized out-parameter which is only read on success.
+    let res = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            raw_path.as_ptr(),
+            0,
+            libc::STATX_MNT_ID,
+            buf.as_mut_ptr(),
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `statx` succeeded, so it has initialized `buf`.
+    let raw = unsafe { buf.assume_init() };
+    Ok((raw.stx_mask & libc::STATX_MNT_ID != 0).then_some(raw.stx_mnt_id))
+}
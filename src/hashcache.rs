@@ -1,31 +1,183 @@
 use std::{
+    cell::RefCell,
     collections::{HashMap, HashSet},
-    fmt::Debug,
-    fs::File,
+    fmt::{self, Debug, Display},
+    fs::Metadata,
     hash::{Hash, Hasher},
     io::{self, Seek, SeekFrom},
+    num::ParseIntError,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use log::trace;
 use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::Xxh3;
 
-use crate::{
-    read_exact_or_end,
-    utils::{GB, MB},
-};
+use crate::utils::{format_size, AlignedBuffer, BlockReader, ReadOptions, GB, MB, PAGE_ALIGN};
 
-/// The number of bytes in each sample.
-const SAMPLE_SIZE: usize = 8 * 1024;
+/// The default number of bytes in each sample.
+const DEFAULT_SAMPLE_SIZE: usize = 8 * 1024;
 
 /// The minimum samples to take when hashing a file.
-const MIN_SAMPLES: u32 = 2;
+pub const MIN_SAMPLES: u32 = 2;
+/// The most samples `--max-samples` can ask for per file.
+pub const MAX_MAX_SAMPLES: u32 = 1 << 16;
+/// The largest sample `--sample-size` can ask for, since every hashing thread
+/// keeps a buffer of this size.
+pub const MAX_SAMPLE_SIZE: u64 = 64 * MB;
 /// The maximum size of a file where we will take [MIN_SAMPLES] samples.
-const MIN_SAMPLES_MAX: u64 = 1 * MB;
-/// The maximum number to take when hashing a file (-1 due to modulo calculations).
-const MAX_SAMPLES: u32 = 4;
-/// The minimum size of a file where we will take [MAX_SAMPLES] samples.
+const MIN_SAMPLES_MAX: u64 = MB;
+/// The default maximum number to take when hashing a file (-1 due to modulo
+/// calculations).
+const DEFAULT_MAX_SAMPLES: u32 = 4;
+/// The minimum size of a file where we will take the maximum number of samples.
 const MAX_SAMPLES_MIN: u64 = 16 * GB;
+/// The most times the samples of a file whose samples are all zero are
+/// doubled, looking for something that tells it apart.
+const ZERO_RESAMPLE_ROUNDS: u32 = 3;
+
+thread_local! {
+    /// The buffer samples are read into, kept around between files.
+    static SAMPLE_BUFFER: RefCell<AlignedBuffer> =
+        RefCell::new(AlignedBuffer::new(DEFAULT_SAMPLE_SIZE, PAGE_ALIGN));
+}
+
+/// The algorithm the samples of a file are hashed with to build its
+/// [FileHashes].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    /// The [seahash] algorithm, which is the fastest.
+    #[default]
+    Seahash,
+    /// The 64-bit XXH3 algorithm.
+    Xxh3,
+    /// The cryptographic [blake3] algorithm, truncated to 64 bits.
+    Blake3,
+    /// A [ContentHasher] supplied by an embedding program, which can't be
+    /// chosen for sampling.
+    Custom,
+}
+
+impl HashAlgo {
+    /// Parses a [HashAlgo] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "seahash" => Ok(Self::Seahash),
+            "xxh3" => Ok(Self::Xxh3),
+            "blake3" => Ok(Self::Blake3),
+            other => Err(format!(
+                "Unknown hash algorithm {other:?}; expected seahash, xxh3, or blake3"
+            )),
+        }
+    }
+
+    /// Starts a new hash using this algorithm.
+    fn hasher(self) -> SampleHasher {
+        match self {
+            Self::Seahash => SampleHasher::Sea(SeaHasher::new()),
+            Self::Xxh3 => SampleHasher::Xxh3(Box::new(Xxh3::new())),
+            Self::Blake3 => SampleHasher::Blake3(Box::new(blake3::Hasher::new())),
+            // Custom hashes aren't built from samples, so there's nothing
+            // better to fall back on than the default
+            Self::Custom => SampleHasher::Sea(SeaHasher::new()),
+        }
+    }
+}
+
+impl Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Seahash => "seahash",
+            Self::Xxh3 => "xxh3",
+            Self::Blake3 => "blake3",
+            Self::Custom => "a custom hasher",
+        })
+    }
+}
+
+/// An in-progress hash of a file's samples, using one of the [HashAlgo]s.
+enum SampleHasher {
+    Sea(SeaHasher),
+    Xxh3(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl SampleHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Sea(hasher) => hasher.write(bytes),
+            Self::Xxh3(hasher) => hasher.update(bytes),
+            Self::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            Self::Sea(hasher) => hasher.finish(),
+            Self::Xxh3(hasher) => hasher.digest(),
+            Self::Blake3(hasher) => {
+                let digest = hasher.finalize();
+                let mut prefix = [0; 8];
+                prefix.copy_from_slice(&digest.as_bytes()[..8]);
+                u64::from_le_bytes(prefix)
+            }
+        }
+    }
+}
+
+/// How files are sampled when calculating their [FileHashes].
+///
+/// Files hashed with different [SampleParams] get different [FileHashes] even
+/// when they're identical, so hashes (and the [GroupId]s derived from them)
+/// can only be compared when they were calculated with the same parameters.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct SampleParams {
+    /// The number of bytes in each sample.
+    pub sample_size: usize,
+    /// The maximum number of samples to take from a file.
+    pub max_samples: u32,
+    /// The algorithm the samples are hashed with.
+    #[serde(default)]
+    pub algo: HashAlgo,
+    /// Whether files whose samples are all zero get extra samples, since
+    /// otherwise every pre-allocated file or VM image of the same size would
+    /// share its hashes; earlier versions didn't, hence the serde default.
+    #[serde(default)]
+    pub resample_zeros: bool,
+}
+
+impl Default for SampleParams {
+    fn default() -> Self {
+        Self {
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            max_samples: DEFAULT_MAX_SAMPLES,
+            algo: HashAlgo::default(),
+            resample_zeros: true,
+        }
+    }
+}
+
+impl Display for SampleParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} samples, up to {} per file, hashed with {}",
+            format_size(self.sample_size as u64),
+            self.max_samples,
+            self.algo
+        )?;
+        if !self.resample_zeros {
+            f.write_str(", without resampling all-zero files")?;
+        }
+        Ok(())
+    }
+}
 
 /// A set of hash values to identify a file when looking for potential file
 /// duplicates.
@@ -33,47 +185,389 @@ const MAX_SAMPLES_MIN: u64 = 16 * GB;
 /// Note that it should NOT be assumed that 2 files with the same [FileHashes]
 /// are identical; this structure explicitly and emphatically trades collision
 /// detection accuracy for speed.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
 pub struct FileHashes {
     /// A hash made by feeding a number of samples from different locations in
-    /// the file into a the [seahash] algorithm.
-    sea: u64,
+    /// the file into the `algo` algorithm.
+    #[serde(alias = "sea")]
+    hash: u64,
     /// The size of the file, treated as a hash.
     size: u64,
+    /// The algorithm `hash` was calculated with, so that hashes from
+    /// different algorithms are never mistaken for each other.
+    #[serde(default)]
+    algo: HashAlgo,
 }
 
 impl FileHashes {
     /// Calculates the [FileHashes] for the file at the given path.
-    pub fn from_path(path: &Path) -> Result<Self, io::Error> {
+    pub fn from_path(path: &Path, opts: ReadOptions) -> Result<Self, io::Error> {
         trace!("Now hashing {path:?}");
 
-        let mut fh = File::open(path)?;
+        let mut fh = BlockReader::open(path, opts)?;
 
         // Calculate the size using a seek-to-end to avoid the fs::metadata
         // call, which is very slow on certain platforms due to all the extra
         // information it pulls in
         let size = fh.seek(SeekFrom::End(0))?;
         fh.seek(SeekFrom::Start(0))?;
-        let skiplen = calculate_skiplen(size, SAMPLE_SIZE);
+        let params = opts.sampling;
+        let skiplen = calculate_skiplen(size, params);
 
-        let mut sea_hasher = SeaHasher::new();
-        let mut buffer = vec![0; SAMPLE_SIZE].into_boxed_slice();
+        let mut hasher = params.algo.hasher();
         let mut total_read = 0;
         let mut samples = 0;
-        loop {
-            let read_count = read_exact_or_end(&mut fh, &mut buffer)?;
-            total_read += read_count;
-            let subbuf = &buffer[..read_count];
-            sea_hasher.write(subbuf);
-            samples += 1;
-            if read_count != buffer.len() {
-                break;
+        let mut all_zero = true;
+        SAMPLE_BUFFER.with_borrow_mut(|buffer| -> io::Result<()> {
+            if buffer.len() != params.sample_size {
+                *buffer = AlignedBuffer::new(params.sample_size, PAGE_ALIGN);
             }
-            fh.seek(SeekFrom::Current(skiplen))?;
-        }
+            // Hashes the next sample, returning whether it filled the buffer &
+            // whether it was all zero
+            let mut sample = |fh: &mut BlockReader, hasher: &mut SampleHasher| {
+                let read_count = fh.read_block(buffer)?;
+                total_read += read_count;
+                let subbuf = &buffer[..read_count];
+                hasher.write(subbuf);
+                samples += 1;
+                let zero = subbuf.iter().all(|&b| b == 0);
+                io::Result::Ok((read_count == params.sample_size, zero))
+            };
+            loop {
+                let (full, zero) = sample(&mut fh, &mut hasher)?;
+                all_zero &= zero;
+                if !full {
+                    break;
+                }
+                fh.seek(SeekFrom::Current(skiplen))?;
+            }
+            if !params.resample_zeros || skiplen == 0 {
+                return Ok(());
+            }
+            // Samples that are all zero say next to nothing about the file, so
+            // the end of the file is sampled, where e.g. disk images keep a
+            // backup partition table, and then the gaps between the samples,
+            // halving them each round until something nonzero turns up. This
+            // only depends on the content, so identical files still get the
+            // same hashes.
+            let sample_size = params.sample_size as u64;
+            if all_zero {
+                fh.seek(SeekFrom::Start(size.saturating_sub(sample_size)))?;
+                all_zero &= sample(&mut fh, &mut hasher)?.1;
+            }
+            let mut stride = skiplen as u64 + sample_size;
+            for _ in 0..ZERO_RESAMPLE_ROUNDS {
+                if !all_zero || stride < 2 * sample_size {
+                    break;
+                }
+                let mut offset = stride / 2;
+                while offset < size {
+                    fh.seek(SeekFrom::Start(offset))?;
+                    all_zero &= sample(&mut fh, &mut hasher)?.1;
+                    offset += stride;
+                }
+                stride /= 2;
+            }
+            Ok(())
+        })?;
         trace!("Finished hashing {path:?} using using {samples} samples ({total_read} bytes).");
-        let sea = sea_hasher.finish();
-        Ok(Self { sea, size })
+        let hash = hasher.finish();
+        Ok(Self {
+            hash,
+            size,
+            algo: params.algo,
+        })
+    }
+
+    /// Calculates the [FileHashes] for the file at `path`, which is `size`
+    /// bytes long, using a custom `hasher` in place of sampling it.
+    pub fn from_hasher(path: &Path, size: u64, hasher: &dyn ContentHasher) -> io::Result<Self> {
+        trace!("Now hashing {path:?} with a custom hasher");
+        Ok(Self {
+            hash: hasher.hash_file(path)?,
+            size,
+            algo: HashAlgo::Custom,
+        })
+    }
+
+    /// The size of the hashed file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The [GroupId] of the duplicate group files with these hashes belong to.
+    pub fn group_id(&self) -> GroupId {
+        let mut hasher = SeaHasher::new();
+        hasher.write_u64(self.hash);
+        hasher.write_u64(self.size);
+        // Only mixed in for the other algorithms, so that seahash groups keep
+        // the IDs recorded by earlier runs
+        if self.algo != HashAlgo::Seahash {
+            self.algo.hash(&mut hasher);
+        }
+        GroupId(hasher.finish())
+    }
+}
+
+/// The value a [ContentHasher] identifies a file's contents by.
+pub type HashValue = u64;
+
+/// Hashes the contents of files to find possible duplicates, in place of the
+/// built-in [SampledHasher].
+///
+/// Files are only grouped together when they're the same size as well, so a
+/// hasher needn't mix the size in; identical files must get the same value,
+/// but different files may share one, since candidates are compared in full
+/// before being linked. Closures taking the path implement it.
+///
+/// Hashes from a custom hasher never match built-in ones, and can't tell 2
+/// custom hashers apart, so a [PersistentState](crate::state::PersistentState)
+/// recorded with 1 hasher shouldn't be reused with another.
+pub trait ContentHasher: Send + Sync {
+    /// Hashes the contents of the file at `path`.
+    fn hash_file(&self, path: &Path) -> io::Result<HashValue>;
+}
+
+impl<F: Fn(&Path) -> io::Result<HashValue> + Send + Sync> ContentHasher for F {
+    fn hash_file(&self, path: &Path) -> io::Result<HashValue> {
+        self(path)
+    }
+}
+
+/// The built-in [ContentHasher], hashing a few samples of each file as set by
+/// the [SampleParams] in its [ReadOptions].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampledHasher(pub ReadOptions);
+
+impl ContentHasher for SampledHasher {
+    fn hash_file(&self, path: &Path) -> io::Result<HashValue> {
+        FileHashes::from_path(path, self.0).map(|hashes| hashes.hash)
+    }
+}
+
+/// A [ContentHasher] which reads nothing, so files are grouped by their size
+/// alone & every candidate is compared in full.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeOnlyHasher;
+
+impl ContentHasher for SizeOnlyHasher {
+    fn hash_file(&self, _path: &Path) -> io::Result<HashValue> {
+        Ok(0)
+    }
+}
+
+/// A [ContentHasher] set on a [ScanOptions](crate::ScanOptions).
+#[derive(Clone)]
+pub struct CustomHasher(pub Arc<dyn ContentHasher>);
+
+impl Debug for CustomHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomHasher(..)")
+    }
+}
+
+/// The size of the reads used by [full_hash].
+const FULL_HASH_READ_SIZE: usize = MB as usize;
+
+/// Hashes the entire contents of the file at `path`, rather than the samples
+/// [FileHashes] are built from.
+///
+/// This is far too slow for finding duplicates, but is used to check how well
+/// the sampled hashes stand in for the full contents.
+pub fn full_hash(path: &Path, opts: ReadOptions) -> io::Result<u64> {
+    let mut fh = BlockReader::open_sequential(path, opts)?;
+    let mut buffer = AlignedBuffer::new(FULL_HASH_READ_SIZE, PAGE_ALIGN);
+    let mut hasher = SeaHasher::new();
+    loop {
+        let read_count = fh.read_block(&mut buffer)?;
+        hasher.write(&buffer[..read_count]);
+        if read_count != buffer.len() {
+            return Ok(hasher.finish());
+        }
+    }
+}
+
+/// Calculates the [ContentDigest] of the entire contents of the file at `path`.
+pub fn content_digest(path: &Path, opts: ReadOptions) -> io::Result<ContentDigest> {
+    let mut fh = BlockReader::open_sequential(path, opts)?;
+    let mut buffer = AlignedBuffer::new(FULL_HASH_READ_SIZE, PAGE_ALIGN);
+    let mut hasher = blake3::Hasher::new();
+    loop {
+        let read_count = fh.read_block(&mut buffer)?;
+        hasher.update(&buffer[..read_count]);
+        if read_count != buffer.len() {
+            return Ok(hasher.finalize().into());
+        }
+    }
+}
+
+/// Everything captured about a file while scanning it: its [FileHashes] along
+/// with the identity & state of its inode, so that later phases don't need to
+/// `stat` it again.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct FileRecord {
+    /// The file's hashes.
+    pub hashes: FileHashes,
+    /// The device the file's inode lives on.
+    pub dev: u64,
+    /// The file's inode number.
+    pub ino: u64,
+    /// The total number of links to the file's inode.
+    pub nlink: u64,
+    /// The file's modification time, in seconds since the Unix epoch.
+    pub mtime: i64,
+    /// The nanosecond part of the file's modification time.
+    pub mtime_nsec: i64,
+    /// Whether the path is a symlink; if so, the other fields describe the
+    /// file it points to.
+    pub symlink: bool,
+    /// Whether the file came from an `--append-scan` of an archive rather than
+    /// this run's scan, so it may be linked to but is never replaced.
+    pub archived: bool,
+}
+
+impl FileRecord {
+    /// Starts building a [FileRecord].
+    pub fn builder() -> FileRecordBuilder {
+        FileRecordBuilder::default()
+    }
+}
+
+/// A builder for [FileRecord]s; any field not set is left zeroed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileRecordBuilder {
+    record: FileRecord,
+}
+
+impl FileRecordBuilder {
+    /// Sets the file's hashes.
+    pub fn hashes(mut self, hashes: FileHashes) -> Self {
+        self.record.hashes = hashes;
+        self
+    }
+
+    /// Sets the inode identity & modification time from the file's metadata,
+    /// which for a symlink should be the metadata of the file it points to.
+    pub fn metadata(self, meta: &Metadata) -> Self {
+        self.inode(meta.dev(), meta.ino(), meta.nlink())
+            .mtime(meta.mtime(), meta.mtime_nsec())
+    }
+
+    /// Sets the file's inode identity & link count.
+    pub fn inode(mut self, dev: u64, ino: u64, nlink: u64) -> Self {
+        self.record.dev = dev;
+        self.record.ino = ino;
+        self.record.nlink = nlink;
+        self
+    }
+
+    /// Sets the file's modification time.
+    pub fn mtime(mut self, secs: i64, nsecs: i64) -> Self {
+        self.record.mtime = secs;
+        self.record.mtime_nsec = nsecs;
+        self
+    }
+
+    /// Sets whether the path is a symlink.
+    pub fn symlink(mut self, symlink: bool) -> Self {
+        self.record.symlink = symlink;
+        self
+    }
+
+    /// Sets whether the file came from a scan of an archive.
+    pub fn archived(mut self, archived: bool) -> Self {
+        self.record.archived = archived;
+        self
+    }
+
+    /// Finishes building the [FileRecord].
+    pub fn build(self) -> FileRecord {
+        self.record
+    }
+}
+
+/// A stable identifier for a group of possible duplicates, derived purely from
+/// the group's [FileHashes] so that the same content gets the same ID across
+/// runs hashed with the same [SampleParams].
+///
+/// Since the hashes are sampled, files with different contents can share an
+/// ID; the [ContentDigest] of files found identical identifies their contents
+/// whatever the sampling.
+///
+/// When serialized, the ID is written in the same hex form it's displayed in.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct GroupId(u64);
+
+impl Display for GroupId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl From<GroupId> for String {
+    fn from(value: GroupId) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for GroupId {
+    type Error = ParseIntError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        u64::from_str_radix(&value, 16).map(GroupId)
+    }
+}
+
+/// A cryptographic digest of a file's entire contents, strong enough that 2
+/// files with the same digest can be treated as identical without comparing
+/// them.
+///
+/// When serialized, the digest is written in hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ContentDigest(blake3::Hash);
+
+impl From<blake3::Hash> for ContentDigest {
+    fn from(value: blake3::Hash) -> Self {
+        Self(value)
+    }
+}
+
+impl Display for ContentDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
+impl From<ContentDigest> for String {
+    fn from(value: ContentDigest) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for ContentDigest {
+    type Error = blake3::HexError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        blake3::Hash::from_hex(value).map(ContentDigest)
+    }
+}
+
+/// A set of paths which share the same [FileHashes], and are therefore
+/// possibly duplicates of each other.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DuplicateGroup {
+    /// The hashes shared by every path in the group.
+    pub hashes: FileHashes,
+    /// The paths in the group.
+    pub paths: HashSet<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// This group's stable [GroupId].
+    pub fn id(&self) -> GroupId {
+        self.hashes.group_id()
     }
 }
 
@@ -82,6 +576,7 @@ impl FileHashes {
 #[derive(Default)]
 pub struct HashCache {
     inner: HashMap<FileHashes, HashSet<PathBuf>>,
+    records: HashMap<PathBuf, FileRecord>,
 }
 
 impl HashCache {
@@ -95,6 +590,17 @@ impl HashCache {
         self.inner.entry(hashes).or_default().insert(path);
     }
 
+    /// Inserts a scanned path & its [FileRecord] into this [HashCache].
+    pub fn insert_record(&mut self, path: PathBuf, record: FileRecord) {
+        self.insert(path.clone(), record.hashes);
+        self.records.insert(path, record);
+    }
+
+    /// The [FileRecord] of every path inserted with one.
+    pub fn records(&self) -> &HashMap<PathBuf, FileRecord> {
+        &self.records
+    }
+
     /// Joins 2 [HashCache] collections into a single [HashCache].
     ///
     /// The returned values will have all hashes & files from both [self] and `other`.
@@ -102,20 +608,58 @@ impl HashCache {
         for (k, v) in other.inner {
             self.inner.entry(k).or_default().extend(v);
         }
-        Self { inner: self.inner }
+        self.records.extend(other.records);
+        Self {
+            inner: self.inner,
+            records: self.records,
+        }
+    }
+
+    /// Iterates over every set of paths sharing the same [FileHashes],
+    /// including sets of just 1 path.
+    pub fn iter(&self) -> impl Iterator<Item = (&FileHashes, &HashSet<PathBuf>)> {
+        self.inner.iter()
     }
 
     /// Retrieves the list of paths with duplicate hash values.
     ///
     /// Each entry of the returned list represents a set of paths with the same
     /// hash.
-    pub fn duplicates(&self) -> Vec<HashSet<PathBuf>> {
-        self.inner
-            .values()
-            .filter(|buf| buf.len() >= 2)
-            .cloned()
+    pub fn duplicates(&self) -> Vec<DuplicateGroup> {
+        self.duplicate_entries()
+            .map(|(hashes, paths)| DuplicateGroup {
+                hashes: *hashes,
+                paths: paths.clone(),
+            })
             .collect()
     }
+
+    /// Iterates over the sets of paths with duplicate hash values, without
+    /// copying them, so callers can start on the first set before the rest are
+    /// found.
+    pub fn duplicate_groups(&self) -> impl Iterator<Item = &HashSet<PathBuf>> {
+        self.duplicate_entries().map(|(_, paths)| paths)
+    }
+
+    /// Removes every set of paths from this [HashCache], yielding the ones
+    /// with duplicate hash values as they're reached; sets of just 1 path are
+    /// dropped.
+    ///
+    /// The iterator doesn't borrow the cache, and the [FileRecord]s of the
+    /// paths are left in place, so they can still be looked up while the
+    /// groups are handled.
+    pub fn drain_duplicates(&mut self) -> impl Iterator<Item = DuplicateGroup> {
+        std::mem::take(&mut self.inner)
+            .into_iter()
+            .filter(|(_, paths)| paths.len() >= 2)
+            .map(|(hashes, paths)| DuplicateGroup { hashes, paths })
+    }
+
+    /// Iterates over every set of paths with duplicate hash values, along with
+    /// the hashes they share.
+    fn duplicate_entries(&self) -> impl Iterator<Item = (&FileHashes, &HashSet<PathBuf>)> {
+        self.inner.iter().filter(|(_, paths)| paths.len() >= 2)
+    }
 }
 
 impl Debug for HashCache {
@@ -145,23 +689,29 @@ impl FromIterator<HashCache> for HashCache {
 // would take an absurd amount of time. Since the hash calculation's goal is
 // already purely to speed up the program itself we sacrifise accuracy for speed
 // and allow later steps to clean up our clumsiness.
-fn calculate_skiplen(filesize: u64, buffsize: usize) -> i64 {
-    let buffsize = buffsize as u64;
-    if filesize <= (MIN_SAMPLES as u64) * buffsize {
+fn calculate_skiplen(filesize: u64, params: SampleParams) -> i64 {
+    let buffsize = params.sample_size as u64;
+    let max_samples = u64::from(params.max_samples.max(MIN_SAMPLES));
+    let min_samples = u64::from(MIN_SAMPLES);
+    if filesize <= min_samples.saturating_mul(buffsize) {
         return 0;
     }
 
     // We scale it logarithmically by getting the base-2 log of all sizes and
     // then doing a basic linear map
+    //
+    // The parameters may come from a state file rather than the command line,
+    // so nothing here is allowed to overflow however large they are
 
-    let size_factor = filesize.ilog2();
+    let size_factor = u64::from(filesize.ilog2());
 
-    let samples = MIN_SAMPLES
-        + (size_factor * (MAX_SAMPLES - MIN_SAMPLES))
-            / (MAX_SAMPLES_MIN.ilog2() - MIN_SAMPLES_MAX.ilog2());
-    let samples = samples.min(MAX_SAMPLES) as u64;
+    let samples = min_samples.saturating_add(
+        size_factor.saturating_mul(max_samples - min_samples)
+            / u64::from(MAX_SAMPLES_MIN.ilog2() - MIN_SAMPLES_MAX.ilog2()),
+    );
+    let samples = samples.min(max_samples);
 
     // Subtract buffsize because that will already be consumed during the `read`
     // call
-    ((filesize / samples) - buffsize) as i64
+    i64::try_from((filesize / samples).saturating_sub(buffsize)).unwrap_or(i64::MAX)
 }
@@ -0,0 +1,233 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, Metadata},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hashcache::{content_digest, ContentDigest, FileRecord, HashCache},
+    state::{FileIdentity, PersistentState},
+    utils::{parallel_map, ReadOptions},
+};
+
+/// A persistent index of every distinct content seen across runs, mapping each
+/// content's [ContentDigest] to a file holding it.
+///
+/// New files can then be linked against files indexed by earlier runs, such as
+/// those in an archive, without scanning the archive again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ContentIndex {
+    /// The files holding each content, with at most 1 per device since only
+    /// files on the same device can be linked together.
+    #[serde(default)]
+    entries: HashMap<ContentDigest, Vec<IndexedFile>>,
+    /// The digests calculated during this run, so that files aren't hashed
+    /// twice.
+    #[serde(skip)]
+    calculated: HashMap<FileIdentity, ContentDigest>,
+}
+
+/// A file holding an indexed content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedFile {
+    /// The identity of the file as of when it was indexed, so that an entry
+    /// stops matching once the file changes.
+    pub identity: FileIdentity,
+    /// A path to the file.
+    pub path: PathBuf,
+}
+
+impl IndexedFile {
+    /// Reads the metadata of the file at `path` if it's still the one that was
+    /// indexed.
+    fn current_metadata(&self) -> Option<Metadata> {
+        fs::symlink_metadata(&self.path)
+            .ok()
+            .filter(|meta| FileIdentity::from_metadata(meta) == self.identity)
+    }
+}
+
+impl ContentIndex {
+    /// Loads the [ContentIndex] stored at `path`.
+    ///
+    /// A missing file is treated as an empty index, since that's what we'll
+    /// have on the very first run.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let fh = match File::open(path) {
+            Ok(fh) => fh,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No index found at {}; starting fresh.", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e),
+        };
+        let retvl = serde_json::from_reader(BufReader::new(fh))?;
+        Ok(retvl)
+    }
+
+    /// Writes this [ContentIndex] to `path`, replacing the previous index only
+    /// once it's fully written.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let fh = File::create(&tmp_path)?;
+        serde_json::to_writer(BufWriter::new(fh), self)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Adds the indexed files with the same content as any file in `cache` to
+    /// it, so that they're found as duplicates of each other.
+    ///
+    /// Only files sharing their size & device with an indexed file are hashed.
+    /// Matches are recorded as verified in `state`, so that neither file needs
+    /// to be read again when they're compared.
+    pub fn add_matches(
+        &mut self,
+        cache: &mut HashCache,
+        state: &mut PersistentState,
+        read_opts: ReadOptions,
+    ) {
+        let indexed = self
+            .entries
+            .values()
+            .flatten()
+            .map(|file| (file.identity.dev, file.identity.size))
+            .collect::<HashSet<_>>();
+        let candidates = cache
+            .records()
+            .iter()
+            .filter(|(_, record)| !record.symlink)
+            .filter(|(_, record)| indexed.contains(&(record.dev, record.hashes.size())))
+            .map(|(path, record)| (path.clone(), *record))
+            .collect::<Vec<_>>();
+        let identities = candidates
+            .iter()
+            .map(|(path, record)| (path.clone(), FileIdentity::from(record)))
+            .collect::<Vec<_>>();
+        debug!(
+            "Checking {} file(s) against the content index.",
+            candidates.len()
+        );
+        let digests = self.calculate(&identities, read_opts);
+
+        let mut matched = 0;
+        for ((path, record), digest) in candidates.iter().zip(digests) {
+            let Some(digest) = digest else {
+                continue;
+            };
+            let Some(file) = self
+                .entries
+                .get(&digest)
+                .and_then(|files| files.iter().find(|file| file.identity.dev == record.dev))
+            else {
+                continue;
+            };
+            if file.identity.ino == record.ino || cache.records().contains_key(&file.path) {
+                continue;
+            }
+            let Some(meta) = file.current_metadata() else {
+                debug!(
+                    "Indexed file {} has changed; not linking against it.",
+                    file.path.display()
+                );
+                continue;
+            };
+            debug!(
+                "{} matches indexed file {}.",
+                path.display(),
+                file.path.display()
+            );
+            let indexed = FileRecord::builder()
+                .metadata(&meta)
+                .hashes(record.hashes)
+                .build();
+            state.record_verified(
+                record.hashes.group_id(),
+                digest,
+                [file.identity, FileIdentity::from(record)],
+            );
+            cache.insert_record(file.path.clone(), indexed);
+            matched += 1;
+        }
+        if matched > 0 {
+            info!("Found {matched} file(s) matching the content index.");
+        }
+    }
+
+    /// Indexes every file in `cache` whose content isn't indexed yet.
+    ///
+    /// Files are looked up again rather than trusting `cache`, since linking
+    /// may have replaced them.
+    pub fn update(&mut self, cache: &HashCache, read_opts: ReadOptions) {
+        let indexed = self
+            .entries
+            .values()
+            .flatten()
+            .map(|file| file.identity)
+            .collect::<HashSet<_>>();
+        let mut seen = HashSet::new();
+        let mut unindexed = Vec::new();
+        for path in cache.records().keys() {
+            let Ok(meta) = fs::symlink_metadata(path) else {
+                continue;
+            };
+            let identity = FileIdentity::from_metadata(&meta);
+            // Empty files are all alike, and never worth linking
+            if !meta.is_file() || meta.len() == 0 || indexed.contains(&identity) {
+                continue;
+            }
+            if seen.insert((identity.dev, identity.ino)) {
+                unindexed.push((path.clone(), identity));
+            }
+        }
+        if unindexed.is_empty() {
+            return;
+        }
+        info!("Adding {} file(s) to the content index.", unindexed.len());
+        let digests = self.calculate(&unindexed, read_opts);
+        for ((path, identity), digest) in unindexed.into_iter().zip(digests) {
+            let Some(digest) = digest else {
+                continue;
+            };
+            let files = self.entries.entry(digest).or_default();
+            // Files that changed since being indexed are replaced by the new one
+            files.retain(|file| file.current_metadata().is_some());
+            if !files.iter().any(|file| file.identity.dev == identity.dev) {
+                files.push(IndexedFile { identity, path });
+            }
+        }
+    }
+
+    /// Calculates the digests of `files`, reusing any already calculated this
+    /// run and logging errors.
+    fn calculate(
+        &mut self,
+        files: &[(PathBuf, FileIdentity)],
+        read_opts: ReadOptions,
+    ) -> Vec<Option<ContentDigest>> {
+        let calculated = &self.calculated;
+        let digests = parallel_map(files, read_opts.thread_count(), |(path, identity)| {
+            if let Some(digest) = calculated.get(identity) {
+                return Some(*digest);
+            }
+            match content_digest(path, read_opts) {
+                Ok(digest) => Some(digest),
+                Err(e) => {
+                    error!("Error hashing {} for the index: {:?}", path.display(), e);
+                    None
+                }
+            }
+        });
+        for ((_, identity), digest) in files.iter().zip(&digests) {
+            if let Some(digest) = digest {
+                self.calculated.insert(*identity, *digest);
+            }
+        }
+        digests
+    }
+}
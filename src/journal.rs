@@ -0,0 +1,538 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{state::FilePair, utils::temp_sibling};
+
+/// A single line of the journal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+enum Record {
+    /// `action` is about to replace `target` with a link to `source`, making
+    /// the replacement at `temp` first if it's made at a temporary path.
+    Begin {
+        id: u64,
+        action: String,
+        source: PathBuf,
+        target: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        temp: Option<PathBuf>,
+    },
+    /// The operation started by the [Record::Begin] with the same `id` is
+    /// over.
+    End { id: u64, outcome: Outcome },
+    /// The first `offset` bytes of `left` & `right`, whose identities are
+    /// `pair`, were found equal by a comparison that's still going.
+    Compared {
+        left: PathBuf,
+        right: PathBuf,
+        pair: FilePair,
+        offset: u64,
+    },
+    /// The comparison of the files whose identities are `pair` finished, so
+    /// the [Record::Compared]s before it no longer matter.
+    CompareDone { pair: FilePair },
+}
+
+/// How an operation in the journal ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// The operation succeeded.
+    Done,
+    /// The operation failed, leaving the target as it was.
+    Failed,
+    /// The operation was interrupted after replacing the target, and was
+    /// finished by `hldup recover`.
+    Completed,
+    /// The operation was interrupted before replacing the target, and was
+    /// undone by `hldup recover`.
+    RolledBack,
+    /// The operation was interrupted, and `hldup recover` can't tell whether
+    /// it replaced the target, so it was left as found.
+    Unknown,
+}
+
+/// An append-only record of every change made to the scanned files, so that
+/// the operations interrupted by a crash can be found & finished by
+/// `hldup recover`.
+///
+/// Each record is written as a line holding the seahash of its JSON in hex,
+/// followed by a space & the JSON itself, and is synced to disk before the
+/// operation goes ahead. A line whose checksum doesn't match, such as one
+/// torn by a crash partway through writing it, is skipped when reading.
+///
+/// A journal without a file records nothing, so callers can use it
+/// unconditionally.
+///
+/// Large comparisons also record how far they've got, so that one cut short
+/// can be resumed by the next run, as long as neither file has changed. Their
+/// records are dropped once they finish, when the journal is next opened.
+#[derive(Debug, Default)]
+pub struct Journal {
+    file: Option<File>,
+    next_id: u64,
+    /// The furthest offset each unfinished comparison has recorded, whether by
+    /// this run or an earlier one.
+    compared: HashMap<FilePair, u64>,
+}
+
+impl Journal {
+    /// Constructs a [Journal] which records nothing.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Opens the journal at `path` for appending, creating it if needed.
+    ///
+    /// This fails if an earlier run left operations incomplete, since they
+    /// need to be recovered before anything else touches their files.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let records = match read_records(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let incomplete = incomplete(&records).len();
+        if incomplete > 0 {
+            return Err(io::Error::other(format!(
+                "{incomplete} operation(s) were left incomplete by an earlier run; run \
+                 `hldup recover --journal {}` first",
+                path.display()
+            )));
+        }
+        let next_id = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Begin { id, .. } | Record::End { id, .. } => Some(id + 1),
+                Record::Compared { .. } | Record::CompareDone { .. } => None,
+            })
+            .max()
+            .unwrap_or_default();
+        let mut compared: HashMap<FilePair, (u64, &Record)> = HashMap::new();
+        for record in &records {
+            match record {
+                Record::Compared { pair, offset, .. } => {
+                    let furthest = compared.entry(*pair).or_insert((*offset, record));
+                    if *offset >= furthest.0 {
+                        *furthest = (*offset, record);
+                    }
+                }
+                Record::CompareDone { pair } => {
+                    compared.remove(pair);
+                }
+                Record::Begin { .. } | Record::End { .. } => {}
+            }
+        }
+        // Only the furthest progress of each unfinished comparison is kept, so
+        // the records of big comparisons don't pile up run after run
+        let progress_records = records
+            .iter()
+            .filter(|record| matches!(record, Record::Compared { .. } | Record::CompareDone { .. }))
+            .count();
+        if progress_records > compared.len() {
+            let mut kept = records
+                .iter()
+                .filter(|record| matches!(record, Record::Begin { .. } | Record::End { .. }))
+                .collect::<Vec<_>>();
+            kept.extend(compared.values().map(|(_, record)| *record));
+            rewrite(path, &kept)?;
+        }
+        Ok(Self {
+            file: Some(open_append(path)?),
+            next_id,
+            compared: compared
+                .into_iter()
+                .map(|(pair, (offset, _))| (pair, offset))
+                .collect(),
+        })
+    }
+
+    /// Records that `action` is about to replace `target` with a link to
+    /// `source`, via the temporary path `temp` if it uses one.
+    ///
+    /// Returns the operation's ID to pass to [Journal::end], or `None` if the
+    /// journal is disabled.
+    pub fn begin(
+        &mut self,
+        action: &str,
+        source: &Path,
+        target: &Path,
+        temp: Option<&Path>,
+    ) -> io::Result<Option<u64>> {
+        if self.file.is_none() {
+            return Ok(None);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.append(&Record::Begin {
+            id,
+            action: action.to_owned(),
+            source: source.to_owned(),
+            target: target.to_owned(),
+            temp: temp.map(Path::to_owned),
+        })?;
+        Ok(Some(id))
+    }
+
+    /// Records that the operation `id` returned by [Journal::begin] is over.
+    pub fn end(&mut self, id: Option<u64>, outcome: Outcome) -> io::Result<()> {
+        match id {
+            Some(id) => self.append(&Record::End { id, outcome }),
+            None => Ok(()),
+        }
+    }
+
+    /// The length of the prefix of the files with the identities `pair` that an
+    /// earlier run's comparison verified equal, or 0 if none got anywhere.
+    pub fn compared_prefix(&self, pair: FilePair) -> u64 {
+        self.compared.get(&pair).copied().unwrap_or_default()
+    }
+
+    /// Records that the first `offset` bytes of `left` & `right`, whose
+    /// identities are `pair`, are equal.
+    pub fn compared(
+        &mut self,
+        left: &Path,
+        right: &Path,
+        pair: FilePair,
+        offset: u64,
+    ) -> io::Result<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+        self.compared.insert(pair, offset);
+        self.append(&Record::Compared {
+            left: left.to_owned(),
+            right: right.to_owned(),
+            pair,
+            offset,
+        })
+    }
+
+    /// Records that the comparison of the files with the identities `pair`
+    /// finished, if it recorded any progress, so that its records can be
+    /// dropped.
+    pub fn compare_done(&mut self, pair: FilePair) -> io::Result<()> {
+        if self.compared.remove(&pair).is_none() {
+            return Ok(());
+        }
+        self.append(&Record::CompareDone { pair })
+    }
+
+    /// Appends `record` to the journal & syncs it to disk.
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        file.write_all(record_line(record)?.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+/// Formats `record` as a line of the journal, prefixed by its checksum.
+fn record_line(record: &Record) -> io::Result<String> {
+    let json = serde_json::to_string(record)?;
+    Ok(format!("{:016x} {json}\n", seahash::hash(json.as_bytes())))
+}
+
+/// Replaces the journal at `path` with just `records`, swapping the new one
+/// into place once it's safely on disk.
+fn rewrite(path: &Path, records: &[&Record]) -> io::Result<()> {
+    let temp = temp_sibling(path);
+    let mut file = File::create(&temp)?;
+    for record in records {
+        file.write_all(record_line(record)?.as_bytes())?;
+    }
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+/// Finishes or undoes every operation left incomplete in the journal at
+/// `path`, returning how many there were.
+///
+/// Replacements are made by swapping a temporary file into place, so an
+/// interrupted operation either left the target untouched with the new link
+/// still at its temporary path, or swapped it in with the old file left at
+/// the temporary path; either way the temporary path is removed, and the
+/// operation is recorded as rolled back or completed accordingly.
+pub fn recover(path: &Path) -> io::Result<usize> {
+    let records = read_records(path)?;
+    let incomplete = incomplete(&records);
+    let mut journal = Journal {
+        file: Some(open_append(path)?),
+        ..Journal::default()
+    };
+    for record in &incomplete {
+        let Record::Begin {
+            id,
+            action,
+            source,
+            target,
+            temp,
+        } = record
+        else {
+            continue;
+        };
+        if let Some(temp) = temp {
+            match fs::remove_file(temp) {
+                Ok(()) => info!("Removed the leftover temporary file {}.", temp.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let outcome = match is_replaced(action, source, target) {
+            Some(true) => Outcome::Completed,
+            Some(false) => Outcome::RolledBack,
+            None => Outcome::Unknown,
+        };
+        info!(
+            "Recovered operation {id} ({action} {} to {}): {}.",
+            target.display(),
+            source.display(),
+            match outcome {
+                Outcome::Completed => "completed",
+                Outcome::Unknown => "unknown whether it finished; left as found",
+                _ => "rolled back",
+            }
+        );
+        journal.append(&Record::End { id: *id, outcome })?;
+    }
+    Ok(incomplete.len())
+}
+
+/// Whether `target` was already replaced by `action`, or `None` if that can't
+/// be told from the files.
+fn is_replaced(action: &str, source: &Path, target: &Path) -> Option<bool> {
+    let target_meta = fs::symlink_metadata(target);
+    match action {
+        "link" => Some(match (fs::symlink_metadata(source), target_meta) {
+            (Ok(source), Ok(target)) => {
+                (source.dev(), source.ino()) == (target.dev(), target.ino())
+            }
+            _ => false,
+        }),
+        "symlink" => Some(target_meta.is_ok_and(|meta| meta.is_symlink())),
+        "delete" => Some(target_meta.is_err_and(|e| e.kind() == io::ErrorKind::NotFound)),
+        // A clone is made by a single ioctl, so the target is never left
+        // half-written, but it looks the same whether or not it ran; custom
+        // actions can't be told apart either
+        _ => None,
+    }
+}
+
+/// The [Record::Begin]s in `records` without a matching [Record::End].
+fn incomplete(records: &[Record]) -> Vec<&Record> {
+    let mut open = BTreeMap::new();
+    for record in records {
+        match record {
+            Record::Begin { id, .. } => {
+                open.insert(*id, record);
+            }
+            Record::End { id, .. } => {
+                open.remove(id);
+            }
+            Record::Compared { .. } | Record::CompareDone { .. } => {}
+        }
+    }
+    open.into_values().collect()
+}
+
+/// Opens the journal at `path` for appending, creating it if needed.
+///
+/// A line torn by a crash is ended first, so that the next record doesn't
+/// get appended to it.
+fn open_append(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    let mut last = [0];
+    if len > 0 {
+        file.read_exact_at(&mut last, len - 1)?;
+        if last[0] != b'\n' {
+            (&file).write_all(b"\n")?;
+        }
+    }
+    Ok(file)
+}
+
+/// Reads every intact record from the journal at `path`.
+fn read_records(path: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let parsed = line
+            .split_once(' ')
+            .filter(|(sum, json)| {
+                u64::from_str_radix(sum, 16).ok() == Some(seahash::hash(json.as_bytes()))
+            })
+            .and_then(|(_, json)| serde_json::from_str(json).ok());
+        match parsed {
+            Some(record) => records.push(record),
+            None => warn!(
+                "Skipping corrupt record on line {} of the journal {}.",
+                idx + 1,
+                path.display()
+            ),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::state::FileIdentity;
+
+    /// A directory unique to this test, emptied first.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hldup-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn recovers_interrupted_operations() {
+        let dir = test_dir("journal-recover");
+        let path = dir.join("journal");
+        let (source, linked, untouched, temp) = (
+            dir.join("source"),
+            dir.join("linked"),
+            dir.join("untouched"),
+            dir.join("temp"),
+        );
+        fs::write(&source, "content").unwrap();
+        fs::hard_link(&source, &linked).unwrap();
+        fs::write(&untouched, "content").unwrap();
+        fs::write(&temp, "content").unwrap();
+
+        let mut journal = Journal::open(&path).unwrap();
+        let done = journal
+            .begin("link", &source, &dir.join("done"), None)
+            .unwrap();
+        journal.end(done, Outcome::Done).unwrap();
+        // Interrupted after swapping in the link, & before swapping it in
+        journal.begin("link", &source, &linked, None).unwrap();
+        journal
+            .begin("link", &source, &untouched, Some(&temp))
+            .unwrap();
+        drop(journal);
+
+        let refused = Journal::open(&path);
+        let recovered = recover(&path);
+        let records = read_records(&path);
+        let reopened = Journal::open(&path);
+        let temp_left = temp.exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(refused.is_err());
+        assert_eq!(recovered.unwrap(), 2);
+        assert!(!temp_left);
+        let outcomes = records
+            .unwrap()
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::End { id, outcome } => Some((id, outcome)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                (0, Outcome::Done),
+                (1, Outcome::Completed),
+                (2, Outcome::RolledBack)
+            ]
+        );
+        assert_eq!(reopened.unwrap().next_id, 3);
+    }
+
+    #[test]
+    fn skips_torn_records() {
+        let dir = test_dir("journal-torn");
+        let path = dir.join("journal");
+        let mut journal = Journal::open(&path).unwrap();
+        journal
+            .begin("delete", Path::new("/a"), &dir.join("missing"), None)
+            .unwrap();
+        drop(journal);
+        // A crash partway through writing the End record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"0123456789abcdef {\"record\":\"end\",\"id\"")
+            .unwrap();
+        drop(file);
+
+        let torn = read_records(&path);
+        let refused = Journal::open(&path);
+        let recovered = recover(&path);
+        let records = read_records(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(torn.unwrap().len(), 1);
+        assert!(refused.is_err());
+        assert_eq!(recovered.unwrap(), 1);
+        assert_eq!(
+            records.unwrap().last(),
+            Some(&Record::End {
+                id: 0,
+                outcome: Outcome::Completed
+            })
+        );
+    }
+
+    #[test]
+    fn compacts_finished_comparisons() {
+        let dir = test_dir("journal-compact");
+        let path = dir.join("journal");
+        let identity = |ino| FileIdentity {
+            dev: 1,
+            ino,
+            size: 10,
+            mtime: 0,
+            mtime_nsec: 0,
+        };
+        let finished = FilePair::new(identity(1), identity(2));
+        let unfinished = FilePair::new(identity(3), identity(4));
+        let (left, right) = (Path::new("/left"), Path::new("/right"));
+
+        let mut journal = Journal::open(&path).unwrap();
+        for offset in [GIB, 2 * GIB] {
+            journal.compared(left, right, finished, offset).unwrap();
+            journal.compared(left, right, unfinished, offset).unwrap();
+        }
+        journal.compare_done(finished).unwrap();
+        drop(journal);
+        let before = read_records(&path).unwrap().len();
+        let journal = Journal::open(&path).unwrap();
+        let after = read_records(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(before, 5);
+        assert_eq!(journal.compared_prefix(finished), 0);
+        assert_eq!(journal.compared_prefix(unfinished), 2 * GIB);
+        assert_eq!(
+            after,
+            [Record::Compared {
+                left: left.to_owned(),
+                right: right.to_owned(),
+                pair: unfinished,
+                offset: 2 * GIB,
+            }]
+        );
+    }
+
+    const GIB: u64 = 1 << 30;
+}
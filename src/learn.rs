@@ -0,0 +1,206 @@
+//! Suggests exclude rules from the duplicates the user kept declining to link.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{confirm, utils::escape_glob};
+
+/// The number of declined duplicates under a single directory, or sharing an
+/// extension, before excluding them is suggested.
+pub const MIN_DECLINED: usize = 3;
+
+/// An exclude rule covering duplicates the user declined to link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExcludeSuggestion {
+    /// The glob to pass to `--exclude`, relative to the scanned roots.
+    pub pattern: String,
+    /// What the declined duplicates have in common, for the prompt.
+    pub reason: String,
+    /// The number of declined duplicates the rule covers.
+    pub declined: usize,
+}
+
+/// Suggests exclude rules for the `declined` duplicates found under `roots`,
+/// first for each directory holding at least [MIN_DECLINED] of them, then for
+/// each extension shared by as many of the rest.
+pub fn suggest_excludes(declined: &[PathBuf], roots: &[PathBuf]) -> Vec<ExcludeSuggestion> {
+    // Scanned paths are canonical, while roots are as given
+    let roots = roots
+        .iter()
+        .map(|root| root.canonicalize().unwrap_or_else(|_| root.clone()))
+        .collect::<Vec<_>>();
+    let relative = declined
+        .iter()
+        .filter_map(|path| {
+            // Nested roots are matched against the deepest one holding the path
+            let root = roots
+                .iter()
+                .filter(|root| path.starts_with(root))
+                .max_by_key(|root| root.components().count())?;
+            Some((path.as_path(), path.strip_prefix(root).ok()?))
+        })
+        .collect::<Vec<_>>();
+
+    let mut by_dir: BTreeMap<&Path, Vec<&Path>> = BTreeMap::new();
+    for (path, rel) in &relative {
+        match rel.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => by_dir.entry(dir).or_default().push(path),
+            // Excluding a whole root is better done by not scanning it
+            _ => {}
+        }
+    }
+    let mut suggestions = Vec::new();
+    let mut covered = HashSet::new();
+    for (dir, paths) in by_dir {
+        if paths.len() < MIN_DECLINED {
+            continue;
+        }
+        let Some(dir_str) = dir.to_str() else {
+            continue;
+        };
+        covered.extend(paths.iter().copied());
+        suggestions.push(ExcludeSuggestion {
+            pattern: format!("{}/**", escape_glob(dir_str)),
+            reason: format!("under {}", dir.display()),
+            declined: paths.len(),
+        });
+    }
+
+    let mut by_ext: BTreeMap<&str, usize> = BTreeMap::new();
+    for (path, _) in &relative {
+        if covered.contains(path) {
+            continue;
+        }
+        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+            *by_ext.entry(ext).or_default() += 1;
+        }
+    }
+    for (ext, count) in by_ext {
+        if count < MIN_DECLINED {
+            continue;
+        }
+        suggestions.push(ExcludeSuggestion {
+            pattern: format!("**/*.{}", escape_glob(ext)),
+            reason: format!("ending in .{ext}"),
+            declined: count,
+        });
+    }
+    suggestions
+}
+
+/// Offers each of `suggestions` to the user, returning the patterns of those
+/// they accept.
+pub fn choose_excludes(suggestions: &[ExcludeSuggestion]) -> Vec<String> {
+    info!(
+        "You declined to link some duplicates with something in common; \
+         excluding them would skip them in future runs."
+    );
+    suggestions
+        .iter()
+        .filter(|suggestion| {
+            confirm(&format!(
+                "You said no to {} duplicate(s) {}. Exclude {} from now on?",
+                suggestion.declined, suggestion.reason, suggestion.pattern
+            ))
+        })
+        .map(|suggestion| suggestion.pattern.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggest(declined: &[&str], roots: &[&str]) -> Vec<(String, usize)> {
+        let declined = declined.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let roots = roots.iter().map(PathBuf::from).collect::<Vec<_>>();
+        suggest_excludes(&declined, &roots)
+            .into_iter()
+            .map(|suggestion| (suggestion.pattern, suggestion.declined))
+            .collect()
+    }
+
+    #[test]
+    fn suggests_directories() {
+        let suggestions = suggest(
+            &[
+                "/hldup-missing/root/cache/a",
+                "/hldup-missing/root/cache/b",
+                "/hldup-missing/root/cache/c",
+                "/hldup-missing/root/other/d",
+            ],
+            &["/hldup-missing/root"],
+        );
+        assert_eq!(suggestions, [("cache/**".to_owned(), 3)]);
+    }
+
+    #[test]
+    fn suggests_extensions() {
+        let suggestions = suggest(
+            &[
+                "/hldup-missing/root/a/x.o",
+                "/hldup-missing/root/b/y.o",
+                "/hldup-missing/root/c/z.o",
+                "/hldup-missing/root/d/w.txt",
+            ],
+            &["/hldup-missing/root"],
+        );
+        assert_eq!(suggestions, [("**/*.o".to_owned(), 3)]);
+    }
+
+    #[test]
+    fn needs_enough_declined() {
+        let suggestions = suggest(
+            &[
+                "/hldup-missing/root/cache/a.o",
+                "/hldup-missing/root/cache/b.o",
+            ],
+            &["/hldup-missing/root"],
+        );
+        assert!(suggestions.is_empty());
+    }
+
+    #[test]
+    fn skips_files_in_roots() {
+        let suggestions = suggest(
+            &[
+                "/hldup-missing/root/a",
+                "/hldup-missing/root/b",
+                "/hldup-missing/root/c",
+                "/hldup-missing/elsewhere/d/e",
+            ],
+            &["/hldup-missing/root"],
+        );
+        assert!(suggestions.is_empty());
+    }
+
+    #[test]
+    fn uses_the_deepest_root() {
+        let suggestions = suggest(
+            &[
+                "/hldup-missing/root/inner/cache/a",
+                "/hldup-missing/root/inner/cache/b",
+                "/hldup-missing/root/inner/cache/c",
+            ],
+            &["/hldup-missing/root", "/hldup-missing/root/inner"],
+        );
+        assert_eq!(suggestions, [("cache/**".to_owned(), 3)]);
+    }
+
+    #[test]
+    fn escapes_globs() {
+        let suggestions = suggest(
+            &[
+                "/hldup-missing/root/[old]*/a",
+                "/hldup-missing/root/[old]*/b",
+                "/hldup-missing/root/[old]*/c",
+            ],
+            &["/hldup-missing/root"],
+        );
+        assert_eq!(suggestions, [(r"\[old\]\*/**".to_owned(), 3)]);
+    }
+}
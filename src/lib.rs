@@ -0,0 +1,109 @@
+//! The library behind the `hlddup` binary, for programs that want to find &
+//! link duplicate files themselves.
+//!
+//! A run is made of 2 phases sharing a [RunContext]: [build_hash_cache] walks
+//! & hashes a directory tree into a [HashCache](hashcache::HashCache), and
+//! [dedup_files](dedup::dedup_files) compares the possible duplicates it found
+//! and links the identical ones according to its
+//! [DedupOptions](dedup::DedupOptions). The checks deciding whether 2 files
+//! may be linked live in [dupchecks], and embedding programs can add their own
+//! through the hooks in [filters].
+//!
+//! [Deduper] runs both phases in 1 call, for programs which don't need to do
+//! anything in between. Either way, an [Observer](events::Observer) added to
+//! the [EventSink](events::EventSink) is told about each file hashed, group
+//! found, pair compared & link made as it happens.
+
+pub mod audit;
+pub mod checkpoint;
+pub mod dedup;
+pub mod deduper;
+pub mod dupchecks;
+pub mod events;
+pub mod filters;
+pub mod hashcache;
+pub mod journal;
+pub mod prompt;
+pub mod scan;
+pub mod state;
+pub mod summary;
+
+mod status;
+
+// Used by the `hlddup` binary, but not part of the library's API
+#[doc(hidden)]
+pub mod calibrate;
+#[doc(hidden)]
+pub mod cgroup;
+#[doc(hidden)]
+pub mod discover;
+#[doc(hidden)]
+pub mod filelist;
+#[doc(hidden)]
+pub mod fsinfo;
+#[doc(hidden)]
+pub mod index;
+#[doc(hidden)]
+pub mod learn;
+#[doc(hidden)]
+pub mod output;
+#[doc(hidden)]
+pub mod plugin;
+#[doc(hidden)]
+pub mod report;
+#[doc(hidden)]
+pub mod scanfile;
+#[doc(hidden)]
+pub mod serve;
+#[doc(hidden)]
+pub mod utils;
+#[doc(hidden)]
+pub mod verify;
+
+pub use deduper::{DedupOutcome, Deduper, DeduperBuilder};
+pub(crate) use prompt::{confirm, prompt_answer};
+pub use prompt::{PromptAnswer, PromptUserMode};
+pub use scan::{build_hash_cache, hash_listed_files, ScanOptions};
+
+use checkpoint::WalkCheckpoint;
+use dedup::StopReason;
+use events::{Event, EventSink};
+use journal::Journal;
+use state::PersistentState;
+use summary::RunSummary;
+
+/// The mutable state threaded through the scan & dedup phases of a run.
+pub struct RunContext {
+    /// Information carried over between runs.
+    pub state: PersistentState,
+    /// Statistics reported to the user at the end of the run.
+    pub summary: RunSummary,
+    /// The stream lifecycle events are emitted to.
+    pub events: EventSink,
+    /// The record of changes made to files, for recovering from crashes.
+    pub journal: Journal,
+    /// The record of how far the walk has got, for resuming it.
+    pub checkpoint: WalkCheckpoint,
+}
+
+impl RunContext {
+    /// Reports the end of the run to the user & the event stream.
+    pub fn finish(&mut self, stopped_early: Option<&StopReason>) {
+        self.summary.log();
+        let (freed_inodes, freed_bytes) = self
+            .summary
+            .filesystems
+            .values()
+            .fold((0, 0), |(inodes, bytes), fs| {
+                (inodes + fs.freed_inodes, bytes + fs.freed_bytes)
+            });
+        self.events.emit(Event::Summary {
+            damaged_files: self.summary.damaged.len(),
+            permission_denied: self.summary.permission_denied.len(),
+            freed_inodes,
+            freed_bytes,
+            stopped_early: stopped_early.map(ToString::to_string),
+        });
+        self.events.flush();
+    }
+}
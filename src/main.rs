@@ -1,26 +1,87 @@
-use std::{collections::HashSet, io::stdin, path::PathBuf, process::ExitCode};
-
-use dupchecks::{is_same_file, should_link};
-use hashcache::{FileHashes, HashCache};
-use log::{debug, error, info, trace};
-use utils::*;
-use walkdir::WalkDir;
-mod dupchecks;
-mod hashcache;
-mod utils;
-
-fn init_logger() {
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use args::{print_usage, print_version, AppArgs, Command};
+use config::Profile;
+use hlddup::{
+    audit::diff_plans,
+    calibrate::tune_sampling,
+    checkpoint::WalkCheckpoint,
+    dedup::{dedup_files, LinkAction},
+    discover::print_discovery,
+    events::EventSink,
+    hash_listed_files,
+    hashcache::SampleParams,
+    index::ContentIndex,
+    journal::{recover, Journal},
+    learn::{choose_excludes, suggest_excludes},
+    output::{write_output, OutputKind},
+    report::{print_quick_estimate, print_reports},
+    scan::{find_files, find_listed_files, hash_roots},
+    scanfile::{merge_scans, ScanFile},
+    serve::serve,
+    state::PersistentState,
+    summary::RunSummary,
+    utils::ReadOptions,
+    verify::verify_hashing,
+    PromptUserMode, RunContext,
+};
+use log::{error, info, trace, LevelFilter};
+mod args;
+mod config;
+
+/// Sets up logging from `HLDUP_LOG`, adjusted by the `verbosity` from the
+/// `-q` & `-v` flags: negative to only log errors & the summary, 0 to leave
+/// `HLDUP_LOG` as is, and higher for each `-v`.
+fn init_logger(verbosity: i8) {
     let env = env_logger::Env::new()
         .filter_or("HLDUP_LOG", "INFO")
         .write_style_or("HLDUP_COLOR", "auto");
     let mut logger = env_logger::Builder::from_env(env);
+    match verbosity {
+        ..0 => {
+            logger
+                .filter_level(LevelFilter::Error)
+                .filter_module("hlddup::summary", LevelFilter::Info);
+        }
+        0 => {}
+        1 => {
+            logger.filter_module("hlddup", LevelFilter::Debug);
+        }
+        2 => {
+            logger.filter_module("hlddup", LevelFilter::Trace);
+        }
+        _ => {
+            logger.filter_level(LevelFilter::Trace);
+        }
+    }
     logger.init();
 }
 
-fn main() -> ExitCode {
-    init_logger();
+/// Prints the timing of every `tracing` span to stderr as it closes, if
+/// `HLDUP_TRACE` is set.
+#[cfg(feature = "tracing")]
+fn init_tracing() {
+    use tracing_subscriber::fmt::format::FmtSpan;
 
-    let args = match AppArgs::parse(&std::env::args().skip(1).collect::<Vec<_>>()) {
+    if std::env::var_os("HLDUP_TRACE").is_some_and(|value| !value.is_empty()) {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_max_level(tracing::Level::DEBUG)
+            .init();
+    }
+}
+
+fn main() -> ExitCode {
+    let args = AppArgs::parse(&std::env::args().skip(1).collect::<Vec<_>>());
+    init_logger(args.as_ref().map_or(0, |args| args.verbosity));
+    #[cfg(feature = "tracing")]
+    init_tracing();
+    let args = match args {
         Ok(v) => v,
         Err(msg) => {
             error!("{msg}");
@@ -29,204 +90,376 @@ fn main() -> ExitCode {
     };
     trace!("Running with args: {args:?}");
 
-    let cache = args
-        .dirs
-        .into_iter()
-        .map(build_hash_cache)
-        .collect::<HashCache>();
-    dedup_files(&cache, args.prompt_mode);
-
-    ExitCode::SUCCESS
-}
-
-#[derive(Debug)]
-pub struct AppArgs {
-    pub prompt_mode: PromptUserMode,
-    pub dirs: Vec<PathBuf>,
-}
-
-impl AppArgs {
-    pub fn parse(raw: &[impl AsRef<str>]) -> Result<Self, String> {
-        let mut dirs = Vec::new();
-        let mut prompt_mode = PromptUserMode::default();
-        for arg in raw {
-            let arg = arg.as_ref();
-            match arg {
-                "--prompt" => {
-                    prompt_mode = PromptUserMode::Prompt;
-                }
-                "--default-yes" => {
-                    prompt_mode = PromptUserMode::DefaultYes;
-                }
-                "--default-no" => {
-                    prompt_mode = PromptUserMode::DefaultNo;
-                }
-                other => {
-                    dirs.push(PathBuf::from(other));
-                }
+    match &args.command {
+        Command::Help => {
+            print_usage();
+            return ExitCode::SUCCESS;
+        }
+        Command::Version => {
+            print_version();
+            return ExitCode::SUCCESS;
+        }
+        _ => {}
+    }
+    if let Some(cgroup) = &args.cgroup {
+        // The limits are a guarantee, so we don't run without them
+        if let Err(e) = cgroup.join() {
+            error!("Error joining cgroup {}: {:?}", cgroup.path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let (Command::Recover, Some(path)) = (&args.command, &args.journal_path) {
+        match recover(path) {
+            Ok(count) => info!("Recovered {count} incomplete operation(s)."),
+            Err(e) => {
+                error!("Error recovering from journal {}: {:?}", path.display(), e);
+                return ExitCode::FAILURE;
             }
         }
-        if dirs.is_empty() {
-            let curdir =
-                std::env::current_dir().map_err(|e| format!("Error getting cwd: {e:?}"))?;
-            dirs.push(curdir);
+        return ExitCode::SUCCESS;
+    }
+    if args.command == Command::Discover {
+        if let Err(e) = print_discovery() {
+            error!("Error listing mounts: {:?}", e);
+            return ExitCode::FAILURE;
         }
-        Ok(Self { dirs, prompt_mode })
+        return ExitCode::SUCCESS;
     }
-}
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
-pub enum PromptUserMode {
-    DefaultYes,
-    DefaultNo,
-    #[default]
-    Prompt,
-}
-
-impl PromptUserMode {
-    pub const fn as_default(self) -> Option<bool> {
-        match self {
-            PromptUserMode::DefaultNo => Some(false),
-            PromptUserMode::DefaultYes => Some(true),
-            PromptUserMode::Prompt => None,
+    if let Command::PlanDiff { old, new } = &args.command {
+        if let Err(e) = diff_plans(old, new) {
+            error!("Error comparing plans: {:?}", e);
+            return ExitCode::FAILURE;
         }
+        return ExitCode::SUCCESS;
     }
-}
-
-fn prompt_bool(msg: &str) -> bool {
-    println!("{msg} [y/N]");
-    let nextln = stdin().lines().next().unwrap().unwrap();
-    const YES_RESPONSES: &[&str] = &["y", "Y", "yes", "Yes", "YES"];
-    YES_RESPONSES.contains(&nextln.as_str())
-}
+    if let Command::MergeScans { inputs, output } = &args.command {
+        if let Err(e) = merge_scans(inputs, output) {
+            error!("Error merging scans: {:?}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+    let mut read_opts = args.read_opts;
+    read_opts.sampling = choose_sampling(&args, read_opts);
 
-pub fn build_hash_cache(root: PathBuf) -> HashCache {
-    debug!("Building hashcache for root dir {root:?}");
+    // Dry runs change nothing, so they have nothing to record
+    let mut journal = match &args.journal_path {
+        Some(path) if !args.dedup_opts.dry_run => match Journal::open(path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error opening journal {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => Journal::disabled(),
+    };
+    if let Command::Serve { socket } = &args.command {
+        if let Err(e) = serve(socket, &args.dedup_opts, read_opts, &mut journal) {
+            error!("Error serving on {}: {:?}", socket.display(), e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
 
-    let mut retvl = HashCache::new();
-    for ent in WalkDir::new(root) {
-        let ent = match ent {
+    let mut state = match &args.state_path {
+        Some(path) => match PersistentState::load(path) {
             Ok(v) => v,
             Err(e) => {
-                error!("Found error walking directory tree: {e:?}");
-                continue;
+                error!("Error loading state from {}: {:?}", path.display(), e);
+                return ExitCode::FAILURE;
             }
-        };
-        if ent.file_type().is_dir() {
-            trace!("Found directory {:?}; skipping.", ent.path());
-            continue;
-        }
-        let path = if ent.path().is_absolute() {
-            ent.path().to_owned()
-        } else {
-            match ent.path().canonicalize() {
-                Ok(p) => p,
+        },
+        None => PersistentState::default(),
+    };
+    state.use_sampling(read_opts.sampling);
+    let mut events = EventSink::disabled();
+    for output in args.outputs_of(OutputKind::Events) {
+        if let Err(e) = events.add_stream(output) {
+            error!("Error opening event stream {}: {:?}", output.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Some(path) = &args.status_fifo {
+        if let Err(e) = events.add_status_fifo(path) {
+            error!("Error opening status FIFO {}: {:?}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+    let checkpoint = match &args.walk_checkpoint {
+        Some(path) => match WalkCheckpoint::open(path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error opening walk checkpoint {}: {:?}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => WalkCheckpoint::disabled(),
+    };
+    let mut ctx = RunContext {
+        state,
+        summary: RunSummary {
+            roots: args.dirs.clone(),
+            ..RunSummary::new()
+        },
+        events,
+        journal,
+        checkpoint,
+    };
+
+    if args.quick_estimate {
+        let found = match &args.file_list {
+            Some(list) => match list.read() {
+                Ok(paths) => vec![find_listed_files(paths, &args.scan_opts, &mut ctx)],
                 Err(e) => {
                     error!(
-                        "Error finding absolute path for {}: {:?}.",
-                        ent.path().display(),
+                        "Error reading the file list {}: {:?}",
+                        list.source.display(),
                         e
                     );
-                    continue;
+                    return ExitCode::FAILURE;
                 }
+            },
+            None => {
+                let mut found = args
+                    .dirs
+                    .iter()
+                    .map(|root| find_files(root, &args.scan_opts, &mut ctx))
+                    .collect::<Vec<_>>();
+                found.push(find_listed_files(
+                    args.files.clone(),
+                    &args.scan_opts,
+                    &mut ctx,
+                ));
+                found
             }
         };
-        debug!("Calculating hash for file {path:?}");
-        let hash = match FileHashes::from_path(&path) {
-            Ok(v) => v,
+        ctx.checkpoint.remove();
+        print_quick_estimate(
+            found.into_iter().flat_map(HashMap::into_values).flatten(),
+            args.dedup_opts.link_tiny,
+        );
+        ctx.finish(None);
+        return ExitCode::SUCCESS;
+    }
+
+    let mut cache = match &args.file_list {
+        Some(list) => match list.read() {
+            Ok(paths) => hash_listed_files(paths, &args.scan_opts, read_opts, &mut ctx),
             Err(e) => {
-                error!("Error getting file hash for {}: {:?}", path.display(), e);
-                continue;
+                error!(
+                    "Error reading the file list {}: {:?}",
+                    list.source.display(),
+                    e
+                );
+                return ExitCode::FAILURE;
             }
-        };
-        retvl.insert(path, hash);
+        },
+        None => hash_roots(
+            &args.dirs,
+            &args.files,
+            &args.scan_opts,
+            read_opts,
+            &mut ctx,
+        ),
+    };
+    // Save once before deduplicating so the hashing work isn't lost if we're
+    // interrupted, and again afterwards to record what we linked
+    save_state(args.state_path.as_deref(), &ctx.state);
+    // The walk is only resumed until it's been hashed, so the next run sees any
+    // new files
+    ctx.checkpoint.remove();
+    if let Some(path) = &args.save_scan {
+        if let Err(e) = ScanFile::from_cache(&cache, read_opts.sampling).save(path) {
+            error!("Error saving scan to {}: {:?}", path.display(), e);
+        }
     }
 
-    retvl
-}
+    if let Command::Report(report_opts) = &args.command {
+        print_reports(report_opts, &cache, &args.dirs, read_opts, &mut ctx.summary);
+        ctx.finish(None);
+        return ExitCode::SUCCESS;
+    }
+    if let Command::VerifyHashing { sample } = &args.command {
+        verify_hashing(&cache, *sample, read_opts, &mut ctx.summary);
+        ctx.finish(None);
+        return ExitCode::SUCCESS;
+    }
 
-pub fn dedup_files(cache: &HashCache, prompt_mode: PromptUserMode) {
-    let dups = cache.duplicates();
-    info!("Found {} possible dupes.", dups.len());
-    for flist in cache.duplicates() {
-
-        // We need to check all possible pairs of files we can generate out of
-        // the flist because of the possibility that, if we have 3 files with
-        // the same hash, only 2 of them are identical; or in a 4 file case, we
-        // actually have 2 pairs of identical files, etc. 
-        //
-        // To do this we do some basic pre-processing of the file list,
-        // generating it and then removing all `(a, a)` pairs and also any `(b,
-        // a)` pairs where we already had the `(a, b)` equivalent.   
-        let pairs = flist
-            .iter()
-            .flat_map(|left| flist.iter().map(move |right| (left, right)))
-            .filter(|(left, right)| left != right)
-            .map(|(left, right)| {
-                if left < right {
-                    (left, right)
-                } else {
-                    (right, left)
-                }
-            })
-            .collect::<HashSet<_>>();
-
-        
-        for (left, right) in pairs {
-            match is_same_file(left, right) {
-                Ok(false) => {
-                    //TODO: Log
-                    continue;
-                }
-                Ok(true) => {}
-                Err(e) => {
-                    error!(
-                        "Error comparing files {} and {}: {:?}",
-                        left.display(),
-                        right.display(),
-                        e
-                    );
-                    continue;
-                }
+    let mut index = match &args.index_path {
+        Some(path) => match ContentIndex::load(path) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                error!("Error loading index from {}: {:?}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    if let Some(index) = &mut index {
+        index.add_matches(&mut cache, &mut ctx.state, read_opts);
+    }
+    for path in &args.append_scans {
+        let scan = match ScanFile::load(path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error loading scan from {}: {:?}", path.display(), e);
+                return ExitCode::FAILURE;
             }
-            info!(
-                "Found candidates {} and {}.",
-                left.display(),
-                right.display()
+        };
+        if scan.sampling != read_opts.sampling {
+            error!(
+                "{} was hashed with different sampling ({}) than this run ({}), so they can't \
+                 be compared",
+                path.display(),
+                scan.sampling,
+                read_opts.sampling
             );
-            match should_link(left, right, prompt_mode) {
-                Err(e) => {
-                    error!(
-                        "IO Error checking candidacy of {} and {}: {:?}",
-                        left.display(),
-                        right.display(),
-                        e
-                    );
-                    continue;
-                }
-                Ok(Err(reason)) => {
-                    error!(
-                        "Not linking {} and {}. Reason: {}",
-                        left.display(),
-                        right.display(),
-                        reason.msg()
-                    );
-                    continue;
-                }
-                Ok(Ok(())) => {}
+            return ExitCode::FAILURE;
+        }
+        let added = scan.append_to(&mut cache);
+        info!(
+            "Found {added} file(s) in {} which may match the scanned files.",
+            path.display()
+        );
+    }
+
+    if args.outputs_of(OutputKind::Report).next().is_some()
+        || args.outputs_of(OutputKind::Csv).next().is_some()
+    {
+        ctx.events.start_report();
+    }
+    let dedup_res = dedup_files(&cache, &args.dedup_opts, read_opts, &mut ctx);
+    if let Err(reason) = &dedup_res {
+        error!("Stopped deduplicating early: {reason}");
+    }
+    if let (Some(index), Some(path)) = (&mut index, &args.index_path) {
+        index.update(&cache, read_opts);
+        if let Err(e) = index.save(path) {
+            error!("Error saving index to {}: {:?}", path.display(), e);
+        }
+    }
+    // The directories we relinked are recorded as they are now, so the next
+    // run doesn't mistake our links for changes
+    ctx.state.refresh_touched();
+    save_state(args.state_path.as_deref(), &ctx.state);
+    ctx.finish(dedup_res.as_ref().err());
+    if let Some(mut report) = ctx.events.take_report() {
+        report.finish(
+            &ctx.summary,
+            dedup_res.as_ref().err().map(ToString::to_string),
+        );
+        for path in args.outputs_of(OutputKind::Report) {
+            if let Err(e) = report.save(path) {
+                error!("Error writing report to {}: {:?}", path.display(), e);
             }
-            match hard_link(left, right) {
-                Ok(()) => {
-                    info!("Linked files {} and {}.", left.display(), right.display());
-                }
-                Err(e) => {
-                    error!(
-                        "Failed linking files {} and {}: {:?}.",
-                        left.display(),
-                        right.display(),
-                        e
-                    );
-                }
+        }
+        for path in args.outputs_of(OutputKind::Csv) {
+            if let Err(e) = report.save_csv(path) {
+                error!("Error writing CSV report to {}: {:?}", path.display(), e);
             }
         }
     }
+    // Runs that only check for duplicates end with a line scripts can parse,
+    // whatever the log level
+    let check_mode = if args.dedup_opts.dry_run {
+        Some("dry-run")
+    } else if args.dedup_opts.prompt_mode == PromptUserMode::DefaultNo {
+        Some("default-no")
+    } else if args.dedup_opts.action == LinkAction::Report {
+        Some("report")
+    } else {
+        None
+    };
+    let machine_summary = ctx
+        .summary
+        .machine_summary(check_mode.unwrap_or("dedup"), dedup_res.is_err());
+    if check_mode.is_some() {
+        println!("{machine_summary}");
+    }
+    for path in args.outputs_of(OutputKind::Summary) {
+        if let Err(e) = write_output(path, &machine_summary) {
+            error!("Error writing summary to {}: {:?}", path.display(), e);
+        }
+    }
+
+    if let Some(path) = &args.damaged_log {
+        if let Err(e) = ctx.summary.write_damaged_log(path) {
+            error!(
+                "Error writing damaged file log to {}: {:?}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    if args.learn_excludes {
+        learn_excludes(&args, &ctx.summary.declined);
+    }
+
+    if dedup_res.is_err() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn save_state(path: Option<&Path>, state: &PersistentState) {
+    let Some(path) = path else {
+        return;
+    };
+    if let Err(e) = state.save(path) {
+        error!("Error saving state to {}: {:?}", path.display(), e);
+    }
+}
+
+/// Offers to exclude the directories & extensions of the `declined`
+/// duplicates, adding the rules accepted to the profile in use.
+fn learn_excludes(args: &AppArgs, declined: &[PathBuf]) {
+    let suggestions = suggest_excludes(declined, &args.dirs);
+    if suggestions.is_empty() {
+        return;
+    }
+    let patterns = choose_excludes(&suggestions);
+    if patterns.is_empty() {
+        return;
+    }
+    let lines = patterns
+        .iter()
+        .map(|pattern| format!("--exclude {pattern}"))
+        .collect::<Vec<_>>();
+    match &args.profile {
+        Some((path, name)) => match Profile::append(path, name, &lines) {
+            Ok(()) => info!(
+                "Added {} exclude rule(s) to profile {name:?} in {}.",
+                lines.len(),
+                path.display()
+            ),
+            Err(e) => error!("{e}"),
+        },
+        None => info!(
+            "No --profile was used to add the rules to; pass {} to apply them.",
+            lines.join(" ")
+        ),
+    }
+}
+
+/// Chooses the [SampleParams] for the run: the defaults, or the result of a
+/// calibration pass with `--tune-sampling`, with any explicitly passed values
+/// taking precedence.
+fn choose_sampling(args: &AppArgs, read_opts: ReadOptions) -> SampleParams {
+    let base = if args.tune_sampling {
+        tune_sampling(&args.dirs, read_opts)
+    } else {
+        SampleParams::default()
+    };
+    let sampling = SampleParams {
+        sample_size: args.sample_size.unwrap_or(base.sample_size),
+        max_samples: args.max_samples.unwrap_or(base.max_samples),
+        algo: args.hash_algo,
+        resample_zeros: !args.no_zero_resampling,
+    };
+    if sampling != SampleParams::default() {
+        info!("Hashing files using {sampling}.");
+    }
+    sampling
 }
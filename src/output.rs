@@ -0,0 +1,81 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// A kind of output which can be written alongside the log on the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputKind {
+    /// The JSON Lines event stream, as written by `--events`.
+    Events,
+    /// The JSON audit report, as written by `--report`.
+    Report,
+    /// The actions from the audit report as CSV, 1 row per action.
+    Csv,
+    /// The single `key=value` summary line printed by dry runs.
+    Summary,
+}
+
+impl OutputKind {
+    /// Parses an [OutputKind] from its command line name.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "events" => Ok(Self::Events),
+            "report" => Ok(Self::Report),
+            "csv" => Ok(Self::Csv),
+            "summary" => Ok(Self::Summary),
+            other => Err(format!(
+                "Unknown output kind {other:?}; expected events, report, csv, or summary"
+            )),
+        }
+    }
+}
+
+/// An output requested with `--output <kind>:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutputSpec {
+    pub kind: OutputKind,
+    /// The file written to, or `-` for stdout.
+    pub path: PathBuf,
+}
+
+impl OutputSpec {
+    /// Parses an [OutputSpec] from its `<kind>:<path>` form.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let (kind, path) = raw
+            .split_once(':')
+            .filter(|(_, path)| !path.is_empty())
+            .ok_or_else(|| format!("Invalid output {raw:?}; expected <kind>:<path>"))?;
+        Ok(Self {
+            kind: OutputKind::parse(kind)?,
+            path: PathBuf::from(path),
+        })
+    }
+
+    /// Whether the output is written to stdout.
+    pub fn is_stdout(&self) -> bool {
+        is_stdout(&self.path)
+    }
+}
+
+/// Whether `path` names stdout rather than a file.
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Writes `line` to the file at `path`, or stdout if `path` is `-`.
+pub fn write_output(path: &Path, line: &str) -> io::Result<()> {
+    let mut out = open_output(path)?;
+    writeln!(out, "{line}")?;
+    out.flush()
+}
+
+/// Opens the file at `path` for writing, or stdout if `path` is `-`.
+pub fn open_output(path: &Path) -> io::Result<Box<dyn Write>> {
+    if is_stdout(path) {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(path)?)))
+    }
+}
@@ -0,0 +1,191 @@
+use std::path::Path;
+
+#[cfg(feature = "wasm-plugins")]
+use std::{
+    fs::{self, Metadata},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "wasm-plugins")]
+use log::error;
+#[cfg(feature = "wasm-plugins")]
+use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+#[cfg(feature = "wasm-plugins")]
+use crate::filters::{EntryFilter, PairFilter};
+use crate::{dedup::DedupOptions, ScanOptions};
+
+/// Loads the plugin at `path`, adding its filters to `scan_opts` &
+/// `dedup_opts`.
+#[cfg(feature = "wasm-plugins")]
+pub fn load_plugin(
+    path: &Path,
+    scan_opts: &mut ScanOptions,
+    dedup_opts: &mut DedupOptions,
+) -> Result<(), String> {
+    let plugin = WasmPlugin::load(path)
+        .map_err(|e| format!("Error loading plugin {}: {e}", path.display()))?;
+    let (keeps_entries, allows_pairs) = {
+        let instance = plugin.instance.lock().unwrap_or_else(|e| e.into_inner());
+        (instance.keep_entry.is_some(), instance.allow_pair.is_some())
+    };
+    let plugin = Arc::new(plugin);
+    if keeps_entries {
+        scan_opts.entry_filters.0.push(plugin.clone());
+    }
+    if allows_pairs {
+        dedup_opts.pair_filters.0.push(plugin);
+    }
+    Ok(())
+}
+
+/// Fails, since plugins need the `wasm-plugins` feature.
+#[cfg(not(feature = "wasm-plugins"))]
+pub fn load_plugin(
+    path: &Path,
+    _scan_opts: &mut ScanOptions,
+    _dedup_opts: &mut DedupOptions,
+) -> Result<(), String> {
+    Err(format!(
+        "Can't load plugin {}: hldup was built without the wasm-plugins feature",
+        path.display()
+    ))
+}
+
+/// A filter loaded from a WebAssembly module with `--plugin`, so that
+/// site-specific policies can be added without recompiling.
+///
+/// The module imports nothing and exports its `memory`, an
+/// `alloc(len: i32) -> i32` function returning the address of `len` free
+/// bytes, and at least 1 of:
+///
+/// * `keep_entry(path: i32, path_len: i32, size: i64, mtime: i64, is_dir: i32)
+///   -> i32`, called for every walked entry like an [EntryFilter], with the
+///   modification time in seconds since the Unix epoch.
+/// * `allow_pair(keeper: i32, keeper_len: i32, duplicate: i32,
+///   duplicate_len: i32) -> i32`, called for every pair of identical files like
+///   a [PairFilter].
+///
+/// Paths are passed as the address & length of their bytes, copied into memory
+/// returned by `alloc` just before each call. A nonzero result accepts the
+/// entry or pair; a trap rejects it.
+#[cfg(feature = "wasm-plugins")]
+struct WasmPlugin {
+    path: PathBuf,
+    /// The module's state, which every call mutates.
+    instance: Mutex<PluginInstance>,
+}
+
+/// The signature of the `keep_entry` export: the path's address & length, the
+/// size, the modification time, and whether it's a directory.
+#[cfg(feature = "wasm-plugins")]
+type KeepEntryFn = TypedFunc<(i32, i32, i64, i64, i32), i32>;
+
+/// An instantiated plugin module & its exports.
+#[cfg(feature = "wasm-plugins")]
+struct PluginInstance {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    keep_entry: Option<KeepEntryFn>,
+    allow_pair: Option<TypedFunc<(i32, i32, i32, i32), i32>>,
+}
+
+#[cfg(feature = "wasm-plugins")]
+impl WasmPlugin {
+    /// Compiles & instantiates the module at `path`.
+    fn load(path: &Path) -> Result<Self, String> {
+        let wasm = fs::read(path).map_err(|e| e.to_string())?;
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm).map_err(|e| e.to_string())?;
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or("the module doesn't export its memory")?;
+        let alloc = instance
+            .get_typed_func(&store, "alloc")
+            .map_err(|e| format!("the module doesn't export alloc(i32) -> i32: {e}"))?;
+        let keep_entry = instance.get_typed_func(&store, "keep_entry").ok();
+        let allow_pair = instance.get_typed_func(&store, "allow_pair").ok();
+        if keep_entry.is_none() && allow_pair.is_none() {
+            return Err("the module exports neither keep_entry nor allow_pair".to_owned());
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            instance: Mutex::new(PluginInstance {
+                store,
+                memory,
+                alloc,
+                keep_entry,
+                allow_pair,
+            }),
+        })
+    }
+
+    /// Runs `call` on the instance, logging any error & treating it as a
+    /// rejection.
+    fn call(&self, call: impl FnOnce(&mut PluginInstance) -> Result<i32, wasmi::Error>) -> bool {
+        let mut instance = self.instance.lock().unwrap_or_else(|e| e.into_inner());
+        match call(&mut instance) {
+            Ok(res) => res != 0,
+            Err(e) => {
+                error!("Plugin {} failed: {e}", self.path.display());
+                false
+            }
+        }
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+impl PluginInstance {
+    /// Copies `path` into the module's memory, returning its address & length.
+    fn pass_path(&mut self, path: &Path) -> Result<(i32, i32), wasmi::Error> {
+        let bytes = path.as_os_str().as_encoded_bytes();
+        let len = i32::try_from(bytes.len()).map_err(|_| wasmi::Error::new("path too long"))?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, bytes)
+            .map_err(|e| wasmi::Error::new(e.to_string()))?;
+        Ok((ptr, len))
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+impl EntryFilter for WasmPlugin {
+    fn keep(&self, path: &Path, meta: &Metadata) -> bool {
+        self.call(|instance| {
+            let Some(keep_entry) = instance.keep_entry else {
+                return Ok(1);
+            };
+            let (ptr, len) = instance.pass_path(path)?;
+            let size = meta.len() as i64;
+            keep_entry.call(
+                &mut instance.store,
+                (ptr, len, size, meta.mtime(), i32::from(meta.is_dir())),
+            )
+        })
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+impl PairFilter for WasmPlugin {
+    fn allow(&self, keeper: &Path, duplicate: &Path) -> bool {
+        self.call(|instance| {
+            let Some(allow_pair) = instance.allow_pair else {
+                return Ok(1);
+            };
+            let (keeper_ptr, keeper_len) = instance.pass_path(keeper)?;
+            let (dup_ptr, dup_len) = instance.pass_path(duplicate)?;
+            allow_pair.call(
+                &mut instance.store,
+                (keeper_ptr, keeper_len, dup_ptr, dup_len),
+            )
+        })
+    }
+}
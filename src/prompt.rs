@@ -0,0 +1,95 @@
+use std::io::stdin;
+
+/// How the user is asked whether to link each set of duplicates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum PromptUserMode {
+    /// Link every duplicate without asking.
+    DefaultYes,
+    /// Link nothing, only reporting the duplicates.
+    DefaultNo,
+    /// Ask before linking each duplicate.
+    #[default]
+    Prompt,
+}
+
+impl PromptUserMode {
+    /// The answer given without asking, or `None` if the user is asked.
+    pub const fn as_default(self) -> Option<bool> {
+        match self {
+            PromptUserMode::DefaultNo => Some(false),
+            PromptUserMode::DefaultYes => Some(true),
+            PromptUserMode::Prompt => None,
+        }
+    }
+}
+
+/// An answer to a prompt about some duplicates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PromptAnswer {
+    /// Link these duplicates.
+    Yes,
+    /// Leave these duplicates alone.
+    No,
+    /// Link these & every remaining duplicate without asking again.
+    All,
+    /// Leave these & every remaining duplicate alone without asking again.
+    None,
+    /// Link these & the rest of their groups without asking again.
+    Group,
+    /// Leave these & the rest of their groups alone without asking again.
+    SkipGroup,
+    /// Leave these & every remaining duplicate alone, and stop deduplicating.
+    Quit,
+}
+
+impl PromptAnswer {
+    /// Parses a [PromptAnswer] from a line typed at a prompt, where an empty
+    /// line means no.
+    fn parse(raw: &str) -> Option<Self> {
+        match raw.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Self::Yes),
+            "" | "n" | "no" => Some(Self::No),
+            "a" | "all" => Some(Self::All),
+            "d" | "none" => Some(Self::None),
+            "g" | "group" => Some(Self::Group),
+            "s" | "skip" => Some(Self::SkipGroup),
+            "q" | "quit" => Some(Self::Quit),
+            _ => None,
+        }
+    }
+}
+
+const PROMPT_HELP: &str = "\
+y - yes to these duplicates
+n - no to these duplicates
+a - yes to these & every remaining duplicate
+d - no to these & every remaining duplicate
+g - yes to these & the rest of their group
+s - no to these & the rest of their group
+q - no to these & every remaining duplicate, and stop";
+
+/// Asks the user the yes/no question `msg`, where anything but yes, including
+/// running out of input, means no.
+pub(crate) fn confirm(msg: &str) -> bool {
+    println!("{msg} [y/N]");
+    match stdin().lines().next() {
+        Some(Ok(line)) => PromptAnswer::parse(&line) == Some(PromptAnswer::Yes),
+        _ => false,
+    }
+}
+
+/// Asks the user `msg`, repeating it until they give a valid answer.
+///
+/// Running out of input is treated as [PromptAnswer::Quit].
+pub(crate) fn prompt_answer(msg: &str) -> PromptAnswer {
+    println!("{msg} [y/N/a/d/g/s/q/?]");
+    loop {
+        let Some(Ok(nextln)) = stdin().lines().next() else {
+            return PromptAnswer::Quit;
+        };
+        match PromptAnswer::parse(&nextln) {
+            Some(answer) => return answer,
+            None => println!("{PROMPT_HELP}\n[y/N/a/d/g/s/q/?]"),
+        }
+    }
+}
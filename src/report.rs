@@ -0,0 +1,471 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, Metadata},
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+
+use crate::{
+    dupchecks::{is_same_file, LinkGroup},
+    fsinfo::BlockSizes,
+    hashcache::{GroupId, HashCache},
+    summary::RunSummary,
+    utils::{copy_marker, format_age, format_size, ReadOptions, DAY},
+};
+
+/// Which reports the `report` command should print.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportOptions {
+    /// Print du-style per-directory usage, both as-is and after a hypothetical
+    /// deduplication.
+    pub du: bool,
+    /// Print the number of duplicates & reclaimable space for each file
+    /// extension.
+    pub extensions: bool,
+    /// Print the modification time spread within each set of duplicates, and
+    /// how old duplicates are overall.
+    pub ages: bool,
+    /// Print the directory names & copy markers that appear most often in the
+    /// paths of duplicates.
+    pub components: bool,
+}
+
+impl ReportOptions {
+    /// Whether any report was requested.
+    pub fn any(&self) -> bool {
+        self.du || self.extensions || self.ages || self.components
+    }
+}
+
+/// A scanned file, as a single [LinkGroup] of all of its scanned links.
+#[derive(Debug, Clone)]
+struct ScannedFile {
+    /// The file's links.
+    group: LinkGroup,
+    /// The ID of the group of possible duplicates the file belongs to.
+    id: GroupId,
+    /// The file's size in bytes.
+    size: u64,
+    /// The file's modification time, in seconds since the Unix epoch, if it
+    /// could be read.
+    mtime: Option<i64>,
+    /// The index of the scanned file this is a confirmed duplicate of, and
+    /// would therefore be freed in favour of by deduplicating.
+    copy_of: Option<usize>,
+}
+
+impl ScannedFile {
+    /// Whether the file would be freed by deduplicating.
+    fn duplicate(&self) -> bool {
+        self.copy_of.is_some()
+    }
+}
+
+/// Prints every report requested in `opts` to stdout.
+///
+/// Duplicates are confirmed byte-for-byte before being counted as
+/// reclaimable, but nothing is modified.
+pub fn print_reports(
+    opts: &ReportOptions,
+    cache: &HashCache,
+    roots: &[PathBuf],
+    read_opts: ReadOptions,
+    summary: &mut RunSummary,
+) {
+    let files = scanned_files(cache, read_opts, summary);
+    let roots = roots
+        .iter()
+        .map(|root| root.canonicalize().unwrap_or_else(|_| root.clone()))
+        .collect::<Vec<_>>();
+    if opts.du {
+        print_du_report(&files, &roots);
+    }
+    if opts.extensions {
+        print_extension_report(&files);
+    }
+    if opts.ages {
+        print_age_report(&files);
+    }
+    if opts.components {
+        print_component_report(&files, &roots);
+    }
+}
+
+/// Prints a rough estimate of the duplication among `files` to stdout, from
+/// their sizes & names alone, without reading them.
+///
+/// Files on the same device sharing a size & name are assumed to be copies of
+/// each other. That misses renamed copies and counts same-named files which
+/// differ, so the estimate is only good for deciding whether a full run is
+/// worthwhile.
+///
+/// Files smaller than a filesystem block aren't counted unless `link_tiny` is
+/// set, as with deduplication.
+pub fn print_quick_estimate(files: impl IntoIterator<Item = (PathBuf, Metadata)>, link_tiny: bool) {
+    let mut by_name: HashMap<(u64, u64, OsString), HashSet<u64>> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut total = 0;
+    let mut block_sizes = BlockSizes::default();
+    for (path, meta) in files {
+        // Empty files are all alike, and never worth linking
+        if !meta.is_file() || meta.len() == 0 {
+            continue;
+        }
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if seen.insert((meta.dev(), meta.ino())) {
+            total += meta.len();
+        }
+        if !link_tiny && meta.len() < block_sizes.get(meta.dev(), &path) {
+            continue;
+        }
+        by_name
+            .entry((meta.dev(), meta.len(), name.to_owned()))
+            .or_default()
+            .insert(meta.ino());
+    }
+
+    let (mut sets, mut copies, mut reclaimable) = (0, 0, 0);
+    for ((_, size, _), inodes) in &by_name {
+        if inodes.len() < 2 {
+            continue;
+        }
+        let extra = inodes.len() as u64 - 1;
+        sets += 1;
+        copies += extra;
+        reclaimable += size * extra;
+    }
+    let pct = if total == 0 {
+        0.0
+    } else {
+        reclaimable as f64 / total as f64 * 100.0
+    };
+    println!(
+        "Estimated from sizes & names only; nothing was hashed or compared.\n\
+         {} file(s) totalling {}; {sets} set(s) of likely copies.\n\
+         About {} ({pct:.1}%) in {copies} file(s) could be reclaimable.",
+        seen.len(),
+        format_size(total),
+        format_size(reclaimable)
+    );
+}
+
+/// Splits everything in `cache` into [ScannedFile]s, comparing possible
+/// duplicates to decide which would be freed.
+fn scanned_files(
+    cache: &HashCache,
+    read_opts: ReadOptions,
+    summary: &mut RunSummary,
+) -> Vec<ScannedFile> {
+    let mut files: Vec<ScannedFile> = Vec::new();
+    for (hashes, paths) in cache.iter() {
+        let size = hashes.size();
+        let (groups, errors) = LinkGroup::split(paths, cache.records(), false);
+        for (path, e) in errors {
+            error!("Error reading metadata for {}: {:?}", path.display(), e);
+        }
+        // The first of each set of identical groups is counted as the copy
+        // which would be kept; the rest would be freed
+        let mut keepers: Vec<usize> = Vec::new();
+        for group in groups {
+            let right = group.representative();
+            let copy_of = keepers.iter().copied().find(|&keeper| {
+                let left = files[keeper].group.representative();
+                match is_same_file(left, right, read_opts) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        summary.record_read_error(&e);
+                        error!(
+                            "Error comparing files {} and {}: {:?}",
+                            left.display(),
+                            right.display(),
+                            e
+                        );
+                        false
+                    }
+                }
+            });
+            if copy_of.is_none() {
+                keepers.push(files.len());
+            }
+            let mtime = match cache.records().get(right) {
+                Some(record) => Some(record.mtime),
+                None => fs::metadata(right).map(|meta| meta.mtime()).ok(),
+            };
+            files.push(ScannedFile {
+                group,
+                id: hashes.group_id(),
+                size,
+                mtime,
+                copy_of,
+            });
+        }
+    }
+    files
+}
+
+/// The disk usage of a single directory tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct DirUsage {
+    /// The space currently used, counting each inode once.
+    apparent: u64,
+    /// The space that would be used if every duplicate was linked.
+    deduped: u64,
+}
+
+impl DirUsage {
+    fn savings(&self) -> u64 {
+        self.apparent - self.deduped
+    }
+}
+
+/// Prints the per-directory usage of every directory under `roots` to stdout,
+/// sorted so that the directories that would shrink the most come first.
+///
+/// Like `du`, files that are already hard-linked together are only counted
+/// once.
+fn print_du_report(files: &[ScannedFile], roots: &[PathBuf]) {
+    let mut usage: HashMap<PathBuf, DirUsage> = HashMap::new();
+    for file in files {
+        let deduped = if file.duplicate() { 0 } else { file.size };
+        for dir in file.group.representative().ancestors().skip(1) {
+            if !roots.iter().any(|root| dir.starts_with(root)) {
+                break;
+            }
+            let entry = usage.entry(dir.to_owned()).or_default();
+            entry.apparent += file.size;
+            entry.deduped += deduped;
+        }
+    }
+
+    let mut usage = usage.into_iter().collect::<Vec<_>>();
+    usage.sort_by(|(a_path, a), (b_path, b)| {
+        b.savings()
+            .cmp(&a.savings())
+            .then_with(|| a_path.cmp(b_path))
+    });
+    println!("{:>12}\t{:>12}\tPATH", "APPARENT", "DEDUPED");
+    for (path, dir_usage) in usage {
+        println!(
+            "{:>12}\t{:>12}\t{}",
+            format_size(dir_usage.apparent),
+            format_size(dir_usage.deduped),
+            path.display()
+        );
+    }
+}
+
+/// The duplicates found with a single file extension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ExtensionUsage {
+    /// The number of duplicate files.
+    duplicates: u64,
+    /// The space those duplicates would free.
+    reclaimable: u64,
+}
+
+/// Prints the number of duplicates & reclaimable space for each file extension
+/// to stdout, along with its share of the total reclaimable space, sorted so
+/// that the extensions with the most reclaimable space come first.
+fn print_extension_report(files: &[ScannedFile]) {
+    let mut usage: HashMap<String, ExtensionUsage> = HashMap::new();
+    for file in files.iter().filter(|file| file.duplicate()) {
+        let ext = file.group.representative().extension().map_or_else(
+            || "(none)".to_owned(),
+            |ext| ext.to_string_lossy().to_lowercase(),
+        );
+        let entry = usage.entry(ext).or_default();
+        entry.duplicates += 1;
+        entry.reclaimable += file.size;
+    }
+    let total = usage.values().map(|ext| ext.reclaimable).sum::<u64>();
+
+    let mut usage = usage.into_iter().collect::<Vec<_>>();
+    usage.sort_by(|(a_ext, a), (b_ext, b)| {
+        b.reclaimable
+            .cmp(&a.reclaimable)
+            .then_with(|| a_ext.cmp(b_ext))
+    });
+    println!(
+        "{:>12}\t{:>12}\t{:>6}\tEXTENSION",
+        "DUPLICATES", "RECLAIMABLE", "SHARE"
+    );
+    for (ext, ext_usage) in usage {
+        let share = if total == 0 {
+            0.0
+        } else {
+            100.0 * ext_usage.reclaimable as f64 / total as f64
+        };
+        println!(
+            "{:>12}\t{:>12}\t{:>5.1}%\t{}",
+            ext_usage.duplicates,
+            format_size(ext_usage.reclaimable),
+            share,
+            ext
+        );
+    }
+}
+
+/// The upper bounds of the age brackets duplicates are counted in by the age
+/// report, along with their labels.
+const AGE_BRACKETS: &[(u64, &str)] = &[
+    (DAY, "< 1 day"),
+    (7 * DAY, "< 1 week"),
+    (30 * DAY, "< 30 days"),
+    (365 * DAY, "< 1 year"),
+    (u64::MAX, ">= 1 year"),
+];
+
+/// Prints the modification time spread within each set of confirmed
+/// duplicates to stdout, sorted so that the widest spreads come first,
+/// followed by how many duplicates fall into each age bracket.
+///
+/// A duplicate's age is that of the copy which would be freed, as of now.
+fn print_age_report(files: &[ScannedFile]) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64);
+    let age = |mtime: i64| now.saturating_sub(mtime).max(0) as u64;
+
+    // The mtimes of each set of identical files, keyed by the index of the
+    // file that would be kept
+    let mut sets: BTreeMap<usize, Vec<i64>> = BTreeMap::new();
+    let mut brackets = vec![(0u64, 0u64); AGE_BRACKETS.len()];
+    let mut ages = Vec::new();
+    for file in files {
+        let (Some(keeper), Some(mtime)) = (file.copy_of, file.mtime) else {
+            continue;
+        };
+        let set = sets
+            .entry(keeper)
+            .or_insert_with(|| files[keeper].mtime.into_iter().collect());
+        set.push(mtime);
+
+        let file_age = age(mtime);
+        ages.push(file_age);
+        let bracket = AGE_BRACKETS
+            .iter()
+            .position(|(max, _)| file_age < *max)
+            .unwrap_or(AGE_BRACKETS.len() - 1);
+        brackets[bracket].0 += 1;
+        brackets[bracket].1 += file.size;
+    }
+
+    let mut sets = sets
+        .into_iter()
+        .map(|(keeper, mtimes)| {
+            let oldest = mtimes.iter().copied().min().unwrap_or(0);
+            let newest = mtimes.iter().copied().max().unwrap_or(0);
+            (&files[keeper], mtimes.len(), oldest, newest)
+        })
+        .collect::<Vec<_>>();
+    sets.sort_by(|(a, _, a_oldest, a_newest), (b, _, b_oldest, b_newest)| {
+        (b_newest - b_oldest)
+            .cmp(&(a_newest - a_oldest))
+            .then_with(|| b.size.cmp(&a.size))
+    });
+    println!(
+        "{:>16}\t{:>6}\t{:>12}\t{:>8}\t{:>8}\t{:>8}\tPATH",
+        "GROUP", "COPIES", "SIZE", "OLDEST", "NEWEST", "SPREAD"
+    );
+    for (keeper, copies, oldest, newest) in sets {
+        println!(
+            "{:>16}\t{:>6}\t{:>12}\t{:>8}\t{:>8}\t{:>8}\t{}",
+            keeper.id,
+            copies,
+            format_size(keeper.size),
+            format_age(age(oldest)),
+            format_age(age(newest)),
+            format_age((newest - oldest) as u64),
+            keeper.group.representative().display()
+        );
+    }
+
+    println!();
+    if !ages.is_empty() {
+        ages.sort_unstable();
+        println!(
+            "Duplicate ages: median {}, 90th percentile {}.",
+            format_age(ages[ages.len() / 2]),
+            format_age(ages[ages.len() * 9 / 10])
+        );
+    }
+    println!("{:>12}\t{:>12}\tAGE", "DUPLICATES", "RECLAIMABLE");
+    for ((_, label), (count, bytes)) in AGE_BRACKETS.iter().zip(brackets) {
+        println!("{:>12}\t{:>12}\t{}", count, format_size(bytes), label);
+    }
+}
+
+/// The maximum number of path components listed by the component report.
+const MAX_LISTED_COMPONENTS: usize = 50;
+
+/// The duplicates whose paths contain a single path component.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ComponentUsage {
+    /// The number of duplicate paths containing the component.
+    paths: u64,
+    /// The space those duplicates would free.
+    reclaimable: u64,
+}
+
+/// Prints the directory names, along with the copy markers in file names (such
+/// as `Copy of` or `(1)`), that appear most often in the paths of duplicates
+/// to stdout, as candidates for exclude rules or cleanup.
+///
+/// Only the parts of paths under the scanned `roots` are counted, and each
+/// component is counted at most once per path.
+fn print_component_report(files: &[ScannedFile], roots: &[PathBuf]) {
+    let mut usage: HashMap<String, ComponentUsage> = HashMap::new();
+    for file in files.iter().filter(|file| file.duplicate()) {
+        for path in &file.group.paths {
+            let relative = roots
+                .iter()
+                .find_map(|root| path.strip_prefix(root).ok())
+                .unwrap_or(path);
+            let mut components = relative
+                .parent()
+                .into_iter()
+                .flat_map(Path::components)
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(format!("{}/", name.to_string_lossy())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if let Some(marker) = relative.file_name().and_then(copy_marker) {
+                components.push(marker.to_owned());
+            }
+            components.sort();
+            components.dedup();
+            for component in components {
+                let entry = usage.entry(component).or_default();
+                entry.paths += 1;
+                entry.reclaimable += file.size;
+            }
+        }
+    }
+
+    let mut usage = usage.into_iter().collect::<Vec<_>>();
+    usage.sort_by(|(a_name, a), (b_name, b)| {
+        b.paths
+            .cmp(&a.paths)
+            .then_with(|| b.reclaimable.cmp(&a.reclaimable))
+            .then_with(|| a_name.cmp(b_name))
+    });
+    println!("{:>12}\t{:>12}\tCOMPONENT", "PATHS", "RECLAIMABLE");
+    for (name, component_usage) in usage.iter().take(MAX_LISTED_COMPONENTS) {
+        println!(
+            "{:>12}\t{:>12}\t{}",
+            component_usage.paths,
+            format_size(component_usage.reclaimable),
+            name
+        );
+    }
+    if usage.len() > MAX_LISTED_COMPONENTS {
+        println!("...and {} more.", usage.len() - MAX_LISTED_COMPONENTS);
+    }
+}
@@ -0,0 +1,847 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs::{self, Metadata},
+    hash::Hasher,
+    io, mem,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info, trace, warn};
+use seahash::SeaHasher;
+use walkdir::WalkDir;
+
+use crate::{
+    events::Event,
+    filters::{is_hidden, CustomFilters, EntryFilter, PathPatterns},
+    fsinfo::is_snapshot,
+    hashcache::{CustomHasher, FileHashes, FileRecord, FileRecordBuilder, HashCache},
+    state::{directory_digest, DirStamp, DirectoryState, InodeState},
+    summary::RunSummary,
+    utils::*,
+    RunContext,
+};
+
+/// Options controlling how the directory tree is walked when building a
+/// [HashCache].
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Whether to silence the individual log messages for paths we don't have
+    /// permission to read; they are still counted in the [RunSummary].
+    pub ignore_permission_errors: bool,
+    /// Extensions whose files are hashed first.
+    pub priority: PriorityExtensions,
+    /// Whether to scan ZFS snapshots & read-only btrfs subvolumes instead of
+    /// skipping them.
+    pub include_snapshots: bool,
+    /// Paths to skip, relative to the root being scanned.
+    pub exclude: PathPatterns,
+    /// If not empty, the only files to scan, relative to the root being
+    /// scanned.
+    pub include: PathPatterns,
+    /// The size in bytes of the smallest file to scan.
+    pub min_size: u64,
+    /// The size in bytes of the largest file to scan, if limited.
+    pub max_size: Option<u64>,
+    /// Only files last modified before this time are scanned, if set.
+    pub modified_before: Option<SystemTime>,
+    /// Only files last modified after this time are scanned, if set.
+    pub modified_after: Option<SystemTime>,
+    /// How many levels below the root to scan, if limited; files directly in
+    /// the root are at depth 1.
+    pub max_depth: Option<usize>,
+    /// Whether to descend into symlinked directories.
+    pub follow_symlinks: bool,
+    /// Whether to stay on the filesystem of the root being scanned, skipping
+    /// mount points below it.
+    pub one_file_system: bool,
+    /// Whether to skip files & directories whose names start with a `.`.
+    pub skip_hidden: bool,
+    /// Whether to skip walking directory trees which the state shows haven't
+    /// had entries added, removed, or renamed since they were recorded,
+    /// reusing their recorded files instead.
+    pub skip_unchanged: bool,
+    /// Filters supplied by an embedding program, which every scanned entry
+    /// must pass.
+    pub entry_filters: CustomFilters<dyn EntryFilter>,
+    /// The hasher supplied by an embedding program to use in place of
+    /// sampling, if any.
+    pub hasher: Option<CustomHasher>,
+}
+
+impl ScanOptions {
+    /// Digests `root` along with the options deciding which of its entries
+    /// are walked, so that a directory recorded by one walk is only skipped by
+    /// another that would find the same files in it; `None` if this walk can't
+    /// skip directories, or be skipped by later walks.
+    fn walk_digest(&self, root: &Path, ctx: &RunContext) -> Option<u64> {
+        // Filters from an embedding program, & the links followed into other
+        // trees, can change what's found without any directory changing
+        if !self.skip_unchanged
+            || ctx.checkpoint.is_enabled()
+            || self.follow_symlinks
+            || !self.entry_filters.is_empty()
+            || self.hasher.is_some()
+        {
+            return None;
+        }
+        let root = root.canonicalize().ok()?;
+        let mut hasher = SeaHasher::new();
+        hasher.write(root.as_os_str().as_bytes());
+        for patterns in [&self.exclude, &self.include] {
+            hasher.write_usize(patterns.patterns().len());
+            for pattern in patterns.patterns() {
+                hasher.write_usize(pattern.len());
+                hasher.write(pattern.as_bytes());
+            }
+        }
+        hasher.write_u64(self.min_size);
+        hasher.write_u64(self.max_size.unwrap_or(u64::MAX));
+        for time in [self.modified_before, self.modified_after] {
+            let nanos = time.and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+            hasher.write_u128(nanos.map_or(u128::MAX, |nanos| nanos.as_nanos()));
+        }
+        hasher.write_usize(self.max_depth.unwrap_or(usize::MAX));
+        hasher.write_u8(self.one_file_system as u8);
+        hasher.write_u8(self.skip_hidden as u8);
+        hasher.write_u8(self.include_snapshots as u8);
+        // 0 marks directories that can't be skipped
+        Some(hasher.finish().max(1))
+    }
+
+    /// Checks if a file of `size` bytes is within the range we scan.
+    fn size_in_range(&self, size: u64) -> bool {
+        size >= self.min_size && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    /// Checks if the file at `path` was last modified within the range we
+    /// scan, going by what symlinks point to like its size.
+    fn modified_in_range(&self, path: &Path, meta: &Metadata) -> bool {
+        if self.modified_before.is_none() && self.modified_after.is_none() {
+            return true;
+        }
+        let modified = if meta.is_symlink() {
+            fs::metadata(path).and_then(|target| target.modified())
+        } else {
+            meta.modified()
+        };
+        let Ok(modified) = modified else {
+            return false;
+        };
+        self.modified_before.is_none_or(|before| modified < before)
+            && self.modified_after.is_none_or(|after| modified > after)
+    }
+
+    /// Records that `path` couldn't be scanned due to a permissions error.
+    fn permission_denied(&self, path: &Path, summary: &mut RunSummary) {
+        if self.ignore_permission_errors {
+            debug!("Permission denied reading {}; skipping.", path.display());
+        } else {
+            warn!("Permission denied reading {}; skipping.", path.display());
+        }
+        summary.permission_denied.push(path.to_owned());
+    }
+}
+
+/// Checks if the directory `ent` is a filesystem snapshot, logging any error
+/// and assuming it isn't.
+fn is_snapshot_entry(ent: &walkdir::DirEntry) -> bool {
+    let res = ent
+        .metadata()
+        .map_err(io::Error::from)
+        .and_then(|meta| is_snapshot(ent.path(), &meta));
+    res.unwrap_or_else(|e| {
+        debug!(
+            "Error checking if {} is a snapshot: {:?}",
+            ent.path().display(),
+            e
+        );
+        false
+    })
+}
+
+/// Walks the tree under `root` and hashes every file found.
+///
+/// Directories whose digest matches the one recorded in `state` reuse the
+/// recorded [FileHashes] instead of re-reading their files; `state` is then
+/// updated with the digests & hashes from this walk. Under
+/// [ScanOptions::skip_unchanged], trees the state shows are unchanged aren't
+/// walked at all.
+pub fn build_hash_cache(
+    root: PathBuf,
+    scan_opts: &ScanOptions,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> HashCache {
+    debug!("Building hashcache for root dir {root:?}");
+    ctx.events.emit(Event::ScanStart { root: &root });
+    let mut walk = walk_tree(&root, scan_opts, ctx);
+    let by_directory = mem::take(&mut walk.by_directory);
+    let found = by_directory.keys().cloned().collect::<HashSet<_>>();
+    let mut cache = hash_files(by_directory, scan_opts, read_opts, ctx);
+    if let Some(digest) = walk.digest {
+        let unchanged = walk.unchanged.iter().collect::<HashSet<_>>();
+        for WalkedDir { path: dir, .. } in &walk.dirs {
+            // Directories without any files we scan are recorded too, since the
+            // trees below them can still be skipped
+            if !found.contains(dir) && !unchanged.contains(dir) {
+                ctx.state
+                    .directories
+                    .insert(dir.clone(), DirectoryState::empty());
+            }
+        }
+        record_walk(&walk, &unchanged, digest, ctx);
+        reuse_unchanged(&walk.unchanged, &mut cache, ctx);
+    }
+    cache
+}
+
+/// Everything a walk found, including what's needed to skip the unchanged
+/// parts of the tree next time.
+#[derive(Default)]
+struct Walk {
+    /// The files found, grouped by directory.
+    by_directory: HashMap<PathBuf, Vec<(PathBuf, Metadata)>>,
+    /// The digest from [ScanOptions::walk_digest], if the walk can skip
+    /// directories.
+    digest: Option<u64>,
+    /// Every directory walked or skipped as unchanged.
+    dirs: Vec<WalkedDir>,
+    /// The directories whose trees weren't walked since they're unchanged.
+    unchanged: Vec<PathBuf>,
+    /// The directories with entries that couldn't be read.
+    incomplete: HashSet<PathBuf>,
+}
+
+/// A directory reached by a [Walk].
+struct WalkedDir {
+    path: PathBuf,
+    /// The directory it's in, unless it's the root.
+    parent: Option<PathBuf>,
+    /// Its [DirStamp] from before its entries were read, so that anything
+    /// added while they're hashed changes it by the next run.
+    stamp: Option<DirStamp>,
+}
+
+/// Walks & hashes the trees under each of the `roots`, along with the
+/// individually listed `files`, into a single [HashCache].
+pub fn hash_roots(
+    roots: &[PathBuf],
+    files: &[PathBuf],
+    scan_opts: &ScanOptions,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> HashCache {
+    let mut cache = roots
+        .iter()
+        .cloned()
+        .map(|root| build_hash_cache(root, scan_opts, read_opts, ctx))
+        .collect::<HashCache>();
+    if !files.is_empty() {
+        let listed = hash_listed_files(files.to_vec(), scan_opts, read_opts, ctx);
+        cache = cache.join(listed);
+    }
+    cache
+}
+
+/// Walks the tree under `root`, returning every file the [ScanOptions] let
+/// through along with its metadata, grouped by directory.
+pub fn find_files(
+    root: &Path,
+    scan_opts: &ScanOptions,
+    ctx: &mut RunContext,
+) -> HashMap<PathBuf, Vec<(PathBuf, Metadata)>> {
+    let scan_opts = ScanOptions {
+        skip_unchanged: false,
+        ..scan_opts.clone()
+    };
+    walk_tree(root, &scan_opts, ctx).by_directory
+}
+
+/// Walks the tree under `root` like [find_files], skipping the directories
+/// the state shows are unchanged if [ScanOptions::skip_unchanged] is set.
+fn walk_tree(root: &Path, scan_opts: &ScanOptions, ctx: &mut RunContext) -> Walk {
+    phase_span!(INFO, "walk", root = %root.display());
+    let mut by_directory: HashMap<PathBuf, Vec<_>> = HashMap::new();
+    let mut walked = Walk {
+        digest: scan_opts.walk_digest(root, ctx),
+        ..Walk::default()
+    };
+    // The subtree digests already rolled up while checking for unchanged
+    // directories
+    let mut checked = HashMap::new();
+    let resumed = ctx.checkpoint.resume(root).unwrap_or_default();
+    for path in resumed.files {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) => {
+                let dir = path.parent().map(ToOwned::to_owned).unwrap_or_default();
+                by_directory.entry(dir).or_default().push((path, meta));
+            }
+            Err(e) => debug!(
+                "Could not read {} found by an earlier run: {:?}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    if resumed.complete {
+        walked.by_directory = by_directory;
+        return walked;
+    }
+    let mut walker = WalkDir::new(root).follow_links(scan_opts.follow_symlinks);
+    if let Some(depth) = scan_opts.max_depth {
+        walker = walker.max_depth(depth);
+    }
+    // The checkpoint relies on everything before an entry being walked by the
+    // time it's reached
+    if ctx.checkpoint.is_enabled() {
+        walker = walker.sort_by_file_name();
+    }
+    let mut walk = walker.into_iter();
+    // When following symlinks, several links can lead to the same directory,
+    // which must only be scanned once
+    let mut visited_dirs = HashSet::new();
+    // The root's device, when staying on its filesystem
+    let mut root_dev = None;
+    while let Some(ent) = walk.next() {
+        let ent = match ent {
+            Ok(v) => v,
+            Err(e) if is_permission_denied(e.io_error()) => {
+                let path = e.path().unwrap_or(root);
+                scan_opts.permission_denied(path, &mut ctx.summary);
+                walked.mark_incomplete(path);
+                continue;
+            }
+            Err(e) => {
+                walked.mark_incomplete(e.path().unwrap_or(root));
+                match e.loop_ancestor() {
+                    Some(ancestor) => info!(
+                        "Skipping {} since it links back to {}.",
+                        e.path().unwrap_or(root).display(),
+                        ancestor.display()
+                    ),
+                    None => error!("Found error walking directory tree: {e:?}"),
+                }
+                continue;
+            }
+        };
+        // Entries walked by an earlier run are skipped, apart from the
+        // directories leading to where it stopped
+        if let Some(resume_from) = &resumed.resume_from {
+            let on_path = ent.file_type().is_dir() && resume_from.starts_with(ent.path());
+            if ent.path() < resume_from.as_path() && !on_path {
+                if ent.file_type().is_dir() {
+                    walk.skip_current_dir();
+                }
+                continue;
+            }
+        }
+        ctx.checkpoint.reached(root, ent.path());
+        // Hidden directories are never descended into, like excluded ones
+        if scan_opts.skip_hidden && ent.depth() > 0 && is_hidden(ent.path()) {
+            trace!("{:?} is hidden; skipping.", ent.path());
+            if ent.file_type().is_dir() {
+                walk.skip_current_dir();
+            }
+            continue;
+        }
+        // Excluded directories are skipped entirely rather than filtering out
+        // each of their files
+        let relative = ent.path().strip_prefix(root).unwrap_or(ent.path());
+        if ent.depth() > 0 && !scan_opts.exclude.is_empty() {
+            let excluded = if ent.file_type().is_dir() {
+                scan_opts.exclude.matches_dir(relative)
+            } else {
+                scan_opts.exclude.matches_file(relative)
+            };
+            if excluded {
+                trace!("Excluding {:?}.", ent.path());
+                if ent.file_type().is_dir() {
+                    walk.skip_current_dir();
+                }
+                continue;
+            }
+        }
+        if ent.depth() > 0
+            && !scan_opts.entry_filters.is_empty()
+            && ent
+                .metadata()
+                .is_ok_and(|meta| !scan_opts.entry_filters.keep(ent.path(), &meta))
+        {
+            trace!("{:?} was rejected by a custom filter.", ent.path());
+            if ent.file_type().is_dir() {
+                walk.skip_current_dir();
+            }
+            continue;
+        }
+        if !ent.file_type().is_dir()
+            && !scan_opts.include.is_empty()
+            && !scan_opts.include.matches_file(relative)
+        {
+            trace!("{:?} isn't included; skipping.", ent.path());
+            continue;
+        }
+        if ent.file_type().is_dir() {
+            if scan_opts.one_file_system {
+                let dev = ent.metadata().ok().map(|meta| meta.dev());
+                if ent.depth() == 0 {
+                    root_dev = dev;
+                } else if dev.is_some() && dev != root_dev {
+                    info!(
+                        "Skipping {} since it's on a different filesystem.",
+                        ent.path().display()
+                    );
+                    walk.skip_current_dir();
+                    continue;
+                }
+            }
+            if !scan_opts.include_snapshots && is_snapshot_entry(&ent) {
+                // A root named outright is scanned even if it's a snapshot,
+                // since that's clearly what was asked for
+                if ent.depth() == 0 {
+                    info!(
+                        "{} is a snapshot; scanning it since it was named, but its files \
+                         can't be replaced.",
+                        ent.path().display()
+                    );
+                } else {
+                    info!("Skipping snapshot {}.", ent.path().display());
+                    ctx.summary.snapshots.push(ent.path().to_owned());
+                    walk.skip_current_dir();
+                    continue;
+                }
+            }
+            let first_visit = ent
+                .metadata()
+                .map_or(true, |meta| visited_dirs.insert((meta.dev(), meta.ino())));
+            if scan_opts.follow_symlinks && !first_visit {
+                info!(
+                    "Skipping {} since its target was already scanned.",
+                    ent.path().display()
+                );
+                walk.skip_current_dir();
+                continue;
+            }
+            if let Some(digest) = walked.digest {
+                // Keyed like the directories files are found in
+                let dir = if ent.depth() == 0 && root.is_relative() {
+                    root.canonicalize()
+                } else {
+                    absolute_path(ent.path())
+                };
+                let Ok(dir) = dir else {
+                    walked.mark_incomplete(ent.path());
+                    continue;
+                };
+                let parent = (ent.depth() > 0).then(|| dir.parent().map(ToOwned::to_owned));
+                walked.dirs.push(WalkedDir {
+                    path: dir.clone(),
+                    parent: parent.flatten(),
+                    stamp: fs::metadata(&dir)
+                        .ok()
+                        .map(|meta| DirStamp::from_metadata(&meta)),
+                });
+                if ctx.state.unchanged_subtree(&dir, digest, &mut checked) {
+                    debug!(
+                        "{} is unchanged since the last run; skipping it.",
+                        dir.display()
+                    );
+                    walked.unchanged.push(dir);
+                    walk.skip_current_dir();
+                    continue;
+                }
+            }
+            trace!("Found directory {:?}; skipping.", ent.path());
+            continue;
+        }
+        let path = match absolute_path(ent.path()) {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Error finding absolute path for {}: {:?}.",
+                    ent.path().display(),
+                    e
+                );
+                continue;
+            }
+        };
+        // Symlinks to files are still handled as symlinks when following links,
+        // rather than as the files they point to
+        let meta = if ent.path_is_symlink() {
+            fs::symlink_metadata(ent.path())
+        } else {
+            ent.metadata().map_err(io::Error::from)
+        };
+        let meta = match meta {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error reading metadata for {}: {:?}", path.display(), e);
+                walked.mark_incomplete(&path);
+                continue;
+            }
+        };
+        // Followed links can still lead to files on other filesystems
+        if root_dev.is_some_and(|dev| dev != meta.dev()) {
+            trace!("{path:?} is on a different filesystem; skipping.");
+            continue;
+        }
+        // Symlinks are sized by what they point to, since that's what we'd hash
+        let size = if meta.is_symlink() {
+            fs::metadata(&path).map_or(0, |target| target.len())
+        } else {
+            meta.len()
+        };
+        if !scan_opts.size_in_range(size) {
+            trace!("{path:?} is outside the size range; skipping.");
+            continue;
+        }
+        if !scan_opts.modified_in_range(&path, &meta) {
+            trace!("{path:?} is outside the modification time range; skipping.");
+            continue;
+        }
+        ctx.checkpoint.found(&path);
+        let dir = path.parent().map(ToOwned::to_owned).unwrap_or_default();
+        by_directory.entry(dir).or_default().push((path, meta));
+    }
+    ctx.checkpoint.finish(root);
+    walked.by_directory = by_directory;
+    walked
+}
+
+impl Walk {
+    /// Notes that the entry at `path` couldn't be read, so neither it nor the
+    /// directory it's in can be skipped next time.
+    fn mark_incomplete(&mut self, path: &Path) {
+        if self.digest.is_none() {
+            return;
+        }
+        let path = absolute_path(path).unwrap_or_else(|_| path.to_owned());
+        if let Some(parent) = path.parent() {
+            self.incomplete.insert(parent.to_owned());
+        }
+        self.incomplete.insert(path);
+    }
+}
+
+/// Records the directories walked with the options whose digest is `digest`
+/// in the state, so that later walks can skip their trees while they're
+/// unchanged.
+///
+/// This runs once the walked files have been hashed & recorded, since a
+/// directory can only be skipped once every file in it has been.
+fn record_walk(walk: &Walk, unchanged: &HashSet<&PathBuf>, digest: u64, ctx: &mut RunContext) {
+    let mut subdirs: HashMap<&Path, Vec<PathBuf>> = HashMap::new();
+    for dir in &walk.dirs {
+        if let Some(parent) = &dir.parent {
+            subdirs.entry(parent).or_default().push(dir.path.clone());
+        }
+    }
+    for WalkedDir {
+        path: dir, stamp, ..
+    } in &walk.dirs
+    {
+        // The trees skipped as unchanged keep their records as they are
+        if unchanged.contains(dir) {
+            continue;
+        }
+        let Some(state) = ctx.state.directories.get_mut(dir) else {
+            continue;
+        };
+        let mut children = subdirs.remove(dir.as_path()).unwrap_or_default();
+        children.sort();
+        state.subdirs = children;
+        state.walk = digest;
+        state.stamp = *stamp;
+        if walk.incomplete.contains(dir) {
+            state.complete = false;
+        }
+    }
+    ctx.state.roll_up();
+}
+
+/// Adds the recorded files in each of the `unchanged` trees to `cache`, in
+/// place of walking them.
+fn reuse_unchanged(unchanged: &[PathBuf], cache: &mut HashCache, ctx: &mut RunContext) {
+    let mut pending = unchanged.to_vec();
+    while let Some(dir) = pending.pop() {
+        let Some(state) = ctx.state.directories.get(&dir) else {
+            continue;
+        };
+        for ((path, hash), inode) in state.files.iter().zip(&state.inodes) {
+            ctx.events.emit(Event::FileHashed {
+                path,
+                size: hash.size(),
+                group: hash.group_id(),
+            });
+            cache.insert_record(path.clone(), inode.record(*hash));
+        }
+        pending.extend(state.subdirs.iter().cloned());
+    }
+}
+
+/// Builds a [HashCache] of exactly the files in `paths`, such as those read by
+/// `--files0-from`, rather than walking a directory.
+///
+/// Only the size range applies to the listed files; the options controlling
+/// the walk, including the include & exclude patterns, are ignored.
+pub fn hash_listed_files(
+    paths: Vec<PathBuf>,
+    scan_opts: &ScanOptions,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> HashCache {
+    debug!("Building hashcache for {} listed files", paths.len());
+    let by_directory = find_listed_files(paths, scan_opts, ctx);
+    hash_files(by_directory, scan_opts, read_opts, ctx)
+}
+
+/// Looks up the files in `paths` like [find_files], skipping directories.
+pub fn find_listed_files(
+    paths: Vec<PathBuf>,
+    scan_opts: &ScanOptions,
+    ctx: &mut RunContext,
+) -> HashMap<PathBuf, Vec<(PathBuf, Metadata)>> {
+    phase_span!(INFO, "walk", files = paths.len());
+    let mut by_directory: HashMap<PathBuf, Vec<_>> = HashMap::new();
+    for path in paths {
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(v) => v,
+            Err(e) if is_permission_denied(Some(&e)) => {
+                scan_opts.permission_denied(&path, &mut ctx.summary);
+                continue;
+            }
+            Err(e) => {
+                error!("Error reading metadata for {}: {:?}", path.display(), e);
+                continue;
+            }
+        };
+        if meta.is_dir() {
+            debug!("{} is a directory; skipping.", path.display());
+            continue;
+        }
+        if !scan_opts.entry_filters.keep(&path, &meta) {
+            trace!("{path:?} was rejected by a custom filter.");
+            continue;
+        }
+        let path = match absolute_path(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Error finding absolute path for {}: {:?}.",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let size = if meta.is_symlink() {
+            fs::metadata(&path).map_or(0, |target| target.len())
+        } else {
+            meta.len()
+        };
+        if !scan_opts.size_in_range(size) {
+            trace!("{path:?} is outside the size range; skipping.");
+            continue;
+        }
+        if !scan_opts.modified_in_range(&path, &meta) {
+            trace!("{path:?} is outside the modification time range; skipping.");
+            continue;
+        }
+        let dir = path.parent().map(ToOwned::to_owned).unwrap_or_default();
+        by_directory.entry(dir).or_default().push((path, meta));
+    }
+
+    by_directory
+}
+
+/// Finds the absolute path of `path` without resolving it if it's a symlink.
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path.to_owned());
+    }
+    // Only the parent is canonicalized, since canonicalizing a symlink would
+    // resolve it to the file it points to
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", path.display()),
+        )
+    })?;
+    Ok(parent.canonicalize()?.join(name))
+}
+
+/// Hashes the files found by a walk or listing, grouped by their directory,
+/// reusing the hashes of directories the state shows are unchanged.
+fn hash_files(
+    by_directory: HashMap<PathBuf, Vec<(PathBuf, Metadata)>>,
+    scan_opts: &ScanOptions,
+    read_opts: ReadOptions,
+    ctx: &mut RunContext,
+) -> HashCache {
+    phase_span!(INFO, "hash", directories = by_directory.len());
+    ctx.events.emit(Event::PhaseStart {
+        phase: "hash",
+        total: by_directory.values().map(Vec::len).sum(),
+    });
+    // Directories with more high-priority files are hashed first; the rest are
+    // left in whatever order the walk produced
+    let mut by_directory = by_directory.into_iter().collect::<Vec<_>>();
+    if scan_opts.priority != PriorityExtensions::default() {
+        by_directory.sort_by_cached_key(|(_, files)| {
+            let priority_files = files
+                .iter()
+                .filter(|(path, _)| scan_opts.priority.matches(path))
+                .count();
+            Reverse(priority_files)
+        });
+    }
+
+    let mut retvl = HashCache::new();
+    let mut pending = Vec::new();
+    for (dir, files) in by_directory {
+        // Inodes always come from this walk, even for unchanged directories,
+        // since their link counts can change without the directory changing
+        let mut records = HashMap::with_capacity(files.len());
+        let mut inodes = HashMap::with_capacity(files.len());
+        for (path, meta) in &files {
+            let (record, inode) = if meta.is_symlink() {
+                match fs::metadata(path) {
+                    Ok(target) => (
+                        FileRecord::builder().metadata(&target).symlink(true),
+                        InodeState::from_metadata(&target, true),
+                    ),
+                    // Dangling symlinks are left for the dedup phase to report
+                    Err(_) => continue,
+                }
+            } else {
+                (
+                    FileRecord::builder().metadata(meta),
+                    InodeState::from_metadata(meta, false),
+                )
+            };
+            records.insert(path.clone(), record);
+            inodes.insert(path.clone(), inode);
+        }
+
+        let digest = directory_digest(&files);
+        // Directories are recorded even when some of their files weren't
+        // hashed, so any file missing from an unchanged directory's record
+        // still needs hashing
+        // An unchanged directory's record is taken out of the state, & put back
+        // once any new files are added to it
+        let known = match ctx.state.unchanged_directory(&dir, digest) {
+            Some(_) => ctx.state.directories.remove(&dir),
+            None => None,
+        };
+        if let Some(known) = &known {
+            debug!("Directory {dir:?} is unchanged since the last run; reusing its hashes.");
+            for (path, hash) in &known.files {
+                ctx.events.emit(Event::FileHashed {
+                    path,
+                    size: hash.size(),
+                    group: hash.group_id(),
+                });
+                insert_hashed(&mut retvl, &mut records, path.clone(), *hash);
+            }
+        }
+        let known_paths = known
+            .iter()
+            .flat_map(|known| &known.files)
+            .map(|(path, _)| path.as_path())
+            .collect::<HashSet<_>>();
+        let found = files.len();
+        let paths = files
+            .into_iter()
+            .map(|(path, _)| path)
+            .filter(|path| !known_paths.contains(path.as_path()))
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            if let Some(mut known) = known {
+                known.set_inodes(&inodes, found);
+                ctx.state.directories.insert(dir, known);
+            }
+            continue;
+        }
+        let reused = known.unwrap_or_default();
+        pending.push((dir, digest, records, inodes, found, reused, paths));
+    }
+
+    // Every file needing hashing is hashed at once so that the threads can
+    // share the work across directories
+    let to_hash = pending
+        .iter()
+        .flat_map(|(_, _, _, _, _, _, paths)| paths)
+        .collect::<Vec<_>>();
+    // Once the read budget runs out the remaining files are left for the next
+    // run to hash
+    let hasher = scan_opts.hasher.as_ref();
+    let mut hashes = parallel_map(&to_hash, read_opts.thread_count(), |path| {
+        if read_opts.io_exhausted() {
+            return None;
+        }
+        debug!("Calculating hash for file {path:?}");
+        Some(match hasher {
+            Some(CustomHasher(hasher)) => fs::metadata(path)
+                .and_then(|meta| FileHashes::from_hasher(path, meta.len(), hasher.as_ref())),
+            None => FileHashes::from_path(path, read_opts),
+        })
+    })
+    .into_iter();
+
+    for (dir, digest, mut records, inodes, found, reused, paths) in pending {
+        let mut dir_state = DirectoryState { digest, ..reused };
+        for (path, hash) in paths.into_iter().zip(hashes.by_ref()) {
+            let Some(hash) = hash else {
+                ctx.summary.unhashed_files += 1;
+                continue;
+            };
+            let hash = match hash {
+                Ok(v) => v,
+                Err(e) => {
+                    if is_permission_denied(Some(&e)) {
+                        scan_opts.permission_denied(&path, &mut ctx.summary);
+                        continue;
+                    }
+                    ctx.summary.record_read_error(&e);
+                    error!("Error getting file hash for {}: {:?}", path.display(), e);
+                    continue;
+                }
+            };
+            ctx.events.emit(Event::FileHashed {
+                path: &path,
+                size: hash.size(),
+                group: hash.group_id(),
+            });
+            dir_state.files.push((path.clone(), hash));
+            insert_hashed(&mut retvl, &mut records, path, hash);
+        }
+        dir_state.set_inodes(&inodes, found);
+        ctx.state.directories.insert(dir, dir_state);
+    }
+
+    retvl
+}
+
+/// Inserts a hashed file into `cache`, along with its [FileRecord] from the
+/// walk if it has one.
+fn insert_hashed(
+    cache: &mut HashCache,
+    records: &mut HashMap<PathBuf, FileRecordBuilder>,
+    path: PathBuf,
+    hash: FileHashes,
+) {
+    match records.remove(&path) {
+        Some(record) => cache.insert_record(path, record.hashes(hash).build()),
+        None => cache.insert(path, hash),
+    }
+}
+
+fn is_permission_denied(err: Option<&io::Error>) -> bool {
+    err.map(io::Error::kind) == Some(io::ErrorKind::PermissionDenied)
+}
@@ -0,0 +1,162 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hashcache::{FileHashes, FileRecord, HashCache, SampleParams},
+    utils::format_size,
+};
+
+/// A serialized [HashCache], written by `--save-scan` so that scans of
+/// different volumes can later be merged & compared without both being online.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanFile {
+    /// Every scanned file and its [FileHashes].
+    pub files: Vec<(PathBuf, FileHashes)>,
+    /// The parameters the hashes were calculated with.
+    #[serde(default)]
+    pub sampling: SampleParams,
+}
+
+impl ScanFile {
+    /// Snapshots the contents of `cache`, whose hashes were calculated with
+    /// `sampling`.
+    pub fn from_cache(cache: &HashCache, sampling: SampleParams) -> Self {
+        let mut files = cache
+            .iter()
+            .flat_map(|(hashes, paths)| paths.iter().map(|path| (path.clone(), *hashes)))
+            .collect::<Vec<_>>();
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self { files, sampling }
+    }
+
+    /// Loads the [ScanFile] stored at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let fh = File::open(path)?;
+        let retvl = serde_json::from_reader(BufReader::new(fh))?;
+        Ok(retvl)
+    }
+
+    /// Writes this [ScanFile] to `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let fh = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(fh), self)?;
+        Ok(())
+    }
+
+    /// Converts this snapshot back into a [HashCache].
+    pub fn to_cache(&self) -> HashCache {
+        let mut retvl = HashCache::new();
+        for (path, hashes) in &self.files {
+            retvl.insert(path.clone(), *hashes);
+        }
+        retvl
+    }
+
+    /// Adds the files in this scan which may be duplicates of a file already in
+    /// `cache` to it as archived, returning how many were added.
+    ///
+    /// Only the matching files are looked up, rather than walking the archive
+    /// again; any that are gone or have changed size are skipped.
+    pub fn append_to(&self, cache: &mut HashCache) -> usize {
+        let live = cache
+            .records()
+            .values()
+            .map(|record| record.hashes)
+            .collect::<HashSet<_>>();
+        let mut added = 0;
+        for (path, hashes) in &self.files {
+            if !live.contains(hashes) || cache.records().contains_key(path) {
+                continue;
+            }
+            let meta = match fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_file() && meta.len() == hashes.size() => meta,
+                Ok(_) => {
+                    debug!("Archived file {} has changed; skipping.", path.display());
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Could not read archived file {}: {:?}", path.display(), e);
+                    continue;
+                }
+            };
+            let record = FileRecord::builder()
+                .metadata(&meta)
+                .hashes(*hashes)
+                .archived(true)
+                .build();
+            cache.insert_record(path.clone(), record);
+            added += 1;
+        }
+        added
+    }
+}
+
+/// Merges the scans at `inputs` into a single scan written to `output`, and
+/// prints the sets of possible duplicates which span more than 1 scan to
+/// stdout.
+///
+/// Since the scanned trees may not be available, the duplicates are only
+/// matched by their hashes and are not compared byte-for-byte.
+pub fn merge_scans(inputs: &[PathBuf], output: &Path) -> io::Result<()> {
+    let mut scans = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let scan = ScanFile::load(input).map_err(|e| {
+            io::Error::new(e.kind(), format!("error loading {}: {e}", input.display()))
+        })?;
+        scans.push(scan);
+    }
+    let sampling = scans[0].sampling;
+    if let Some(idx) = scans.iter().position(|scan| scan.sampling != sampling) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} was hashed with different sampling ({}) than {} ({}), so they can't be \
+                 compared",
+                inputs[idx].display(),
+                scans[idx].sampling,
+                inputs[0].display(),
+                sampling
+            ),
+        ));
+    }
+
+    // Which scans each set of hashes appeared in, so we only report the
+    // duplicates that span volumes
+    let mut sources: HashMap<FileHashes, BTreeSet<usize>> = HashMap::new();
+    for (idx, scan) in scans.iter().enumerate() {
+        for (_, hashes) in &scan.files {
+            sources.entry(*hashes).or_default().insert(idx);
+        }
+    }
+
+    let merged = scans.iter().map(ScanFile::to_cache).collect::<HashCache>();
+    let mut dups = merged.duplicates();
+    dups.sort_by_key(|dup| Reverse(dup.hashes.size()));
+    for dup in dups {
+        let scan_count = sources.get(&dup.hashes).map_or(0, BTreeSet::len);
+        if scan_count < 2 {
+            continue;
+        }
+        println!(
+            "Group {} ({}) found in {} scans:",
+            dup.id(),
+            format_size(dup.hashes.size()),
+            scan_count
+        );
+        let mut paths = dup.paths.iter().collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            println!("    {}", path.display());
+        }
+    }
+
+    ScanFile::from_cache(&merged, sampling).save(output)
+}
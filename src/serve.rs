@@ -0,0 +1,213 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::unix::{
+        fs::MetadataExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dedup::{verify_target, DedupOptions, ReadOnlyDirPolicy},
+    dupchecks::{check_link, is_same_file, ShouldNotRelinkReason},
+    fsinfo::BlockSizes,
+    hashcache::{FileHashes, GroupId},
+    journal::{Journal, Outcome},
+    state::FileIdentity,
+    utils::{is_writable_dir, with_writable_dir, LinkSource, ReadOptions},
+};
+
+/// A request sent to the server, as a single line of JSON.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    /// Calculate the [FileHashes] of a file.
+    Hash { path: PathBuf },
+    /// Compare 2 files byte-for-byte.
+    Compare { left: PathBuf, right: PathBuf },
+    /// Do our `--action` to `right`, keeping `left`, if they're identical and
+    /// our policies allow it.
+    Link { left: PathBuf, right: PathBuf },
+}
+
+/// The server's reply to a [Request], as a single line of JSON.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+enum Response {
+    Hashed { group: GroupId, size: u64 },
+    Compared { identical: bool },
+    Linked,
+    NotLinked { reason: String },
+    Error { message: String },
+}
+
+/// Listens on the Unix socket at `socket`, handling requests from each client
+/// in turn until we're killed.
+///
+/// Clients write 1 JSON [Request] per line and read back 1 JSON [Response] per
+/// line. Links are made on the client's behalf without prompting, since the
+/// request itself is the client's approval, but all other policies apply,
+/// and each change is recorded in `journal` like those of a normal run.
+pub fn serve(
+    socket: &Path,
+    opts: &DedupOptions,
+    read_opts: ReadOptions,
+    journal: &mut Journal,
+) -> io::Result<()> {
+    let listener = UnixListener::bind(socket)?;
+    info!("Listening on {}.", socket.display());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error accepting a connection: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = handle_client(stream, opts, read_opts, journal) {
+            error!("Error handling a client: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle_client(
+    stream: UnixStream,
+    opts: &DedupOptions,
+    read_opts: ReadOptions,
+    journal: &mut Journal,
+) -> io::Result<()> {
+    debug!("Accepted a client.");
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("Handling request {request:?}");
+                handle_request(request, opts, read_opts, journal)
+            }
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {e}"),
+            },
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    debug!("Client disconnected.");
+    Ok(())
+}
+
+fn handle_request(
+    request: Request,
+    opts: &DedupOptions,
+    read_opts: ReadOptions,
+    journal: &mut Journal,
+) -> Response {
+    let res = match request {
+        Request::Hash { path } => {
+            FileHashes::from_path(&path, read_opts).map(|hashes| Response::Hashed {
+                group: hashes.group_id(),
+                size: hashes.size(),
+            })
+        }
+        Request::Compare { left, right } => {
+            is_same_file(&left, &right, read_opts).map(|identical| Response::Compared { identical })
+        }
+        Request::Link { left, right } => link(&left, &right, opts, read_opts, journal),
+    };
+    res.unwrap_or_else(|e| Response::Error {
+        message: e.to_string(),
+    })
+}
+
+/// Does our action to `right`, keeping `left`, if they're identical & our
+/// policies allow it.
+fn link(
+    left: &Path,
+    right: &Path,
+    opts: &DedupOptions,
+    read_opts: ReadOptions,
+    journal: &mut Journal,
+) -> io::Result<Response> {
+    let not_linked = |reason: &str| {
+        Ok(Response::NotLinked {
+            reason: reason.to_owned(),
+        })
+    };
+    let action = opts.dedup_action();
+    let left_meta = fs::metadata(left)?;
+    // Taken before comparing, so that anything written to `right` afterwards
+    // is noticed before it's lost
+    let right_identity = FileIdentity::from_metadata(&fs::metadata(right)?);
+    if !opts.link_tiny && left_meta.size() < BlockSizes::default().get(left_meta.dev(), left) {
+        return not_linked("The files are smaller than a filesystem block.");
+    }
+    if !is_same_file(left, right, read_opts)? {
+        return not_linked("The files differ.");
+    }
+    if let Err(reason) = check_link(left, right, opts)? {
+        return not_linked(reason.msg());
+    }
+    let chmod_dir = match right.parent() {
+        Some(dir) if !is_writable_dir(dir) => match opts.readonly_dir_policy {
+            ReadOnlyDirPolicy::Skip => {
+                return not_linked(ShouldNotRelinkReason::ReadOnlyDirectory.msg());
+            }
+            ReadOnlyDirPolicy::Chmod => Some(dir),
+        },
+        _ => None,
+    };
+    if let Err(reason) = opts.check_headroom(right) {
+        return not_linked(&reason.to_string());
+    }
+    if opts.dry_run || !action.modifies() {
+        return not_linked(&format!(
+            "Would {} {} {} {}, but {}.",
+            action.verb(),
+            right.display(),
+            action.preposition(),
+            left.display(),
+            if opts.dry_run {
+                "this is a dry run"
+            } else {
+                "the action only reports duplicates"
+            }
+        ));
+    }
+    let source = LinkSource::open_verified(left, left_meta.dev(), left_meta.ino())?;
+    let temp = action.temp_path(right);
+    let op = journal.begin(action.event_name(false), left, right, temp.as_deref())?;
+    let verified = if action.destroys_target() {
+        verify_target(right, Some(right_identity))
+    } else {
+        Ok(())
+    };
+    let res = verified.and_then(|()| match chmod_dir {
+        Some(dir) => with_writable_dir(dir, || action.replace(&source, left, right)),
+        None => action.replace(&source, left, right),
+    });
+    let outcome = if res.is_ok() {
+        Outcome::Done
+    } else {
+        Outcome::Failed
+    };
+    journal.end(op, outcome)?;
+    res?;
+    info!(
+        "Did a client's request to {} {} {} {}.",
+        action.verb(),
+        right.display(),
+        action.preposition(),
+        left.display()
+    );
+    Ok(Response::Linked)
+}
@@ -0,0 +1,473 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, Metadata},
+    hash::Hasher,
+    io::{self, BufReader, BufWriter},
+    mem,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::hashcache::{ContentDigest, FileHashes, FileRecord, GroupId, SampleParams};
+
+/// Information carried over between runs of the application so that work done
+/// by a previous run doesn't need to be redone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PersistentState {
+    /// The state of each directory we've scanned, keyed by its absolute path.
+    #[serde(default)]
+    pub directories: HashMap<PathBuf, DirectoryState>,
+    /// The duplicate groups whose content has been consolidated into a single
+    /// inode in a previous run.
+    #[serde(default)]
+    pub consolidated: HashSet<GroupId>,
+    /// Pairs of files which were compared byte-for-byte and found to differ.
+    #[serde(default)]
+    pub known_different: HashSet<FilePair>,
+    /// The full-content digests of duplicate groups whose files were found to
+    /// be identical, so that new files in those groups can be checked against
+    /// the digest rather than reread alongside an existing copy.
+    #[serde(default)]
+    pub verified_content: HashMap<GroupId, VerifiedContent>,
+    /// The parameters the recorded hashes were calculated with.
+    #[serde(default)]
+    pub sampling: SampleParams,
+    /// The directories whose files were relinked this run, which are
+    /// refreshed by [PersistentState::refresh_touched].
+    #[serde(skip)]
+    touched: HashSet<PathBuf>,
+}
+
+/// Identifies a file's inode along with the metadata that changes whenever its
+/// content does, so that a recorded [FileIdentity] stops matching once the
+/// file is modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FileIdentity {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+impl FileIdentity {
+    /// Builds the [FileIdentity] of the file with the given metadata.
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+        }
+    }
+}
+
+impl From<&FileRecord> for FileIdentity {
+    fn from(record: &FileRecord) -> Self {
+        Self {
+            dev: record.dev,
+            ino: record.ino,
+            size: record.hashes.size(),
+            mtime: record.mtime,
+            mtime_nsec: record.mtime_nsec,
+        }
+    }
+}
+
+/// The content of a duplicate group, as verified by a byte-for-byte comparison.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifiedContent {
+    /// The digest of the content.
+    pub digest: ContentDigest,
+    /// The files known to have the content, as of when they were checked.
+    pub files: HashSet<FileIdentity>,
+}
+
+/// An unordered pair of [FileIdentity]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FilePair(FileIdentity, FileIdentity);
+
+impl FilePair {
+    /// Builds the [FilePair] of `left` and `right`, which is the same
+    /// regardless of their order.
+    pub fn new(left: FileIdentity, right: FileIdentity) -> Self {
+        if left <= right {
+            Self(left, right)
+        } else {
+            Self(right, left)
+        }
+    }
+}
+
+/// The inode of a recorded file, so that the file can be reused without
+/// being `stat`ed when its directory is skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InodeState {
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    /// Whether the path is a symlink; if so, the other fields describe the
+    /// file it points to.
+    pub symlink: bool,
+}
+
+impl InodeState {
+    /// Builds the [InodeState] of the file with the given metadata, which for
+    /// a symlink should be the metadata of the file it points to.
+    pub fn from_metadata(meta: &Metadata, symlink: bool) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            nlink: meta.nlink(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            symlink,
+        }
+    }
+
+    /// Builds the [FileRecord] of a file with this inode & `hashes`.
+    pub fn record(&self, hashes: FileHashes) -> FileRecord {
+        FileRecord::builder()
+            .hashes(hashes)
+            .inode(self.dev, self.ino, self.nlink)
+            .mtime(self.mtime, self.mtime_nsec)
+            .symlink(self.symlink)
+            .build()
+    }
+}
+
+/// A directory's own inode along with the times that change whenever an entry
+/// is added to, removed from, or renamed within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DirStamp {
+    pub dev: u64,
+    pub ino: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+}
+
+impl DirectoryState {
+    /// The state of a directory without any files we scan.
+    pub fn empty() -> Self {
+        Self {
+            digest: directory_digest(&[]),
+            complete: true,
+            ..Self::default()
+        }
+    }
+
+    /// Records the `inodes` the walk found for the directory's files, and
+    /// whether all `found` of them were hashed.
+    pub fn set_inodes(&mut self, inodes: &HashMap<PathBuf, InodeState>, found: usize) {
+        self.inodes = self
+            .files
+            .iter()
+            .map_while(|(path, _)| inodes.get(path).copied())
+            .collect();
+        self.complete = self.files.len() == found && self.inodes.len() == found;
+    }
+}
+
+impl DirStamp {
+    /// Builds the [DirStamp] of the directory with the given metadata.
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+        }
+    }
+}
+
+/// The recorded state of a single directory as of the last time it was
+/// scanned.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectoryState {
+    /// The digest of the directory's entries; see [directory_digest].
+    pub digest: u64,
+    /// The files directly inside the directory and their [FileHashes]; files
+    /// that couldn't be hashed are left out, so they're retried next time.
+    pub files: Vec<(PathBuf, FileHashes)>,
+    /// The inodes of `files`, in the same order; empty for directories
+    /// recorded before inodes were.
+    #[serde(default)]
+    pub inodes: Vec<InodeState>,
+    /// Whether every file found in the directory was hashed, and nothing in it
+    /// failed to be read.
+    #[serde(default)]
+    pub complete: bool,
+    /// The directory's own [DirStamp] when it was walked, if it was.
+    #[serde(default)]
+    pub stamp: Option<DirStamp>,
+    /// The directories directly inside this one that were walked.
+    #[serde(default)]
+    pub subdirs: Vec<PathBuf>,
+    /// The digest of the root & [ScanOptions](crate::ScanOptions) the
+    /// directory was walked with, or 0 if it can't be skipped whatever they
+    /// are.
+    #[serde(default)]
+    pub walk: u64,
+    /// The digest rolled up from this directory & everything below it, or
+    /// `None` if some of it can't be skipped; see
+    /// [PersistentState::unchanged_subtree].
+    #[serde(default)]
+    pub subtree: Option<u64>,
+}
+
+impl PersistentState {
+    /// Loads the [PersistentState] stored at `path`.
+    ///
+    /// A missing file is treated as an empty state, since that's what we'll
+    /// have on the very first run.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let fh = match File::open(path) {
+            Ok(fh) => fh,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No state found at {}; starting fresh.", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e),
+        };
+        let retvl = serde_json::from_reader(BufReader::new(fh))?;
+        Ok(retvl)
+    }
+
+    /// Writes this [PersistentState] to `path`.
+    ///
+    /// The state is first written to a temporary file which is then renamed
+    /// over `path`, so that an interrupted write can't corrupt the previous
+    /// state.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let fh = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(fh);
+        serde_json::to_writer(&mut writer, self)?;
+        // A failed write must leave the previous state in place, so it's only
+        // replaced once the new one is safely on disk
+        io::Write::flush(&mut writer)?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Switches this state to hashes calculated with `sampling`.
+    ///
+    /// If the recorded hashes were calculated with different parameters they
+    /// can't be compared to new ones, so they're discarded along with
+    /// everything else identified by their groups.
+    pub fn use_sampling(&mut self, sampling: SampleParams) {
+        if self.sampling == sampling {
+            return;
+        }
+        if !self.directories.is_empty() || !self.consolidated.is_empty() {
+            info!(
+                "The state's hashes were calculated with different sampling ({}); rehashing \
+                 everything.",
+                self.sampling
+            );
+        }
+        self.directories.clear();
+        self.consolidated.clear();
+        self.verified_content.clear();
+        self.sampling = sampling;
+    }
+
+    /// Records that the files with the given identities in group `id` have the
+    /// content with `digest`.
+    pub fn record_verified(
+        &mut self,
+        id: GroupId,
+        digest: ContentDigest,
+        files: impl IntoIterator<Item = FileIdentity>,
+    ) {
+        let content = self
+            .verified_content
+            .entry(id)
+            .or_insert_with(|| VerifiedContent {
+                digest,
+                files: HashSet::new(),
+            });
+        // Groups are only as distinct as their sampled hashes, so 1 group can
+        // turn out to hold several contents; only the latest is kept
+        if content.digest != digest {
+            content.digest = digest;
+            content.files.clear();
+        }
+        content.files.extend(files);
+    }
+
+    /// Retrieves the stored files for `dir` if its digest is still `digest`.
+    pub fn unchanged_directory(&self, dir: &Path, digest: u64) -> Option<&DirectoryState> {
+        self.directories
+            .get(dir)
+            .filter(|state| state.digest == digest)
+    }
+
+    /// Checks whether nothing has been added to, removed from, or renamed
+    /// within `dir` or any directory below it since they were recorded, as
+    /// walked with the options whose digest is `walk`, by rolling up a digest
+    /// of the subtree from the directories' current [DirStamp]s & comparing it
+    /// to the recorded one.
+    ///
+    /// Only the directories themselves are `stat`ed, not their files, so files
+    /// modified in place aren't noticed. `checked` remembers the digests
+    /// already rolled up, so that checking each directory on the way down a
+    /// tree doesn't `stat` the same directories again.
+    pub fn unchanged_subtree(
+        &self,
+        dir: &Path,
+        walk: u64,
+        checked: &mut HashMap<PathBuf, Option<u64>>,
+    ) -> bool {
+        let Some(recorded) = self.directories.get(dir).and_then(|state| state.subtree) else {
+            return false;
+        };
+        let mut current_stamp = |dir: &Path, _: &DirectoryState| {
+            fs::metadata(dir)
+                .ok()
+                .map(|meta| DirStamp::from_metadata(&meta))
+        };
+        self.rolled_up(dir, Some(walk), &mut current_stamp, checked) == Some(recorded)
+    }
+
+    /// Recalculates the recorded subtree digest of every directory from the
+    /// recorded [DirStamp]s, once any of them have changed.
+    pub fn roll_up(&mut self) {
+        let mut rolled = HashMap::new();
+        let mut recorded_stamp = |_: &Path, state: &DirectoryState| state.stamp;
+        for dir in self.directories.keys() {
+            self.rolled_up(dir, None, &mut recorded_stamp, &mut rolled);
+        }
+        for (dir, state) in &mut self.directories {
+            state.subtree = rolled.get(dir).copied().flatten();
+        }
+    }
+
+    /// Rolls up the digest of `dir` & everything below it from the stamp each
+    /// directory gets from `stamp_of`, or `None` if any of them can't be
+    /// skipped, or weren't walked with the options whose digest is `walk`.
+    fn rolled_up(
+        &self,
+        dir: &Path,
+        walk: Option<u64>,
+        stamp_of: &mut impl FnMut(&Path, &DirectoryState) -> Option<DirStamp>,
+        rolled: &mut HashMap<PathBuf, Option<u64>>,
+    ) -> Option<u64> {
+        if let Some(digest) = rolled.get(dir) {
+            return *digest;
+        }
+        let digest = self
+            .directories
+            .get(dir)
+            .filter(|state| {
+                state.complete
+                    && state.walk != 0
+                    && walk.is_none_or(|walk| walk == state.walk)
+                    && state.inodes.len() == state.files.len()
+            })
+            .and_then(|state| {
+                let stamp = stamp_of(dir, state)?;
+                let mut hasher = SeaHasher::new();
+                hasher.write_u64(state.digest);
+                hasher.write_u64(state.walk);
+                hasher.write_u64(stamp.dev);
+                hasher.write_u64(stamp.ino);
+                hasher.write_i64(stamp.mtime);
+                hasher.write_i64(stamp.mtime_nsec);
+                hasher.write_i64(stamp.ctime);
+                hasher.write_i64(stamp.ctime_nsec);
+                for subdir in &state.subdirs {
+                    hasher.write(subdir.as_os_str().as_bytes());
+                    hasher.write_u64(self.rolled_up(subdir, walk, stamp_of, rolled)?);
+                }
+                Some(hasher.finish())
+            });
+        rolled.insert(dir.to_owned(), digest);
+        digest
+    }
+
+    /// Notes that files in `dir` were relinked, so that its record needs
+    /// refreshing before it's saved.
+    pub fn touch(&mut self, dir: &Path) {
+        self.touched.insert(dir.to_owned());
+    }
+
+    /// Refreshes the records of the directories relinked this run, so that the
+    /// links we made don't look like changes to the next run.
+    ///
+    /// Linking keeps each file's content but can change its inode, and changes
+    /// the directory's own times, so each recorded file is `stat`ed again &
+    /// the directory's digests recalculated, keeping the recorded hashes.
+    /// Files that are gone, such as those deleted as duplicates, are dropped.
+    pub fn refresh_touched(&mut self) {
+        if self.touched.is_empty() {
+            return;
+        }
+        for dir in mem::take(&mut self.touched) {
+            let Some(state) = self.directories.get_mut(&dir) else {
+                continue;
+            };
+            let mut files = Vec::with_capacity(state.files.len());
+            let mut found = Vec::with_capacity(state.files.len());
+            let mut inodes = Vec::with_capacity(state.files.len());
+            for (path, hashes) in mem::take(&mut state.files) {
+                let Ok(meta) = fs::symlink_metadata(&path) else {
+                    continue;
+                };
+                let inode = if meta.is_symlink() {
+                    match fs::metadata(&path) {
+                        Ok(target) => InodeState::from_metadata(&target, true),
+                        Err(_) => continue,
+                    }
+                } else {
+                    InodeState::from_metadata(&meta, false)
+                };
+                files.push((path.clone(), hashes));
+                found.push((path, meta));
+                inodes.push(inode);
+            }
+            state.digest = directory_digest(&found);
+            state.files = files;
+            state.inodes = inodes;
+            state.stamp = fs::metadata(&dir)
+                .ok()
+                .map(|meta| DirStamp::from_metadata(&meta));
+        }
+        self.roll_up();
+    }
+}
+
+/// Calculates a digest identifying the current contents of a directory from the
+/// metadata of the files directly inside it.
+///
+/// The digest covers each file's name, size, modification time, and inode
+/// identity, so adding, removing, modifying, or relinking a file changes it.
+pub fn directory_digest(files: &[(PathBuf, Metadata)]) -> u64 {
+    let mut files = files.iter().collect::<Vec<_>>();
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut hasher = SeaHasher::new();
+    for (path, meta) in files {
+        let name = path.file_name().unwrap_or_default();
+        hasher.write(name.as_bytes());
+        hasher.write_u64(meta.size());
+        hasher.write_i64(meta.mtime());
+        hasher.write_i64(meta.mtime_nsec());
+        hasher.write_u64(meta.dev());
+        hasher.write_u64(meta.ino());
+    }
+    hasher.finish()
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::error;
+use serde::Serialize;
+
+use crate::{events::Event, hashcache::GroupId};
+
+/// How often the status is written to the FIFO, at most.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The JSON object written to the FIFO.
+#[derive(Debug, Clone, Default, Serialize)]
+struct Status {
+    /// The phase the run is in: `starting`, `scan`, `hash`, `compare`, `link`,
+    /// or `done`.
+    phase: &'static str,
+    /// Seconds since the run started.
+    elapsed_secs: u64,
+    /// How many files, groups or paths the current phase has worked through.
+    done: usize,
+    /// How many the current phase will work through, if known.
+    total: Option<usize>,
+    /// The estimated seconds until the current phase finishes, once there's
+    /// enough progress to go by.
+    eta_secs: Option<u64>,
+    files_hashed: u64,
+    bytes_hashed: u64,
+    groups_found: u64,
+    pairs_compared: u64,
+    /// The paths replaced or deleted, or that would have been in a dry run.
+    paths_linked: u64,
+    /// About how many bytes the replaced or deleted paths freed, or would have
+    /// in a dry run.
+    bytes_saved: u64,
+}
+
+/// A named pipe that the run's progress is written to as a single line of JSON,
+/// at most once a second, for `--status-fifo`.
+///
+/// Each status is written with the FIFO opened afresh & closed again, so a
+/// reader like `cat` gets exactly 1 status each time it opens it. Writes never
+/// block the run: if nothing is reading the FIFO, or the reader isn't keeping
+/// up, that status is skipped.
+#[derive(Debug)]
+pub struct StatusFifo {
+    path: PathBuf,
+    started: Instant,
+    /// When the current phase started.
+    phase_started: Instant,
+    last_write: Option<Instant>,
+    /// The size of each group found, to total up the bytes saved.
+    group_sizes: HashMap<GroupId, u64>,
+    status: Status,
+}
+
+impl StatusFifo {
+    /// Opens the FIFO at `path`, creating it if it doesn't exist yet.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_fifo() => {}
+            Ok(_) => {
+                return Err(io::Error::other(format!(
+                    "{} exists and isn't a FIFO",
+                    path.display()
+                )))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let raw = CString::new(path.as_os_str().as_bytes())?;
+                // SAFETY: `raw` is a valid NUL-terminated string that outlives
+                // the call.
+                if unsafe { libc::mkfifo(raw.as_ptr(), 0o644) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        let now = Instant::now();
+        Ok(Self {
+            path: path.to_owned(),
+            started: now,
+            phase_started: now,
+            last_write: None,
+            group_sizes: HashMap::new(),
+            status: Status {
+                phase: "starting",
+                ..Status::default()
+            },
+        })
+    }
+
+    /// Updates the status from `event`, writing it out if one is due.
+    pub fn record(&mut self, event: &Event<'_>) {
+        let status = &mut self.status;
+        match event {
+            Event::ScanStart { .. } => self.start_phase("scan", None),
+            Event::PhaseStart { phase, total } => self.start_phase(phase, Some(*total)),
+            Event::FileHashed { size, .. } => {
+                status.files_hashed += 1;
+                status.bytes_hashed += size;
+                status.done += 1;
+            }
+            Event::GroupFound { group, size, .. } => {
+                status.groups_found += 1;
+                status.done += 1;
+                self.group_sizes.insert(*group, *size);
+            }
+            Event::CompareResult { .. } => status.pairs_compared += 1,
+            // Additions to the --link-into store aren't counted, since they
+            // don't replace anything
+            Event::Action {
+                group,
+                action,
+                success,
+                ..
+            } if status.phase == "link" && !action.ends_with("store") => {
+                status.done += 1;
+                if *success && *action != "report" {
+                    status.paths_linked += 1;
+                    status.bytes_saved += self.group_sizes.get(group).copied().unwrap_or_default();
+                }
+            }
+            Event::Summary { .. } => {
+                self.start_phase("done", None);
+                self.write();
+                return;
+            }
+            Event::Classified { .. } | Event::Action { .. } => {}
+        }
+        if self
+            .last_write
+            .is_none_or(|last| last.elapsed() >= STATUS_INTERVAL)
+        {
+            self.write();
+        }
+    }
+
+    /// Moves on to `phase`, which will work through `total` items if known.
+    fn start_phase(&mut self, phase: &'static str, total: Option<usize>) {
+        self.phase_started = Instant::now();
+        self.status.phase = phase;
+        self.status.done = 0;
+        self.status.total = total;
+    }
+
+    /// Writes the current status to the FIFO if anything is reading it.
+    fn write(&mut self) {
+        self.last_write = Some(Instant::now());
+        let status = &mut self.status;
+        status.elapsed_secs = self.started.elapsed().as_secs();
+        // The rate so far in this phase is assumed to hold for the rest of it
+        status.eta_secs = status.total.filter(|_| status.done > 0).map(|total| {
+            let remaining = total.saturating_sub(status.done) as f64;
+            let per_item = self.phase_started.elapsed().as_secs_f64() / status.done as f64;
+            (remaining * per_item).round() as u64
+        });
+        let line = match serde_json::to_string(status) {
+            Ok(json) => format!("{json}\n"),
+            Err(e) => {
+                error!("Error serializing the status: {e:?}");
+                return;
+            }
+        };
+        let res = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path)
+            .and_then(|mut fifo| fifo.write_all(line.as_bytes()));
+        match res {
+            Ok(()) => {}
+            // Nothing is reading the FIFO, or the reader is falling behind
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => error!("Error writing status to {}: {:?}", self.path.display(), e),
+        }
+    }
+}
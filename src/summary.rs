@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    fsinfo::{supports_snapshots, FsStats},
+    utils::{escape_glob, format_size, shell_quote, DamagedRegion},
+};
+
+/// The maximum number of inaccessible subtrees listed individually in the
+/// summary.
+const MAX_LOGGED_DENIED_ROOTS: usize = 20;
+
+/// The percentage of free inodes below which a filesystem is called out as
+/// running low.
+const LOW_INODE_PERCENT: f64 = 5.0;
+
+/// Statistics & notable events collected over the course of a run, reported to
+/// the user once it finishes.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    /// Files we couldn't fully read because of IO errors partway through.
+    pub damaged: Vec<DamagedFile>,
+    /// Paths we skipped because we didn't have permission to read them.
+    pub permission_denied: Vec<PathBuf>,
+    /// Snapshot directories we skipped scanning.
+    pub snapshots: Vec<PathBuf>,
+    /// The number of paths that would have been linked in a dry run.
+    pub dry_run_links: u64,
+    /// The number of duplicates that would have been deleted in a dry run.
+    pub dry_run_deletes: u64,
+    /// The number of bytes the links or deletions in a dry run would have
+    /// freed.
+    pub dry_run_bytes: u64,
+    /// The number of files left unhashed because the read budget ran out.
+    pub unhashed_files: u64,
+    /// The number of duplicate groups left uncompared because the read budget
+    /// ran out.
+    pub uncompared_groups: u64,
+    /// The number of duplicate groups skipped because their files are smaller
+    /// than a filesystem block.
+    pub tiny_groups: u64,
+    /// The number of duplicates deleted by `--action delete`.
+    pub deleted_files: u64,
+    /// The number of paths replaced by links or clones.
+    pub linked_files: u64,
+    /// The duplicates the user answered no to at a prompt.
+    pub declined: Vec<PathBuf>,
+    /// The directories scanned, as given, which the excludes suggested for
+    /// inaccessible subtrees are relative to.
+    pub roots: Vec<PathBuf>,
+    /// Totals for each filesystem we found duplicates on, keyed by device ID.
+    pub filesystems: HashMap<u64, FilesystemSummary>,
+}
+
+/// The space & inodes deduplication could and did free on a single
+/// filesystem.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilesystemSummary {
+    /// A path on the filesystem, used to query its current usage.
+    pub sample_path: PathBuf,
+    /// The number of inodes that confirmed duplicates would free if linked.
+    pub reclaimable_inodes: u64,
+    /// The number of bytes that confirmed duplicates would free if linked.
+    pub reclaimable_bytes: u64,
+    /// The number of inodes freed by the links we made.
+    pub freed_inodes: u64,
+    /// The number of bytes freed by the links we made.
+    pub freed_bytes: u64,
+    /// The number of groups kept as an additional canonical copy because every
+    /// identical file had reached the link limit.
+    pub link_limited_groups: u64,
+    /// The link limit that was reached, if any.
+    pub link_max: Option<u64>,
+    /// The number of groups linked on an overlay filesystem, which aren't
+    /// counted as freeing anything.
+    pub overlay_links: u64,
+}
+
+/// A file which failed to be read partway through, likely due to bad sectors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedFile {
+    /// The path of the damaged file.
+    pub path: PathBuf,
+    /// The offset of the read which failed.
+    pub offset: u64,
+    /// A description of the error the read failed with.
+    pub error: String,
+}
+
+impl RunSummary {
+    /// Constructs an empty [RunSummary].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an error encountered while reading a file, returning `true` if
+    /// it indicated the file is damaged.
+    pub fn record_read_error(&mut self, err: &io::Error) -> bool {
+        let Some(region) = DamagedRegion::from_io(err) else {
+            return false;
+        };
+        if !self.damaged.iter().any(|d| d.path == region.path) {
+            self.damaged.push(DamagedFile {
+                path: region.path.clone(),
+                offset: region.offset,
+                error: region.source.to_string(),
+            });
+        }
+        true
+    }
+
+    /// Formats the totals as a single line of `key=value` pairs, for scripts
+    /// wrapping runs that don't link anything.
+    ///
+    /// `mode` names the kind of run, e.g. `dry-run`.
+    pub fn machine_summary(&self, mode: &str, stopped_early: bool) -> String {
+        let (inodes, bytes) = self
+            .filesystems
+            .values()
+            .fold((0, 0), |(inodes, bytes), fs| {
+                (inodes + fs.reclaimable_inodes, bytes + fs.reclaimable_bytes)
+            });
+        format!(
+            "hldup-summary mode={mode} reclaimable_inodes={inodes} reclaimable_bytes={bytes} \
+             would_link={} would_delete={} would_free_bytes={} damaged={} permission_denied={} \
+             unhashed_files={} uncompared_groups={} stopped_early={}",
+            self.dry_run_links,
+            self.dry_run_deletes,
+            self.dry_run_bytes,
+            self.damaged.len(),
+            self.permission_denied.len(),
+            self.unhashed_files,
+            self.uncompared_groups,
+            u8::from(stopped_early)
+        )
+    }
+
+    /// Retrieves the [FilesystemSummary] for the filesystem with device ID
+    /// `dev`, which contains `path`.
+    pub fn filesystem(&mut self, dev: u64, path: &Path) -> &mut FilesystemSummary {
+        self.filesystems
+            .entry(dev)
+            .or_insert_with(|| FilesystemSummary {
+                sample_path: path.to_owned(),
+                ..Default::default()
+            })
+    }
+
+    /// Logs the summary for the user.
+    pub fn log(&self) {
+        let mut filesystems = self.filesystems.values().collect::<Vec<_>>();
+        filesystems.sort_by(|a, b| a.sample_path.cmp(&b.sample_path));
+        for fs in filesystems {
+            fs.log();
+        }
+        if self.dry_run_links > 0 {
+            info!(
+                "Dry run: would have linked {} path(s), freeing about {}.",
+                self.dry_run_links,
+                format_size(self.dry_run_bytes)
+            );
+        }
+        if self.dry_run_deletes > 0 {
+            warn!(
+                "Dry run: would have deleted {} duplicate file(s), freeing about {}.",
+                self.dry_run_deletes,
+                format_size(self.dry_run_bytes)
+            );
+        }
+        if self.deleted_files > 0 {
+            warn!(
+                "Deleted {} duplicate file(s); only the kept copies of their contents remain.",
+                self.deleted_files
+            );
+        }
+        if self.unhashed_files > 0 || self.uncompared_groups > 0 {
+            warn!(
+                "Ran out of read budget with {} file(s) left to hash and {} group(s) left to \
+                 compare; run again with the same --state to continue.",
+                self.unhashed_files, self.uncompared_groups
+            );
+        }
+        if self.tiny_groups > 0 {
+            info!(
+                "Skipped {} group(s) of files smaller than a filesystem block; pass --link-tiny \
+                 to link them.",
+                self.tiny_groups
+            );
+        }
+        if !self.snapshots.is_empty() {
+            info!(
+                "Skipped {} snapshot(s); pass --include-snapshots to scan them.",
+                self.snapshots.len()
+            );
+        }
+        if !self.damaged.is_empty() {
+            warn!(
+                "Found {} damaged file(s) which were skipped:",
+                self.damaged.len()
+            );
+            for damaged in &self.damaged {
+                warn!(
+                    "    {} (failed at offset {}: {})",
+                    damaged.path.display(),
+                    damaged.offset,
+                    damaged.error
+                );
+            }
+        }
+        if !self.permission_denied.is_empty() {
+            warn!(
+                "Skipped {} path(s) due to permission errors.",
+                self.permission_denied.len()
+            );
+            let roots = self.permission_denied_roots();
+            warn!(
+                "The inaccessible subtrees were rooted at the following {} path(s):",
+                roots.len()
+            );
+            for root in roots.iter().take(MAX_LOGGED_DENIED_ROOTS) {
+                warn!("    {}", root.display());
+            }
+            if roots.len() > MAX_LOGGED_DENIED_ROOTS {
+                warn!("    ...and {} more.", roots.len() - MAX_LOGGED_DENIED_ROOTS);
+            }
+            let excludes = self.accessible_excludes(&roots);
+            if !excludes.is_empty() {
+                let excludes = excludes
+                    .iter()
+                    .map(|pattern| format!("--exclude {}", shell_quote(pattern)))
+                    .collect::<Vec<_>>();
+                let roots = self.roots.iter().map(shell_quote).collect::<Vec<_>>();
+                warn!(
+                    "To scan only what's accessible, re-run with these excludes added:\n    \
+                     hldup [options] {} {}",
+                    excludes.join(" "),
+                    roots.join(" ")
+                );
+            }
+        }
+    }
+
+    /// Retrieves the top-most paths we were denied access to, with any paths
+    /// inside them omitted.
+    fn permission_denied_roots(&self) -> Vec<&Path> {
+        let mut paths = self
+            .permission_denied
+            .iter()
+            .map(PathBuf::as_path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut roots: Vec<&Path> = Vec::new();
+        for path in paths {
+            if roots.last().is_some_and(|root| path.starts_with(root)) {
+                continue;
+            }
+            roots.push(path);
+        }
+        roots
+    }
+
+    /// The `--exclude` patterns skipping each of the inaccessible subtrees
+    /// `denied` under the scanned roots, so that a re-run doesn't try them
+    /// again.
+    ///
+    /// Patterns are relative to the deepest root holding each subtree, so they
+    /// apply to the same relative path in every root.
+    fn accessible_excludes(&self, denied: &[&Path]) -> Vec<String> {
+        // Paths denied while walking are under the roots as given, while those
+        // denied while hashing are absolute
+        let roots = self
+            .roots
+            .iter()
+            .flat_map(|root| [Some(root.clone()), root.canonicalize().ok()])
+            .flatten()
+            .collect::<Vec<_>>();
+        let mut excludes = Vec::new();
+        for path in denied {
+            let Some(relative) = roots
+                .iter()
+                .filter(|root| path.starts_with(root))
+                .max_by_key(|root| root.components().count())
+                .and_then(|root| path.strip_prefix(root).ok())
+            else {
+                continue;
+            };
+            // A root that can't be read at all is better left off the re-run
+            let Some(relative) = relative.to_str().filter(|rel| !rel.is_empty()) else {
+                continue;
+            };
+            let pattern = if fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir()) {
+                format!("{}/**", escape_glob(relative))
+            } else {
+                escape_glob(relative)
+            };
+            if !excludes.contains(&pattern) {
+                excludes.push(pattern);
+            }
+        }
+        excludes
+    }
+
+    /// Writes the list of damaged files & their failing offsets to `path`, one
+    /// tab-separated `offset path` pair per line.
+    pub fn write_damaged_log(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for damaged in &self.damaged {
+            writeln!(out, "{}\t{}", damaged.offset, damaged.path.display())?;
+        }
+        out.flush()
+    }
+}
+
+impl FilesystemSummary {
+    fn log(&self) {
+        let stats = match FsStats::for_path(&self.sample_path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Could not read filesystem stats for {}: {:?}",
+                    self.sample_path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        let free_inode_pct = if stats.total_inodes == 0 {
+            100.0
+        } else {
+            100.0 * stats.free_inodes as f64 / stats.total_inodes as f64
+        };
+        info!(
+            "Filesystem containing {}: {} of {} free, {} of {} inodes free ({:.1}%).",
+            self.sample_path.display(),
+            format_size(stats.free_bytes),
+            format_size(stats.total_bytes),
+            stats.free_inodes,
+            stats.total_inodes,
+            free_inode_pct
+        );
+        info!(
+            "    Duplicates found could free {} inode(s) and {}; {} inode(s) and {} were freed.",
+            self.reclaimable_inodes,
+            format_size(self.reclaimable_bytes),
+            self.freed_inodes,
+            format_size(self.freed_bytes)
+        );
+        if supports_snapshots(&self.sample_path).unwrap_or(false) {
+            info!(
+                "    This filesystem supports snapshots; space used by duplicates that are also \
+                 held by a snapshot is pinned by it, and won't be freed until the snapshot is \
+                 deleted."
+            );
+        }
+        if let Some(link_max) = self.link_max {
+            info!(
+                "    {} additional canonical cop(ies) were kept because of the limit of {} \
+                 links per file.",
+                self.link_limited_groups, link_max
+            );
+        }
+        if self.overlay_links > 0 {
+            warn!(
+                "    {} group(s) were linked on an overlay filesystem and aren't counted as \
+                 freed; copy-ups from lower layers may have used more space than they saved.",
+                self.overlay_links
+            );
+        }
+        if free_inode_pct < LOW_INODE_PERCENT {
+            warn!(
+                "    This filesystem is low on free inodes. Linking only frees a duplicate's \
+                 inode once every link to it has been replaced, so duplicates that are \
+                 also linked from outside the scanned paths free nothing."
+            );
+        }
+    }
+}
@@ -1,32 +1,258 @@
 use std::{
-    fs,
-    io::{self, Read},
-    path::Path,
+    error::Error,
+    ffi::{CString, OsStr, OsString},
+    fmt::{self, Display},
+    fs::{self, File, FileTimes, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use log::{debug, error};
+
+use crate::hashcache::SampleParams;
 
 pub const KB: u64 = 1024;
 pub const MB: u64 = 1024 * KB;
 pub const GB: u64 = 1024 * MB;
+pub const TB: u64 = 1024 * GB;
+
+pub const MINUTE: u64 = 60;
+pub const HOUR: u64 = 60 * MINUTE;
+pub const DAY: u64 = 24 * HOUR;
+
+/// Parses a human-readable byte count such as `512`, `64K`, `1.5G`, or `2TiB`.
+///
+/// Suffixes are always treated as powers of 1024.
+pub fn parse_size(raw: &str) -> Result<u64, String> {
+    let trimmed = raw.trim();
+    let split_idx = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split_idx);
+    let multiplier = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => KB,
+        "M" | "MB" | "MIB" => MB,
+        "G" | "GB" | "GIB" => GB,
+        "T" | "TB" | "TIB" => TB,
+        other => return Err(format!("Unknown size suffix {other:?} in {raw:?}")),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("Invalid size {raw:?}: {e}"))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Formats a byte count for display using binary unit suffixes, e.g. `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[(u64, &str)] = &[(TB, "TiB"), (GB, "GiB"), (MB, "MiB"), (KB, "KiB")];
+    for (size, suffix) in UNITS {
+        if bytes >= *size {
+            return format!("{:.1} {suffix}", bytes as f64 / *size as f64);
+        }
+    }
+    format!("{bytes} B")
+}
+
+/// Quotes `arg`, such as a path, for pasting into a shell, if it needs it.
+pub fn shell_quote(arg: impl AsRef<Path>) -> String {
+    let raw = arg.as_ref().display().to_string();
+    let plain = raw
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"/._-+,:@%".contains(&b));
+    if plain {
+        raw
+    } else {
+        format!("'{}'", raw.replace('\'', r"'\''"))
+    }
+}
+
+/// Escapes the characters in `raw` that globs treat specially, so that it only
+/// matches itself.
+pub fn escape_glob(raw: &str) -> String {
+    let mut escaped = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '{' | '}' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Formats a duration in seconds for display using the largest sensible unit,
+/// e.g. `3.5d`.
+pub fn format_age(secs: u64) -> String {
+    const UNITS: &[(u64, &str)] = &[(365 * DAY, "y"), (DAY, "d"), (HOUR, "h"), (MINUTE, "m")];
+    for (size, suffix) in UNITS {
+        if secs >= *size {
+            return format!("{:.1}{suffix}", secs as f64 / *size as f64);
+        }
+    }
+    format!("{secs}s")
+}
+
+/// Finds the marker file managers & browsers add to the names of copied files
+/// in `name`, such as `Copy of report.pdf` or `report (1).pdf`.
+pub fn copy_marker(name: &OsStr) -> Option<&'static str> {
+    let name = name.to_string_lossy();
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => &name,
+    };
+    if stem.starts_with("Copy of ") {
+        Some("Copy of *")
+    } else if stem.ends_with(" - Copy") || stem.ends_with(" copy") {
+        Some("* copy")
+    } else if stem
+        .strip_suffix(')')
+        .and_then(|rest| rest.rsplit_once(" ("))
+        .is_some_and(|(_, num)| !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit()))
+    {
+        Some("* (N)")
+    } else {
+        None
+    }
+}
+
+/// Enters a `tracing` span at `$level` (e.g. `INFO`) for the rest of the
+/// enclosing scope, timing the phase it covers; without the `tracing` feature
+/// it does nothing.
+macro_rules! phase_span {
+    ($level:ident, $name:literal $($fields:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::$level, $name $($fields)*).entered();
+    };
+}
+pub(crate) use phase_span;
+
+/// Parses a point in time, given either as an age like `30d` or `12h` before
+/// `now`, or as a `YYYY-MM-DD` date, taken as midnight UTC.
+pub fn parse_time(raw: &str, now: SystemTime) -> Result<SystemTime, String> {
+    let trimmed = raw.trim();
+    if let Some(date) = parse_date(trimmed) {
+        return date.ok_or_else(|| format!("Invalid date {raw:?}"));
+    }
+    let split_idx = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split_idx);
+    let multiplier = match suffix.trim() {
+        "s" => 1,
+        "m" => MINUTE,
+        "h" => HOUR,
+        "d" => DAY,
+        "w" => 7 * DAY,
+        "y" => 365 * DAY,
+        "" => {
+            return Err(format!(
+                "Missing unit in {raw:?}; expected s, m, h, d, w, or y"
+            ))
+        }
+        other => return Err(format!("Unknown time unit {other:?} in {raw:?}")),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("Invalid age {raw:?}: {e}"))?;
+    let age = Duration::from_secs_f64(number * multiplier as f64);
+    Ok(now.checked_sub(age).unwrap_or(UNIX_EPOCH))
+}
+
+/// Parses a `YYYY-MM-DD` date as midnight UTC, returning `None` if `raw`
+/// isn't shaped like a date and `Some(None)` if it is but isn't a valid one.
+fn parse_date(raw: &str) -> Option<Option<SystemTime>> {
+    let mut parts = raw.split('-');
+    let (Some(year), Some(month), Some(day), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let (Ok(year), Ok(month), Ok(day)) = (
+        year.parse::<i64>(),
+        month.parse::<i64>(),
+        day.parse::<i64>(),
+    ) else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return Some(None);
+    }
+    // Days since the epoch in the proleptic Gregorian calendar, counting
+    // years from March so that leap days fall at the end
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(Some(UNIX_EPOCH + Duration::from_secs(days as u64 * DAY)))
+}
+
+/// A set of file extensions the user considers high-value, whose files are
+/// hashed & compared before everything else so that big wins surface early.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PriorityExtensions {
+    /// The lowercased extensions, without their leading `.`.
+    extensions: Vec<String>,
+}
+
+impl PriorityExtensions {
+    /// Parses a comma-separated list of extensions, such as `iso,.mkv,VMDK`.
+    pub fn parse(raw: &str) -> Self {
+        let extensions = raw
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect();
+        Self { extensions }
+    }
+
+    /// Checks whether `path` has one of the priority extensions.
+    pub fn matches(&self, path: &Path) -> bool {
+        let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+            return false;
+        };
+        self.extensions
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(ext))
+    }
+}
 
 /// Helper to pull bytes from a [Read]er into a buffer until either the buffer
 /// is filled or we read the end of the [Read]er. Returns the number of bytes
-/// read. 
-/// 
+/// read.
+///
 /// If the `read_exact_or_end(rdr, buf)? != buf.len()` then it is guranteed that
 /// `rdr` has reached `EOF`.
-/// 
+///
 /// This is necessary since [Read::read] does not gurantee that the buffer being
 /// filled means we've reached `EOF`, and [Read::read_exact] will return an
-/// [io::Error] if it reaches `EOF` before filling the buffer. 
+/// [io::Error] if it reaches `EOF` before filling the buffer.
 pub fn read_exact_or_end<T: Read>(reader: &mut T, buffer: &mut [u8]) -> io::Result<usize> {
     let mut cur_idx = 0;
     loop {
         let subbuf = &mut buffer[cur_idx..];
-        let read_count = match reader.read(subbuf) {
-            Ok(v) => v,
-            Err(e) => return Err(e),
-        };
+        let read_count = reader.read(subbuf)?;
         cur_idx += read_count;
         if read_count == 0 || cur_idx == buffer.len() {
             return Ok(cur_idx);
@@ -34,28 +260,609 @@ pub fn read_exact_or_end<T: Read>(reader: &mut T, buffer: &mut [u8]) -> io::Resu
     }
 }
 
-/// Wrapper around [std::fs::hard_link] that lets us overwrite existing files. 
-/// 
+/// The size of the reads used when retrying a failed read, chosen to isolate
+/// the bad region of the disk as closely as is practical.
+const RETRY_READ_SIZE: usize = 4 * KB as usize;
+
+/// Options controlling how file contents are read when hashing & comparing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ReadOptions {
+    /// How many times a failed read is retried, using smaller reads, before we
+    /// give up on the file.
+    pub retries: u32,
+    /// Whether sequential comparison reads bypass the page cache using
+    /// `O_DIRECT`.
+    pub direct_io: bool,
+    /// Whether to log a hexdump of both files around the first difference
+    /// found when comparing them.
+    pub debug_compare: bool,
+    /// How files are sampled when hashing them.
+    pub sampling: SampleParams,
+    /// The number of threads reading files at once; 0 uses 1 per CPU.
+    pub threads: usize,
+    /// The number of bytes to read over the whole run before stopping, if
+    /// limited.
+    pub max_io: Option<u64>,
+}
+
+impl ReadOptions {
+    /// Checks whether the run has read all the bytes `max_io` allows.
+    pub fn io_exhausted(&self) -> bool {
+        self.max_io.is_some_and(|max| bytes_read() >= max)
+    }
+
+    /// The number of threads to read files with.
+    pub fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        }
+    }
+}
+
+/// Applies `f` to every item using up to `threads` threads, returning the
+/// results in the same order as `items`.
+///
+/// Rather than splitting the items up front, each thread takes the next
+/// unclaimed item as soon as it finishes its last one, so a few slow items
+/// can't leave the other threads idle.
+pub fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(idx) else {
+                            return done;
+                        };
+                        done.push((idx, f(item)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| match worker.join() {
+                Ok(done) => done,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect::<Vec<_>>()
+    });
+    results.sort_unstable_by_key(|(idx, _)| *idx);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// The alignment required for buffers used with `O_DIRECT`.
+pub const PAGE_ALIGN: usize = 4 * KB as usize;
+/// The alignment of large buffers, so that the kernel can back them with
+/// transparent huge pages.
+pub const HUGE_PAGE_ALIGN: usize = 2 * MB as usize;
+
+/// A zeroed, heap-allocated byte buffer whose start is aligned to a given
+/// power of 2.
+///
+/// This over-allocates by the alignment and hands out the aligned portion,
+/// which avoids having to manage the allocation by hand.
+pub struct AlignedBuffer {
+    raw: Box<[u8]>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    /// Allocates a buffer of `len` bytes starting at a multiple of `align`.
+    pub fn new(len: usize, align: usize) -> Self {
+        debug_assert!(align.is_power_of_two());
+        let raw = vec![0; len + align].into_boxed_slice();
+        let offset = raw.as_ptr().align_offset(align);
+        Self { raw, offset, len }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.raw[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[self.offset..self.offset + self.len]
+    }
+}
+
+/// The payload of an [io::Error] for a read that failed partway through a
+/// file, such as when the disk has bad sectors.
+#[derive(Debug)]
+pub struct DamagedRegion {
+    /// The file that failed to be read.
+    pub path: PathBuf,
+    /// The offset of the read which failed.
+    pub offset: u64,
+    /// The underlying error.
+    pub source: io::Error,
+}
+
+impl DamagedRegion {
+    /// Retrieves the [DamagedRegion] from an [io::Error], if it has one.
+    pub fn from_io(err: &io::Error) -> Option<&DamagedRegion> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl Display for DamagedRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read of {} failed at offset {}: {}",
+            self.path.display(),
+            self.offset,
+            self.source
+        )
+    }
+}
+
+impl Error for DamagedRegion {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// The total number of bytes read by every [BlockReader] so far.
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+
+/// Retrieves the total number of bytes read from files for hashing & comparing
+/// them so far.
+pub fn bytes_read() -> u64 {
+    BYTES_READ.load(Ordering::Relaxed)
+}
+
+/// A file being read block-by-block for hashing or comparison.
+///
+/// Failed reads are retried according to the [ReadOptions] the reader was
+/// opened with, and reads that still fail are reported as a [DamagedRegion].
+pub struct BlockReader {
+    fh: File,
+    path: PathBuf,
+    opts: ReadOptions,
+}
+
+impl BlockReader {
+    /// Opens the file at `path` for reading.
+    pub fn open(path: &Path, opts: ReadOptions) -> io::Result<Self> {
+        let fh = File::open(path)?;
+        Ok(Self {
+            fh,
+            path: path.to_owned(),
+            opts,
+        })
+    }
+
+    /// Opens the file at `path` for reading it front-to-back, using `O_DIRECT`
+    /// if [ReadOptions::direct_io] is set.
+    ///
+    /// With `O_DIRECT` every read must go into a [PAGE_ALIGN]ed buffer at an
+    /// aligned offset. If the filesystem doesn't support `O_DIRECT` the file is
+    /// opened normally instead.
+    pub fn open_sequential(path: &Path, opts: ReadOptions) -> io::Result<Self> {
+        if !opts.direct_io {
+            return Self::open(path, opts);
+        }
+        let fh = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+        {
+            Ok(fh) => fh,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                debug!(
+                    "O_DIRECT unsupported for {}; falling back to buffered reads.",
+                    path.display()
+                );
+                return Self::open(path, opts);
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            fh,
+            path: path.to_owned(),
+            opts,
+        })
+    }
+
+    /// Fills `buffer` from the file, following the same semantics as
+    /// [read_exact_or_end].
+    pub fn read_block(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.read_block_uncounted(buffer)?;
+        BYTES_READ.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+
+    /// Implements [BlockReader::read_block], without counting the bytes read.
+    fn read_block_uncounted(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let start = self.fh.stream_position()?;
+        let first_err = match read_exact_or_end(&mut self.fh, buffer) {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        if self.opts.retries == 0 {
+            return Err(self.damaged(start, first_err));
+        }
+        debug!(
+            "Read of {} at offset {} failed ({:?}); retrying with smaller reads.",
+            self.path.display(),
+            start,
+            first_err
+        );
+
+        // We don't know where in the buffer the failure happened, so we redo
+        // the whole thing in small chunks to pin down the bad region
+        let mut idx = 0;
+        while idx < buffer.len() {
+            let end = (idx + RETRY_READ_SIZE).min(buffer.len());
+            let chunk = &mut buffer[idx..end];
+            let offset = start + idx as u64;
+            let mut attempt = 0;
+            let read_count = loop {
+                self.fh.seek(SeekFrom::Start(offset))?;
+                match read_exact_or_end(&mut self.fh, chunk) {
+                    Ok(v) => break v,
+                    Err(e) if attempt >= self.opts.retries => {
+                        return Err(self.damaged(offset, e));
+                    }
+                    Err(e) => {
+                        attempt += 1;
+                        debug!(
+                            "Retry {attempt} of {} at offset {offset} failed: {e:?}",
+                            self.path.display()
+                        );
+                    }
+                }
+            };
+            idx += read_count;
+            if read_count != chunk.len() {
+                break;
+            }
+        }
+        Ok(idx)
+    }
+
+    fn damaged(&self, offset: u64, source: io::Error) -> io::Error {
+        let kind = source.kind();
+        let region = DamagedRegion {
+            path: self.path.clone(),
+            offset,
+            source,
+        };
+        io::Error::new(kind, region)
+    }
+}
+
+impl Seek for BlockReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.fh.seek(pos)
+    }
+}
+
+/// A handle to a file we're about to link other paths to, verified to still be
+/// the file we compared.
+///
+/// Linking is done from the handle rather than by path, so that the file can't
+/// be swapped out from under us between verifying it and linking it.
+#[derive(Debug)]
+pub struct LinkSource {
+    fh: File,
+}
+
+impl LinkSource {
+    /// Opens the file at `path`, checking that it is still the inode `ino` on
+    /// device `dev`.
+    pub fn open_verified(path: &Path, dev: u64, ino: u64) -> io::Result<Self> {
+        let fh = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)?;
+        let meta = fh.metadata()?;
+        if meta.dev() != dev || meta.ino() != ino {
+            return Err(io::Error::other(format!(
+                "{} was replaced after it was compared (expected inode {dev}:{ino}, found {}:{})",
+                path.display(),
+                meta.dev(),
+                meta.ino()
+            )));
+        }
+        Ok(Self { fh })
+    }
+
+    /// Opens the source for reading, as the same inode the handle refers to.
+    pub fn open_readable(&self) -> io::Result<File> {
+        File::open(format!("/proc/self/fd/{}", self.fh.as_raw_fd()))
+    }
+
+    /// Creates a new hard link to the source at `dest`, which must not exist
+    /// yet.
+    pub fn link_to(&self, dest: &Path) -> io::Result<()> {
+        let raw_dest = CString::new(dest.as_os_str().as_bytes())?;
+        // SAFETY: the fd is open for the lifetime of `self`, and both paths
+        // are valid NUL-terminated strings that outlive the call.
+        let res = unsafe {
+            libc::linkat(
+                self.fh.as_raw_fd(),
+                c"".as_ptr(),
+                libc::AT_FDCWD,
+                raw_dest.as_ptr(),
+                libc::AT_EMPTY_PATH,
+            )
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if !matches!(err.raw_os_error(), Some(libc::EPERM) | Some(libc::ENOENT)) {
+            return Err(err);
+        }
+
+        // Linking with AT_EMPTY_PATH needs CAP_DAC_READ_SEARCH, so unprivileged
+        // users go through the fd's magic link in /proc instead, which still
+        // refers to the verified inode
+        let proc_path = CString::new(format!("/proc/self/fd/{}", self.fh.as_raw_fd()))?;
+        // SAFETY: as above.
+        let res = unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                proc_path.as_ptr(),
+                libc::AT_FDCWD,
+                raw_dest.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Wrapper around [std::fs::hard_link] that lets us atomically overwrite
+/// existing files.
+///
 /// # Implementation details
-/// This enables overwriting by first checking if the previous file exists, and
-/// if so renaming it and then deleting the renamed file once the
-/// [std::fs::hard_link] call completes. 
-pub fn hard_link(left: &Path, right: &Path) -> io::Result<()> {
-    let old_right_ext = right.extension().unwrap_or_default();
-    let new_right_ext = {
-        let mut buf = old_right_ext.to_os_string();
-        buf.push(".bak");
-        buf
-    };
-    let tmp_right_path = right.with_extension(new_right_ext);
-    let mut did_backup = false;
-    if !fs::exists(&tmp_right_path)? {
-        fs::rename(right, &tmp_right_path)?;
-        did_backup = true;
-    }
-    fs::hard_link(left, right)?;
-    if did_backup {
-        fs::remove_file(&tmp_right_path)?;
+/// The new link is first created at a temporary name next to `right`, and then
+/// swapped into place using `renameat2(RENAME_EXCHANGE)`; afterwards the
+/// temporary name refers to the old file, which is then removed. This way
+/// `right` always resolves to either the old or the new file, even if we're
+/// interrupted partway through.
+///
+/// On kernels or filesystems without `RENAME_EXCHANGE` support we fall back to
+/// a plain [std::fs::rename] of the temporary link over `right`, which is also
+/// atomic but loses the old file immediately.
+pub fn hard_link(left: &LinkSource, right: &Path) -> io::Result<()> {
+    replace_atomically(right, |tmp| left.link_to(tmp))
+}
+
+/// Replaces the contents of the file at `right` with a copy-on-write clone of
+/// `left`'s using the `FICLONE` ioctl, so that the 2 share their data blocks
+/// while staying separate, independently mutable files.
+///
+/// `right` keeps its inode, and with it its links, owner & permissions. Its
+/// times are restored afterwards, since cloning updates them.
+pub fn reflink(left: &LinkSource, right: &Path) -> io::Result<()> {
+    let src = left.open_readable()?;
+    let dest = OpenOptions::new().write(true).open(right)?;
+    let meta = dest.metadata()?;
+    // SAFETY: both fds are open for the duration of the call.
+    let res = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if res != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::EINVAL | libc::ENOTTY) => io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the filesystem doesn't support reflinks ({err})"),
+            ),
+            _ => err,
+        });
+    }
+    let times = FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(meta.modified()?);
+    dest.set_times(times)
+}
+
+/// Replaces `right` with a symlink to `target`, relative to the directory
+/// containing `right`, in the same atomic way as [hard_link].
+pub fn symlink_relative(target: &Path, right: &Path) -> io::Result<()> {
+    let dir = right.parent().unwrap_or(Path::new("/"));
+    let relative = relative_path(dir, target);
+    replace_atomically(right, |tmp| std::os::unix::fs::symlink(&relative, tmp))
+}
+
+/// The path of `to` relative to the directory `from`; both must be absolute.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    relative.extend(&to[common..]);
+    relative
+}
+
+/// Replaces `right` with the file `create` makes at the temporary path it's
+/// given, as described for [hard_link].
+fn replace_atomically(
+    right: &Path,
+    create: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let tmp_right_path = temp_sibling(right);
+    create(&tmp_right_path)?;
+    let res = match rename_exchange(&tmp_right_path, right) {
+        Ok(()) => fs::remove_file(&tmp_right_path),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) => {
+            debug!(
+                "RENAME_EXCHANGE unsupported for {} ({:?}); falling back to rename.",
+                right.display(),
+                e
+            );
+            fs::rename(&tmp_right_path, right)
+        }
+        Err(e) => Err(e),
+    };
+    if res.is_err() && fs::symlink_metadata(&tmp_right_path).is_ok() {
+        let _ = fs::remove_file(&tmp_right_path);
+    }
+    res
+}
+
+/// Flushes the entries of the directory at `dir` to disk, so that links created
+/// or renamed in it survive a crash.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Checks whether we can create & remove entries in `dir`.
+pub fn is_writable_dir(dir: &Path) -> bool {
+    let Ok(raw_dir) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: the path is a valid NUL-terminated string that outlives the call.
+    unsafe { libc::access(raw_dir.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
+/// Runs `f`, temporarily adding owner write permission to `dir` if it isn't
+/// writable; the original permissions are restored afterwards.
+pub fn with_writable_dir<T>(dir: &Path, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    if is_writable_dir(dir) {
+        return f();
+    }
+    let original = fs::metadata(dir)?.permissions();
+    let mut writable = original.clone();
+    writable.set_mode(original.mode() | 0o300);
+    debug!(
+        "Temporarily making {} writable (mode {:o}).",
+        dir.display(),
+        original.mode()
+    );
+    fs::set_permissions(dir, writable)?;
+    let res = f();
+    if let Err(e) = fs::set_permissions(dir, original) {
+        error!(
+            "Error restoring the permissions of {}: {:?}",
+            dir.display(),
+            e
+        );
+    }
+    res
+}
+
+/// Builds a hidden temporary path in the same directory as `path`, unique to
+/// this process.
+pub fn temp_sibling(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".hldup-{}", process::id()));
+    path.with_file_name(name)
+}
+
+/// Atomically swaps the files at `left` and `right` using
+/// `renameat2(RENAME_EXCHANGE)`.
+fn rename_exchange(left: &Path, right: &Path) -> io::Result<()> {
+    let raw_left = CString::new(left.as_os_str().as_bytes())?;
+    let raw_right = CString::new(right.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings that outlive the call.
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            raw_left.as_ptr(),
+            libc::AT_FDCWD,
+            raw_right.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 * KB));
+        assert_eq!(parse_size(" 1.5G "), Ok(GB + GB / 2));
+        assert_eq!(parse_size("2TiB"), Ok(2 * TB));
+        assert_eq!(parse_size("3 mb"), Ok(3 * MB));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.2.3K").is_err());
+    }
+
+    #[test]
+    fn parses_ages() {
+        let now = UNIX_EPOCH + Duration::from_secs(100 * DAY);
+        assert_eq!(
+            parse_time("30d", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(70 * DAY))
+        );
+        assert_eq!(
+            parse_time("1.5h", now),
+            Ok(now - Duration::from_secs(HOUR + HOUR / 2))
+        );
+        assert_eq!(
+            parse_time("2w", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(86 * DAY))
+        );
+        assert!(parse_time("30", now).is_err());
+        assert!(parse_time("30q", now).is_err());
+    }
+
+    #[test]
+    fn parses_dates() {
+        let now = SystemTime::now();
+        assert_eq!(parse_time("1970-01-01", now), Ok(UNIX_EPOCH));
+        assert_eq!(
+            parse_time("2000-03-01", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(951_868_800))
+        );
+        assert_eq!(
+            parse_time("2024-02-29", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_709_164_800))
+        );
+        assert!(parse_time("2024-13-01", now).is_err());
+        assert!(parse_time("1969-12-31", now).is_err());
+    }
+
+    #[test]
+    fn finds_relative_paths() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(relative("/a/b", "/a/b/c"), PathBuf::from("c"));
+        assert_eq!(relative("/a/b", "/a/c/d"), PathBuf::from("../c/d"));
+        assert_eq!(relative("/a/b/c", "/x"), PathBuf::from("../../../x"));
+        assert_eq!(relative("/", "/a/b"), PathBuf::from("a/b"));
+        // Components are compared whole, not as string prefixes
+        assert_eq!(relative("/a/bc", "/a/b/d"), PathBuf::from("../b/d"));
+    }
+}
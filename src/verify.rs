@@ -0,0 +1,89 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use log::{error, info};
+
+use crate::{
+    hashcache::{full_hash, HashCache},
+    summary::RunSummary,
+    utils::ReadOptions,
+};
+
+/// The default number of files [verify_hashing] hashes in full.
+pub const DEFAULT_VERIFY_SAMPLE: usize = 1000;
+
+/// Checks how often the sampled [FileHashes](crate::hashcache::FileHashes) in
+/// `cache` group together files whose full contents differ, by hashing up to
+/// `sample` files in full, and prints the results to stdout.
+///
+/// Only files sharing their sampled hashes with another file are checked,
+/// since those are the only ones a false grouping can affect. Whole groups are
+/// checked at a time, largest files first, until the sample is used up.
+pub fn verify_hashing(
+    cache: &HashCache,
+    sample: usize,
+    read_opts: ReadOptions,
+    summary: &mut RunSummary,
+) {
+    let total_files = cache.iter().map(|(_, paths)| paths.len()).sum::<usize>();
+    let mut dups = cache.duplicates();
+    dups.sort_by_key(|dup| std::cmp::Reverse(dup.hashes.size()));
+    let candidates = dups.iter().map(|dup| dup.paths.len()).sum::<usize>();
+
+    let mut hashed_files = 0;
+    let mut checked_groups = 0;
+    let mut false_groups = 0;
+    let mut extra_groups = 0;
+    for dup in &dups {
+        if hashed_files + dup.paths.len() > sample {
+            break;
+        }
+        let mut by_content: HashMap<u64, Vec<&PathBuf>> = HashMap::new();
+        for path in &dup.paths {
+            match full_hash(path, read_opts) {
+                Ok(hash) => by_content.entry(hash).or_default().push(path),
+                Err(e) => {
+                    summary.record_read_error(&e);
+                    error!("Error hashing {}: {:?}", path.display(), e);
+                }
+            }
+        }
+        hashed_files += dup.paths.len();
+        checked_groups += 1;
+        if by_content.len() > 1 {
+            info!(
+                "Group {} holds {} different files under the same sampled hashes.",
+                dup.id(),
+                by_content.len()
+            );
+            false_groups += 1;
+            extra_groups += by_content.len() - 1;
+        }
+    }
+
+    let distinct = cache.iter().count();
+    let sizes = cache
+        .iter()
+        .map(|(hashes, _)| hashes.size())
+        .collect::<HashSet<_>>()
+        .len();
+    println!("Scanned files:                 {total_files}");
+    println!("Distinct sampled hashes:       {distinct}");
+    println!("Distinct sizes:                {sizes}");
+    println!("Files sharing sampled hashes:  {candidates}");
+    println!("Files hashed in full:          {hashed_files}");
+    println!(
+        "Groups checked:                {checked_groups} of {}",
+        dups.len()
+    );
+    println!("Falsely grouped:               {false_groups}");
+    if checked_groups > 0 {
+        println!(
+            "False grouping rate:           {:.2}%",
+            100.0 * false_groups as f64 / checked_groups as f64
+        );
+        println!("Extra groups needed:           {extra_groups}");
+    }
+}